@@ -0,0 +1,473 @@
+//! API key authentication. Clients send their key in the `X-Api-Key` header, keys come from
+//! the `API_KEYS_PATH` config file or are created through the admin routes.
+//!
+//! Requests go unauthenticated while there are no keys in either place, so local setups keep
+//! working. The admin routes stay closed until then, since they could mint the first admin
+//! key, unless `OPEN_ADMIN_ROUTES` is set.
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    env,
+    fs::File,
+    io::Read,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{LazyLock, OnceLock},
+    time::SystemTime,
+};
+
+use aide::OperationInput;
+use anyhow::Context;
+use axum::extract::{FromRequestParts, Request, State};
+use axum::http::{HeaderMap, request::Parts};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::logic::{
+    Stores, api_key_usage_today, find_api_key, hash_api_key, list_api_keys,
+    refund_api_key_document, reserve_api_key_document,
+};
+use crate::types::{ApiKey, ApiKeyError, ApiKeyRole, DocStatus, unix_millis};
+
+use super::error::ApiError;
+
+/// Header clients send their key in, the same one datalab uses.
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// Let anyone use the admin routes while no keys exist, for local setups without any config.
+pub static OPEN_ADMIN_ROUTES: LazyLock<bool> = LazyLock::new(|| {
+    env::var("OPEN_ADMIN_ROUTES")
+        .map(|val| val == "true" || val == "1")
+        .unwrap_or(false)
+});
+
+/// Keys from the config, by id. Filled once on startup by `load_configured_keys`.
+static CONFIGURED_KEYS: OnceLock<HashMap<String, ApiKey>> = OnceLock::new();
+
+/// Set once a key was found in the store, auth then stays on until the process restarts.
+static STORED_KEYS_SEEN: AtomicBool = AtomicBool::new(false);
+
+/// An entry of the `API_KEYS_PATH` file, a json list of these.
+#[derive(Deserialize)]
+struct ConfiguredKey {
+    key: String,
+    owner: String,
+    #[serde(default)]
+    role: ApiKeyRole,
+    daily_documents: Option<u64>,
+    daily_pages: Option<u64>,
+    /// Signs the key's completion webhooks. Without it, or `WEBHOOK_SECRET` to fall back on,
+    /// the key's tasks can't be given a `callback_url`.
+    webhook_secret: Option<String>,
+}
+
+/// Read the keys in `API_KEYS_PATH`. `CRIMSON_API_KEY` is kept working as an admin key
+/// owned by `admin`, signing its webhooks with `CRIMSON_WEBHOOK_SECRET`.
+pub fn load_configured_keys() -> anyhow::Result<()> {
+    let mut configured = Vec::new();
+    if let Ok(path) = env::var("API_KEYS_PATH") {
+        let file = File::open(&path).with_context(|| format!("Could not open {path}"))?;
+        configured = serde_json::from_reader(file)
+            .with_context(|| format!("{path} should be a json list of API keys"))?;
+    }
+    if let Ok(key) = env::var("CRIMSON_API_KEY") {
+        configured.push(ConfiguredKey {
+            key,
+            owner: "admin".to_string(),
+            role: ApiKeyRole::Admin,
+            daily_documents: None,
+            daily_pages: None,
+            webhook_secret: env::var("CRIMSON_WEBHOOK_SECRET").ok(),
+        });
+    }
+    let created_at = unix_millis(SystemTime::now());
+    let keys: HashMap<String, ApiKey> = configured
+        .into_iter()
+        .map(|configured| {
+            let id = hash_api_key(&configured.key);
+            let key = ApiKey {
+                id: id.clone(),
+                owner: configured.owner,
+                role: configured.role,
+                daily_documents: configured.daily_documents,
+                daily_pages: configured.daily_pages,
+                created_at,
+                webhook_secret: configured
+                    .webhook_secret
+                    .filter(|secret| !secret.is_empty()),
+            };
+            (id, key)
+        })
+        .collect();
+    if keys.is_empty() {
+        warn!(
+            open_admin_routes = *OPEN_ADMIN_ROUTES,
+            "No API keys are configured, routes are open to anyone until a key is stored"
+        );
+    } else {
+        info!(keys = keys.len(), "Loaded API keys from the config");
+    }
+    let _ = CONFIGURED_KEYS.set(keys);
+    Ok(())
+}
+
+/// Whether requests need a key, because keys are configured or stored. The store is looked
+/// at on every request until it holds a key, so keys created by another process count too.
+pub async fn auth_enabled(stores: &Stores) -> Result<bool, ApiKeyError> {
+    if CONFIGURED_KEYS.get().is_some_and(|keys| !keys.is_empty())
+        || STORED_KEYS_SEEN.load(Ordering::Relaxed)
+    {
+        return Ok(true);
+    }
+    let stored = !list_api_keys(stores).await?.is_empty();
+    if stored {
+        STORED_KEYS_SEEN.store(true, Ordering::Relaxed);
+    }
+    Ok(stored)
+}
+
+/// Whether the key comes from the config rather than the store, and so can't be revoked.
+pub fn is_configured_key(id: &str) -> bool {
+    CONFIGURED_KEYS
+        .get()
+        .is_some_and(|keys| keys.contains_key(id))
+}
+
+/// Every key from the config, by owner.
+pub fn configured_keys() -> Vec<ApiKey> {
+    let mut keys: Vec<_> = CONFIGURED_KEYS
+        .get()
+        .map(|keys| keys.values().cloned().collect())
+        .unwrap_or_default();
+    keys.sort_by(|a, b| a.owner.cmp(&b.owner));
+    keys
+}
+
+fn random_hex() -> String {
+    let mut f = File::open("/dev/urandom").expect("Failed to open /dev/urandom");
+    let mut bytes = [0u8; 24];
+    f.read_exact(&mut bytes)
+        .expect("Failed to read random bytes");
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Random secret for a new key, only ever shown to whoever created it.
+pub fn generate_api_key_secret() -> String {
+    format!("crimson_{}", random_hex())
+}
+
+/// Random secret to sign a key's completion webhooks with.
+pub fn generate_webhook_secret() -> String {
+    format!("whsec_{}", random_hex())
+}
+
+/// A configured or stored key by its id.
+pub async fn key_by_id(stores: &Stores, id: &str) -> Result<Option<ApiKey>, ApiKeyError> {
+    if let Some(key) = CONFIGURED_KEYS.get().and_then(|keys| keys.get(id)) {
+        return Ok(Some(key.clone()));
+    }
+    find_api_key(stores, id).await
+}
+
+async fn resolve_key(stores: &Stores, secret: &str) -> Result<Option<ApiKey>, ApiKeyError> {
+    key_by_id(stores, &hash_api_key(secret)).await
+}
+
+/// The key the request was made with.
+async fn authenticate(stores: &Stores, headers: &HeaderMap) -> Result<ApiKey, ApiError> {
+    let Some(secret) = headers
+        .get(API_KEY_HEADER)
+        .and_then(|key| key.to_str().ok())
+    else {
+        return Err(ApiError::Unauthorized(format!(
+            "Missing {API_KEY_HEADER} header"
+        )));
+    };
+    resolve_key(stores, secret)
+        .await?
+        .ok_or_else(|| ApiError::Unauthorized("Invalid API key".to_string()))
+}
+
+/// Middleware rejecting requests without a valid key, handlers find the key through `Caller`.
+pub async fn require_api_key(
+    State(stores): State<Stores>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match auth_enabled(&stores).await {
+        Ok(true) => {}
+        Ok(false) => return next.run(request).await,
+        Err(err) => return ApiError::from(err).into_response(),
+    }
+    match authenticate(&stores, request.headers()).await {
+        Ok(key) => {
+            request.extensions_mut().insert(key);
+            next.run(request).await
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Middleware for the admin routes, only letting admin keys through.
+pub async fn require_admin_key(
+    State(stores): State<Stores>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match auth_enabled(&stores).await {
+        Ok(true) => {}
+        Ok(false) if *OPEN_ADMIN_ROUTES => return next.run(request).await,
+        Ok(false) => {
+            return ApiError::Unauthorized(
+                "Admin routes need an admin key from API_KEYS_PATH or CRIMSON_API_KEY, or OPEN_ADMIN_ROUTES=true".to_string(),
+            )
+            .into_response();
+        }
+        Err(err) => return ApiError::from(err).into_response(),
+    }
+    match authenticate(&stores, request.headers()).await {
+        Ok(key) if key.role == ApiKeyRole::Admin => {
+            request.extensions_mut().insert(key);
+            next.run(request).await
+        }
+        Ok(_) => {
+            ApiError::Forbidden("Only admin keys may use this route".to_string()).into_response()
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+/// The key a request was made with, `None` while authentication is off.
+#[derive(Clone, Debug, Default)]
+pub struct Caller(pub Option<ApiKey>);
+
+impl<S: Send + Sync> FromRequestParts<S> for Caller {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Caller(parts.extensions.get::<ApiKey>().cloned()))
+    }
+}
+
+impl OperationInput for Caller {}
+
+impl Caller {
+    /// Tag a new task with the caller's key and owner. Only admins may submit on behalf of
+    /// another owner, everyone else's tasks belong to their key's owner.
+    pub fn claim(&self, status: &mut DocStatus, requested_owner: Option<String>) {
+        let Some(key) = &self.0 else {
+            status.owner = requested_owner;
+            return;
+        };
+        status.api_key_id = Some(key.id.clone());
+        status.owner = match key.role {
+            ApiKeyRole::Admin => requested_owner
+                .filter(|owner| !owner.is_empty())
+                .or_else(|| Some(key.owner.clone())),
+            ApiKeyRole::User => Some(key.owner.clone()),
+        };
+    }
+
+    /// Admins see every task, other keys only those of their own owner.
+    pub fn can_see(&self, status: &DocStatus) -> bool {
+        self.can_see_owner(status.owner.as_deref())
+    }
+
+    pub fn can_see_owner(&self, owner: Option<&str>) -> bool {
+        match &self.0 {
+            None => true,
+            Some(key) => key.role == ApiKeyRole::Admin || owner == Some(&key.owner),
+        }
+    }
+
+    /// Refuse new documents early once the key used up either of today's quotas, before the
+    /// upload is read. Pages are only charged once converted, so the document that crosses the
+    /// page quota still goes through. `reserve_document` is what actually holds the document
+    /// quota.
+    pub async fn check_quota(&self, stores: &Stores) -> Result<(), ApiError> {
+        let Some(key) = &self.0 else {
+            return Ok(());
+        };
+        if key.daily_documents.is_none() && key.daily_pages.is_none() {
+            return Ok(());
+        }
+        let used = api_key_usage_today(stores, &key.id).await?;
+        if let Some(limit) = key.daily_documents
+            && used.documents >= limit
+        {
+            return Err(documents_used_up(limit));
+        }
+        if let Some(limit) = key.daily_pages
+            && used.pages >= limit
+        {
+            return Err(ApiError::TooManyRequests(format!(
+                "Daily quota of {limit} pages used up, it resets at midnight UTC"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Count a new document against the key's daily quota, refusing it once used up. Counting
+    /// and checking are one step in the store, so concurrent ingests can't overshoot the quota.
+    /// Returns the day it was counted on, `None` without a key.
+    pub async fn reserve_document(&self, stores: &Stores) -> Result<Option<i64>, ApiError> {
+        let Some(key) = &self.0 else {
+            return Ok(None);
+        };
+        match reserve_api_key_document(stores, &key.id, key.daily_documents).await? {
+            Some(day) => Ok(Some(day)),
+            None => Err(documents_used_up(key.daily_documents.unwrap_or_default())),
+        }
+    }
+
+    /// Hand back a document from `reserve_document` that couldn't be queued.
+    pub async fn refund_document(&self, stores: &Stores, reserved_on: Option<i64>) {
+        let (Some(key), Some(day)) = (&self.0, reserved_on) else {
+            return;
+        };
+        if let Err(err) = refund_api_key_document(stores, &key.id, day).await {
+            warn!(%err, key = key.id, "Could not refund a document that wasn't queued");
+        }
+    }
+}
+
+fn documents_used_up(limit: u64) -> ApiError {
+    ApiError::TooManyRequests(format!(
+        "Daily quota of {limit} documents used up, it resets at midnight UTC"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+    use crate::api::tests::{
+        remove_upload, serve_api, stored_key, task_id_of, upload, upload_form,
+    };
+    use crate::logic::tests::memory_stores;
+
+    async fn get_status(base_url: &str, secret: &str, task_id: u64) -> StatusCode {
+        reqwest::Client::new()
+            .get(format!("{base_url}/v1/status/{task_id}"))
+            .header(API_KEY_HEADER, secret)
+            .send()
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn keys_only_see_their_own_owners_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        let alice = stored_key(&stores, "alice", ApiKeyRole::User, None).await;
+        let bob = stored_key(&stores, "bob", ApiKeyRole::User, None).await;
+        let admin = stored_key(&stores, "ops", ApiKeyRole::Admin, None).await;
+        let base_url = serve_api(stores).await;
+
+        let (status, body) = upload(&base_url, "/v1/ingest/upload", &alice, upload_form(&[])).await;
+        assert_eq!(status, StatusCode::OK);
+        let task_id = task_id_of(&body);
+        assert_eq!(get_status(&base_url, &alice, task_id).await, StatusCode::OK);
+        assert_eq!(get_status(&base_url, &admin, task_id).await, StatusCode::OK);
+        assert_eq!(
+            get_status(&base_url, &bob, task_id).await,
+            StatusCode::NOT_FOUND
+        );
+        let cancelled = reqwest::Client::new()
+            .delete(format!("{base_url}/v1/task/{task_id}"))
+            .header(API_KEY_HEADER, &bob)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(cancelled.status(), StatusCode::NOT_FOUND);
+        remove_upload(task_id);
+    }
+
+    #[tokio::test]
+    async fn requests_need_a_known_key_and_admin_routes_an_admin_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        let user = stored_key(&stores, "alice", ApiKeyRole::User, None).await;
+        let admin = stored_key(&stores, "ops", ApiKeyRole::Admin, None).await;
+        let base_url = serve_api(stores).await;
+        let client = reqwest::Client::new();
+
+        let usage = format!("{base_url}/v1/usage");
+        let missing = client.get(&usage).send().await.unwrap();
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+        let unknown = client.get(&usage).header(API_KEY_HEADER, "crimson_nope");
+        assert_eq!(
+            unknown.send().await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let known = client.get(&usage).header(API_KEY_HEADER, &user);
+        assert_eq!(known.send().await.unwrap().status(), StatusCode::OK);
+
+        let keys = format!("{base_url}/admin/api_keys");
+        let as_user = client.get(&keys).header(API_KEY_HEADER, &user);
+        assert_eq!(
+            as_user.send().await.unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+        let as_admin = client.get(&keys).header(API_KEY_HEADER, &admin);
+        assert_eq!(as_admin.send().await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn no_admin_key_can_be_minted_while_no_keys_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        let base_url = serve_api(stores.clone()).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{base_url}/admin/api_keys"))
+            .json(&json!({"owner": "mallory", "role": "admin"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(list_api_keys(&stores).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn uploads_are_refused_once_the_document_quota_is_used_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        let secret = stored_key(&stores, "alice", ApiKeyRole::User, Some(1)).await;
+        let base_url = serve_api(stores.clone()).await;
+
+        let (status, body) =
+            upload(&base_url, "/v1/ingest/upload", &secret, upload_form(&[])).await;
+        assert_eq!(status, StatusCode::OK);
+        remove_upload(task_id_of(&body));
+        let (status, body) =
+            upload(&base_url, "/v1/ingest/upload", &secret, upload_form(&[])).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(body["message"].as_str().unwrap().contains("1 documents"));
+    }
+
+    #[tokio::test]
+    async fn refunded_documents_count_against_the_quota_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        let secret = stored_key(&stores, "alice", ApiKeyRole::User, Some(1)).await;
+        let key = find_api_key(&stores, &hash_api_key(&secret))
+            .await
+            .unwrap()
+            .unwrap();
+        let caller = Caller(Some(key));
+
+        let reserved_on = caller.reserve_document(&stores).await.unwrap();
+        assert!(reserved_on.is_some());
+        assert!(matches!(
+            caller.reserve_document(&stores).await,
+            Err(ApiError::TooManyRequests(_))
+        ));
+        caller.refund_document(&stores, reserved_on).await;
+        assert!(caller.reserve_document(&stores).await.is_ok());
+    }
+}
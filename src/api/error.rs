@@ -0,0 +1,418 @@
+//! Errors returned by the API handlers. Every error is answered with its HTTP status and a
+//! json body holding a machine readable `code`, a `message` and the request's `trace_id`.
+use aide::OperationOutput;
+use aide::generate::GenContext;
+use aide::openapi::{Operation, Response as ApiResponse};
+use axum::Json;
+use axum::extract::multipart::MultipartError;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum_tracing_opentelemetry::tracing_opentelemetry_instrumentation_sdk::find_current_trace_id;
+use redis::RedisError;
+use rusqlite::ErrorCode;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{error, warn};
+
+use crate::types::{
+    ApiKeyError, CancelTaskError, DocStatusError, IngestError, QuarantineError, QueueError,
+    StoreError,
+};
+
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    PayloadTooLarge(String),
+    #[error("{0}")]
+    TooManyRequests(String),
+    #[error("{0}")]
+    Internal(String),
+    /// A store or queue can't be reached right now, retrying later may work.
+    #[error("{0}")]
+    Unavailable(String),
+}
+
+/// Body of every error response, also left in the response's extensions for middleware that
+/// answers errors in another shape.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct ApiErrorBody {
+    /// Stable identifier of the kind of error, such as `not_found` or `too_many_requests`.
+    pub code: String,
+    /// Human readable description of what went wrong.
+    pub message: String,
+    /// Trace the request was recorded under, to find it in the logs.
+    pub trace_id: Option<String>,
+}
+
+impl ApiError {
+    /// Every status an `ApiError` can be answered with.
+    const STATUSES: [StatusCode; 9] = [
+        StatusCode::BAD_REQUEST,
+        StatusCode::UNAUTHORIZED,
+        StatusCode::FORBIDDEN,
+        StatusCode::NOT_FOUND,
+        StatusCode::CONFLICT,
+        StatusCode::PAYLOAD_TOO_LARGE,
+        StatusCode::TOO_MANY_REQUESTS,
+        StatusCode::INTERNAL_SERVER_ERROR,
+        StatusCode::SERVICE_UNAVAILABLE,
+    ];
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::Internal(_) => "internal",
+            ApiError::Unavailable(_) => "unavailable",
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            error!(err = %self, code = self.code(), "Request failed");
+        } else if status == StatusCode::CONFLICT || status == StatusCode::TOO_MANY_REQUESTS {
+            warn!(err = %self, code = self.code(), "Request refused");
+        }
+        let body = ApiErrorBody {
+            code: self.code().to_string(),
+            message: self.to_string(),
+            trace_id: find_current_trace_id(),
+        };
+        let mut response = (status, Json(body.clone())).into_response();
+        response.extensions_mut().insert(body);
+        response
+    }
+}
+
+impl OperationOutput for ApiError {
+    type Inner = ApiErrorBody;
+
+    fn operation_response(ctx: &mut GenContext, operation: &mut Operation) -> Option<ApiResponse> {
+        Json::<ApiErrorBody>::operation_response(ctx, operation)
+    }
+
+    fn inferred_responses(
+        ctx: &mut GenContext,
+        operation: &mut Operation,
+    ) -> Vec<(Option<u16>, ApiResponse)> {
+        let Some(response) = Self::operation_response(ctx, operation) else {
+            return Vec::new();
+        };
+        Self::STATUSES
+            .iter()
+            .map(|status| {
+                let description = status.canonical_reason().unwrap_or_default().to_string();
+                (
+                    Some(status.as_u16()),
+                    ApiResponse {
+                        description,
+                        ..response.clone()
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+/// Connection trouble is worth retrying, anything else is a bug or corrupt data.
+fn redis_error(err: RedisError) -> ApiError {
+    if err.is_io_error()
+        || err.is_connection_refusal()
+        || err.is_connection_dropped()
+        || err.is_timeout()
+    {
+        ApiError::Unavailable(format!("Redis is unavailable: {err}"))
+    } else {
+        ApiError::Internal(format!("Redis error: {err}"))
+    }
+}
+
+/// A busy or locked database clears up once the other writer is done.
+fn sqlite_error(err: rusqlite::Error) -> ApiError {
+    match err.sqlite_error_code() {
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => {
+            ApiError::Unavailable(format!("Sqlite is busy: {err}"))
+        }
+        _ => ApiError::Internal(format!("Sqlite error: {err}")),
+    }
+}
+
+impl From<StoreError> for ApiError {
+    fn from(err: StoreError) -> Self {
+        match err {
+            StoreError::InvalidLocation => ApiError::BadRequest(err.to_string()),
+            StoreError::S3(err) => ApiError::Unavailable(format!("S3 error: {err}")),
+            StoreError::Serde(_) | StoreError::LocalFile => ApiError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<QueueError> for ApiError {
+    fn from(err: QueueError) -> Self {
+        match err {
+            QueueError::Redis(err) => redis_error(err),
+            QueueError::Sqlite(err) => sqlite_error(err),
+            QueueError::LeaseLost => ApiError::Conflict(err.to_string()),
+            QueueError::QueueEmpty | QueueError::Serde(_) => ApiError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<DocStatusError> for ApiError {
+    fn from(err: DocStatusError) -> Self {
+        match err {
+            DocStatusError::DocidNotFound => ApiError::NotFound(err.to_string()),
+            DocStatusError::Redis(err) => redis_error(err),
+            DocStatusError::Sqlite(err) => sqlite_error(err),
+            DocStatusError::Serde(_) => ApiError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<QuarantineError> for ApiError {
+    fn from(err: QuarantineError) -> Self {
+        match err {
+            QuarantineError::Redis(err) => redis_error(err),
+            QuarantineError::Sqlite(err) => sqlite_error(err),
+            QuarantineError::Serde(_) => ApiError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<ApiKeyError> for ApiError {
+    fn from(err: ApiKeyError) -> Self {
+        match err {
+            ApiKeyError::Redis(err) => redis_error(err),
+            ApiKeyError::Sqlite(err) => sqlite_error(err),
+            ApiKeyError::Serde(_) => ApiError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<CancelTaskError> for ApiError {
+    fn from(err: CancelTaskError) -> Self {
+        match err {
+            CancelTaskError::DocidNotFound => ApiError::NotFound(err.to_string()),
+            CancelTaskError::AlreadyFinished(_) => ApiError::Conflict(err.to_string()),
+            CancelTaskError::Status(err) => err.into(),
+        }
+    }
+}
+
+impl From<IngestError> for ApiError {
+    fn from(err: IngestError) -> Self {
+        match err {
+            IngestError::Status(err) => err.into(),
+            IngestError::Queue(err) => err.into(),
+        }
+    }
+}
+
+/// Uploads past the body limit are 413, anything else is a malformed form.
+impl From<MultipartError> for ApiError {
+    fn from(err: MultipartError) -> Self {
+        let message = format!("Could not read the multipart form: {}", err.body_text());
+        if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            ApiError::PayloadTooLarge(message)
+        } else {
+            ApiError::BadRequest(message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use redis::ErrorKind;
+    use rusqlite::ffi;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::types::ProcessingStage;
+
+    fn serde_error() -> serde_json::Error {
+        serde_json::from_str::<Value>("{").unwrap_err()
+    }
+
+    fn sqlite(code: i32) -> rusqlite::Error {
+        rusqlite::Error::SqliteFailure(ffi::Error::new(code), None)
+    }
+
+    fn redis_refused() -> RedisError {
+        io::Error::new(io::ErrorKind::ConnectionRefused, "refused").into()
+    }
+
+    fn redis_type_error() -> RedisError {
+        (ErrorKind::TypeError, "unexpected reply").into()
+    }
+
+    #[tokio::test]
+    async fn store_errors_map_to_their_status_and_body() {
+        let cases: Vec<(ApiError, StatusCode, &str, &str)> = vec![
+            (
+                DocStatusError::DocidNotFound.into(),
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "Doc ID Not Found",
+            ),
+            (
+                DocStatusError::Redis(redis_refused()).into(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                "Redis is unavailable",
+            ),
+            (
+                DocStatusError::Redis(redis_type_error()).into(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "Redis error",
+            ),
+            (
+                DocStatusError::Sqlite(sqlite(ffi::SQLITE_BUSY)).into(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                "Sqlite is busy",
+            ),
+            (
+                DocStatusError::Serde(serde_error()).into(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "Serialization error",
+            ),
+            (
+                QueueError::Sqlite(sqlite(ffi::SQLITE_LOCKED)).into(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                "Sqlite is busy",
+            ),
+            (
+                QueueError::Sqlite(sqlite(ffi::SQLITE_CORRUPT)).into(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "Sqlite error",
+            ),
+            (
+                QueueError::LeaseLost.into(),
+                StatusCode::CONFLICT,
+                "conflict",
+                "lease expired",
+            ),
+            (
+                QueueError::QueueEmpty.into(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "Queue is Empty",
+            ),
+            (
+                StoreError::InvalidLocation.into(),
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+                "Invalid file location",
+            ),
+            (
+                StoreError::LocalFile.into(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "Local File System",
+            ),
+            (
+                QuarantineError::Redis(redis_refused()).into(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                "Redis is unavailable",
+            ),
+            (
+                ApiKeyError::Sqlite(sqlite(ffi::SQLITE_BUSY)).into(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                "Sqlite is busy",
+            ),
+            (
+                ApiKeyError::Serde(serde_error()).into(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "Serialization error",
+            ),
+            (
+                CancelTaskError::DocidNotFound.into(),
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "Doc ID Not Found",
+            ),
+            (
+                CancelTaskError::AlreadyFinished(ProcessingStage::Completed).into(),
+                StatusCode::CONFLICT,
+                "conflict",
+                "already finished as Completed",
+            ),
+            (
+                CancelTaskError::Status(DocStatusError::Redis(redis_refused())).into(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                "Redis is unavailable",
+            ),
+            (
+                IngestError::Queue(QueueError::LeaseLost).into(),
+                StatusCode::CONFLICT,
+                "conflict",
+                "lease expired",
+            ),
+            (
+                IngestError::Status(DocStatusError::DocidNotFound).into(),
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "Doc ID Not Found",
+            ),
+        ];
+
+        for (err, status, code, message) in cases {
+            let described = format!("{err:?}");
+            let response = err.into_response();
+            assert_eq!(response.status(), status, "{described}");
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: ApiErrorBody = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body.code, code, "{described}");
+            assert!(
+                body.message.contains(message),
+                "{described}: {:?} should contain {message:?}",
+                body.message
+            );
+        }
+    }
+}
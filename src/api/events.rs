@@ -0,0 +1,478 @@
+//! Push task status changes as they happen, over server-sent events or a websocket, so
+//! clients don't have to poll `/v1/status/{task_id}`.
+use std::{convert::Infallible, time::Duration};
+
+use aide::axum::ApiRouter;
+use aide::axum::routing::get;
+use axum::extract::ws::rejection::WebSocketUpgradeRejection;
+use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code};
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures::{Stream, StreamExt};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio::time::{Instant, interval_at, timeout};
+use tracing::{debug, warn};
+
+use crate::common::shutdown::SHUTDOWN;
+use crate::logic::{Stores, get_task_data_from_id};
+use crate::types::{ApiKeyRole, TaskEvent, TaskID};
+
+use super::auth::Caller;
+use super::error::ApiError;
+use super::{TaskIDParams, visible_task};
+
+/// How often a stream following one task re-reads its status, in case an event got lost.
+const TASK_RESYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Events held for a slow client before its stream stops reading the bus.
+const STREAM_BUFFER: usize = 64;
+
+/// Largest message taken from a websocket client, it has nothing to say beyond control frames.
+const MAX_CLIENT_MESSAGE: usize = 64 * 1024;
+
+/// How long a close we started waits for the client's close in return.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, JsonSchema)]
+struct EventStreamParams {
+    /// Only follow tasks submitted under this batch label.
+    batch: Option<String>,
+    /// Only follow tasks submitted with this API key. Defaults to the caller's key, admins
+    /// follow every key's tasks unless they set it.
+    api_key_id: Option<String>,
+}
+
+/// Which events a stream passes on to its client.
+struct EventFilter {
+    caller: Caller,
+    task_id: Option<TaskID>,
+    batch: Option<String>,
+    api_key_id: Option<String>,
+}
+
+impl EventFilter {
+    fn task(caller: Caller, task_id: TaskID) -> Self {
+        EventFilter {
+            caller,
+            task_id: Some(task_id),
+            batch: None,
+            api_key_id: None,
+        }
+    }
+
+    fn tasks(caller: Caller, params: EventStreamParams) -> Self {
+        let api_key_id = params.api_key_id.or_else(|| match &caller.0 {
+            Some(key) if key.role != ApiKeyRole::Admin => Some(key.id.clone()),
+            _ => None,
+        });
+        EventFilter {
+            caller,
+            task_id: None,
+            batch: params.batch,
+            api_key_id,
+        }
+    }
+
+    fn matches(&self, event: &TaskEvent) -> bool {
+        self.task_id
+            .is_none_or(|task_id| task_id == event.request_id)
+            && (self.batch.is_none() || event.batch == self.batch)
+            && (self.api_key_id.is_none() || event.api_key_id == self.api_key_id)
+            && self.caller.can_see_owner(event.owner.as_deref())
+    }
+}
+
+/// The followed task's current status, `None` for streams over many tasks.
+async fn current_event(stores: &Stores, filter: &EventFilter) -> Option<TaskEvent> {
+    let task_id = filter.task_id?;
+    match get_task_data_from_id(stores, task_id).await {
+        Ok(status) => Some(TaskEvent::from(&status)),
+        Err(err) => {
+            warn!(%err, task_id, "Could not re-read the followed task's status");
+            None
+        }
+    }
+}
+
+/// Pass the events matching `filter` on from a task of its own. A single task's stream
+/// starts with its current status and ends once the task finishes, every stream ends when
+/// the client goes away or the server shuts down.
+async fn follow(
+    stores: &Stores,
+    filter: EventFilter,
+) -> Result<mpsc::Receiver<TaskEvent>, ApiError> {
+    // Subscribe before reading the status, so no change slips in between.
+    let mut events = stores.event_bus.subscribe();
+    let mut pending = match filter.task_id {
+        Some(task_id) => Some(TaskEvent::from(
+            &visible_task(stores, &filter.caller, task_id).await?,
+        )),
+        None => None,
+    };
+    let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+    let stores = stores.clone();
+    tokio::spawn(async move {
+        let single_task = filter.task_id.is_some();
+        let mut last: Option<TaskEvent> = None;
+        let mut resync = interval_at(Instant::now() + TASK_RESYNC_INTERVAL, TASK_RESYNC_INTERVAL);
+        loop {
+            // Re-reading the status mostly finds what was already sent.
+            if let Some(event) = pending.take()
+                && !(single_task && last.as_ref() == Some(&event))
+            {
+                let finished = single_task && event.status.is_finished();
+                if sender.send(event.clone()).await.is_err() || finished {
+                    return;
+                }
+                last = Some(event);
+            }
+            pending = tokio::select! {
+                _ = sender.closed() => return,
+                _ = SHUTDOWN.cancelled() => return,
+                received = events.recv() => match received {
+                    Ok(event) if filter.matches(&event) => Some(event),
+                    Ok(_) => None,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(missed, "Task event stream fell behind the event bus");
+                        current_event(&stores, &filter).await
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                _ = resync.tick(), if single_task => current_event(&stores, &filter).await,
+            };
+        }
+    });
+    Ok(receiver)
+}
+
+fn event_stream(receiver: mpsc::Receiver<TaskEvent>) -> impl Stream<Item = TaskEvent> {
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        let event = receiver.recv().await?;
+        Some((event, receiver))
+    })
+}
+
+/// Server-sent events named `status`, each holding a json `TaskEvent`.
+fn sse_response(receiver: mpsc::Receiver<TaskEvent>) -> Response {
+    let stream = event_stream(receiver).map(|event| {
+        let data = serde_json::to_string(&event).expect("task events serialize to json");
+        Ok::<_, Infallible>(Event::default().event("status").data(data))
+    });
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// The upgrade of a websocket route, refusing plain requests with the usual json error.
+fn websocket_upgrade(
+    upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Result<WebSocketUpgrade, ApiError> {
+    upgrade.map_err(|rejection| {
+        ApiError::BadRequest(format!(
+            "Expected a websocket upgrade request: {}",
+            rejection.body_text()
+        ))
+    })
+}
+
+/// A text message holding a json `TaskEvent` per change.
+fn websocket_response(upgrade: WebSocketUpgrade, receiver: mpsc::Receiver<TaskEvent>) -> Response {
+    upgrade
+        .max_message_size(MAX_CLIENT_MESSAGE)
+        .on_upgrade(|socket| async move {
+            if let Err(err) = push_events(socket, receiver).await {
+                debug!(%err, "Websocket closed with an error");
+            }
+        })
+}
+
+/// Send events until the stream ends, then close the socket. Pings are answered for us,
+/// anything else the client sends is dropped.
+async fn push_events(
+    mut socket: WebSocket,
+    mut receiver: mpsc::Receiver<TaskEvent>,
+) -> Result<(), axum::Error> {
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let Some(event) = event else { break };
+                let text = serde_json::to_string(&event).expect("task events serialize to json");
+                socket.send(Message::Text(text.into())).await?;
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err),
+            },
+        }
+    }
+    let close = CloseFrame {
+        code: close_code::NORMAL,
+        reason: "".into(),
+    };
+    socket.send(Message::Close(Some(close))).await?;
+    // Give the client a moment to close in return.
+    let _ = timeout(CLOSE_TIMEOUT, async {
+        while let Some(Ok(_)) = socket.recv().await {}
+    })
+    .await;
+    Ok(())
+}
+
+/// Stream a task's status changes as server-sent events, ending once it finishes.
+async fn task_events(
+    State(stores): State<Stores>,
+    caller: Caller,
+    UrlPath(TaskIDParams { task_id }): UrlPath<TaskIDParams>,
+) -> Result<Response, ApiError> {
+    let events = follow(&stores, EventFilter::task(caller, task_id)).await?;
+    Ok(sse_response(events))
+}
+
+/// Stream a task's status changes over a websocket, closed once it finishes.
+async fn task_events_websocket(
+    State(stores): State<Stores>,
+    caller: Caller,
+    UrlPath(TaskIDParams { task_id }): UrlPath<TaskIDParams>,
+    upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Result<Response, ApiError> {
+    let upgrade = websocket_upgrade(upgrade)?;
+    let events = follow(&stores, EventFilter::task(caller, task_id)).await?;
+    Ok(websocket_response(upgrade, events))
+}
+
+/// Stream the status changes of every task of a batch or API key as server-sent events.
+async fn tasks_events(
+    State(stores): State<Stores>,
+    caller: Caller,
+    Query(params): Query<EventStreamParams>,
+) -> Result<Response, ApiError> {
+    let events = follow(&stores, EventFilter::tasks(caller, params)).await?;
+    Ok(sse_response(events))
+}
+
+/// Stream the status changes of every task of a batch or API key over a websocket.
+async fn tasks_events_websocket(
+    State(stores): State<Stores>,
+    caller: Caller,
+    Query(params): Query<EventStreamParams>,
+    upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Result<Response, ApiError> {
+    let upgrade = websocket_upgrade(upgrade)?;
+    let events = follow(&stores, EventFilter::tasks(caller, params)).await?;
+    Ok(websocket_response(upgrade, events))
+}
+
+/// Event stream routes, meant to be nested under `/v1/`.
+pub fn router() -> ApiRouter<Stores> {
+    ApiRouter::new()
+        .api_route("/status/{task_id}/events", get(task_events))
+        .api_route("/status/{task_id}/ws", get(task_events_websocket))
+        .api_route("/events", get(tasks_events))
+        .api_route("/events/ws", get(tasks_events_websocket))
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::api::auth::API_KEY_HEADER;
+    use crate::api::tests::{serve_api, stored_key};
+    use crate::logic::tests::memory_stores;
+    use crate::logic::{TASK_EVENT_BUFFER, update_task_data};
+    use crate::types::{
+        ApiKey, ConversionOptions, DocStatus, FileLocation, MarkdownConversionMethod,
+        ProcessingStage,
+    };
+
+    fn status(id: TaskID, owner: &str, stage: ProcessingStage) -> DocStatus {
+        let mut status = DocStatus::new_from_id_loc(
+            id,
+            FileLocation::LocalPath(format!("/tmp/{id}.pdf").into()),
+            MarkdownConversionMethod::Simple,
+            ConversionOptions::default(),
+        );
+        status.owner = Some(owner.to_string());
+        status.api_key_id = Some(format!("{owner}-key"));
+        status.status = stage;
+        status
+    }
+
+    async fn stages(receiver: &mut mpsc::Receiver<TaskEvent>) -> Vec<(TaskID, ProcessingStage)> {
+        let mut stages = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            stages.push((event.request_id, event.status));
+        }
+        stages
+    }
+
+    #[tokio::test]
+    async fn a_task_stream_follows_the_task_until_it_finishes() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        update_task_data(&stores, status(1, "alice", ProcessingStage::Waiting))
+            .await
+            .unwrap();
+        let mut events = follow(&stores, EventFilter::task(Caller::default(), 1))
+            .await
+            .unwrap();
+
+        for update in [
+            status(1, "alice", ProcessingStage::Processing),
+            status(2, "alice", ProcessingStage::Completed),
+            status(1, "alice", ProcessingStage::Completed),
+            // Anything after the task finished isn't passed on anymore.
+            status(1, "alice", ProcessingStage::Errored),
+        ] {
+            update_task_data(&stores, update).await.unwrap();
+        }
+        assert_eq!(
+            stages(&mut events).await,
+            [
+                (1, ProcessingStage::Waiting),
+                (1, ProcessingStage::Processing),
+                (1, ProcessingStage::Completed),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_over_many_tasks_only_pass_on_what_the_caller_may_see() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        let alice = ApiKey {
+            id: "alice-key".to_string(),
+            owner: "alice".to_string(),
+            role: ApiKeyRole::User,
+            daily_documents: None,
+            daily_pages: None,
+            created_at: 0,
+            webhook_secret: None,
+        };
+        let own_tasks = EventStreamParams {
+            batch: None,
+            api_key_id: None,
+        };
+        let mut own = follow(&stores, EventFilter::tasks(Caller(Some(alice)), own_tasks))
+            .await
+            .unwrap();
+        let batch = EventStreamParams {
+            batch: Some("reports".to_string()),
+            api_key_id: None,
+        };
+        let mut batched = follow(&stores, EventFilter::tasks(Caller::default(), batch))
+            .await
+            .unwrap();
+
+        let in_batch = |stage| DocStatus {
+            batch: Some("reports".to_string()),
+            ..status(3, "bob", stage)
+        };
+        for update in [
+            status(1, "alice", ProcessingStage::Waiting),
+            status(2, "bob", ProcessingStage::Waiting),
+            in_batch(ProcessingStage::Waiting),
+            status(1, "alice", ProcessingStage::Completed),
+            in_batch(ProcessingStage::Completed),
+        ] {
+            update_task_data(&stores, update).await.unwrap();
+        }
+        // Each stream's last event comes after everything it should have left out.
+        for (events, expected) in [(&mut own, 1), (&mut batched, 3)] {
+            let mut received = Vec::new();
+            for _ in 0..2 {
+                let event = events.recv().await.unwrap();
+                received.push((event.request_id, event.status));
+            }
+            assert_eq!(
+                received,
+                [
+                    (expected, ProcessingStage::Waiting),
+                    (expected, ProcessingStage::Completed),
+                ]
+            );
+            assert!(events.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn a_lagging_task_stream_resyncs_from_the_stored_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        update_task_data(&stores, status(1, "alice", ProcessingStage::Waiting))
+            .await
+            .unwrap();
+        let mut events = follow(&stores, EventFilter::task(Caller::default(), 1))
+            .await
+            .unwrap();
+
+        // The stream doesn't get to run until the test awaits it, so these overflow the bus.
+        stores
+            .status_store
+            .set_doc_status(status(1, "alice", ProcessingStage::Completed))
+            .await
+            .unwrap();
+        let mut processing = status(1, "alice", ProcessingStage::Processing);
+        for attempts in 0..2 * TASK_EVENT_BUFFER as u32 {
+            processing.attempts = attempts;
+            stores
+                .event_bus
+                .publish(TaskEvent::from(&processing))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            stages(&mut events).await,
+            [
+                (1, ProcessingStage::Waiting),
+                (1, ProcessingStage::Completed)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn server_sent_events_end_once_the_task_finishes() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        let secret = stored_key(&stores, "alice", ApiKeyRole::User, None).await;
+        update_task_data(&stores, status(1, "alice", ProcessingStage::Waiting))
+            .await
+            .unwrap();
+        let base_url = serve_api(stores.clone()).await;
+
+        // Headers only come back once the stream is subscribed to the bus.
+        let response = reqwest::Client::new()
+            .get(format!("{base_url}/v1/status/1/events"))
+            .header(API_KEY_HEADER, &secret)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        update_task_data(&stores, status(1, "alice", ProcessingStage::Processing))
+            .await
+            .unwrap();
+        update_task_data(&stores, status(1, "alice", ProcessingStage::Completed))
+            .await
+            .unwrap();
+
+        let body = response.text().await.unwrap();
+        let statuses: Vec<ProcessingStage> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str::<TaskEvent>(data).unwrap().status)
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ProcessingStage::Waiting,
+                ProcessingStage::Processing,
+                ProcessingStage::Completed,
+            ]
+        );
+        assert!(body.contains("event: status"));
+    }
+}
@@ -0,0 +1,225 @@
+//! Routes mirroring datalab's Marker API, so clients written against datalab can point at
+//! Crimson without code changes.
+use std::collections::HashMap;
+
+use aide::axum::ApiRouter;
+use aide::axum::routing::{get, post};
+use axum::Json;
+use axum::extract::{Multipart, Path as UrlPath, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::common::shutdown::reject_while_shutting_down;
+use crate::logic::Stores;
+use crate::types::{DOMAIN, DocStatus, ProcessingStage, TaskID};
+
+use super::auth::Caller;
+use super::error::{ApiError, ApiErrorBody};
+use super::rate_limit::limit_ingest;
+use super::{
+    ingest_uploaded_form, make_task_id, read_upload_form, upload_body_limit, visible_task,
+};
+
+/// Response to a marker submission.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct MarkerIngestResponse {
+    success: bool,
+    error: Option<String>,
+    request_id: Option<String>,
+    request_check_url: Option<String>,
+}
+
+impl MarkerIngestResponse {
+    fn failed(err: ApiError) -> (StatusCode, Json<Self>) {
+        (err.status(), Json(Self::failure(err.to_string())))
+    }
+
+    fn failure(error: String) -> Self {
+        MarkerIngestResponse {
+            success: false,
+            error: Some(error),
+            request_id: None,
+            request_check_url: None,
+        }
+    }
+}
+
+/// Middleware answering the errors of the layers around the marker routes, like a missing
+/// key or a rate limit, in marker's shape rather than as an `ApiErrorBody`. Layered outside
+/// `require_api_key`, so it sees its rejections too.
+pub async fn marker_error_shape(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    let Some(body) = response.extensions().get::<ApiErrorBody>().cloned() else {
+        return response;
+    };
+    let (parts, _) = response.into_parts();
+    (parts, Json(MarkerIngestResponse::failure(body.message))).into_response()
+}
+
+/// Response to polling a marker `request_check_url`.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct MarkerStatusResponse {
+    output_format: String,
+    markdown: Option<String>,
+    /// `processing` until the task finishes, then `complete` whether or not it succeeded.
+    status: String,
+    success: Option<bool>,
+    images: Option<HashMap<String, String>>,
+    metadata: Option<HashMap<String, String>>,
+    error: Option<String>,
+    page_count: Option<u32>,
+}
+
+impl From<DocStatus> for MarkerStatusResponse {
+    fn from(input: DocStatus) -> Self {
+        let (status, success) = match input.status {
+            ProcessingStage::Waiting | ProcessingStage::Processing => ("processing", None),
+            ProcessingStage::Completed => ("complete", Some(true)),
+            ProcessingStage::Errored | ProcessingStage::Cancelled => ("complete", Some(false)),
+        };
+        MarkerStatusResponse {
+            output_format: "markdown".to_string(),
+            markdown: input.markdown,
+            status: status.to_string(),
+            success,
+            images: input.images,
+            metadata: input.metadata,
+            error: input.error,
+            page_count: input.page_count,
+        }
+    }
+}
+
+fn make_marker_check_url(id: TaskID) -> String {
+    format!("{}/api/v1/marker/{id}", *DOMAIN)
+}
+
+/// Submit a document for conversion using datalab's marker form fields.
+async fn marker_ingest(
+    State(stores): State<Stores>,
+    caller: Caller,
+    multipart: Multipart,
+) -> Result<Json<MarkerIngestResponse>, (StatusCode, Json<MarkerIngestResponse>)> {
+    caller
+        .check_quota(&stores)
+        .await
+        .map_err(MarkerIngestResponse::failed)?;
+    let task_id: TaskID = make_task_id();
+    let form = read_upload_form(task_id, multipart)
+        .await
+        .map_err(MarkerIngestResponse::failed)?;
+    ingest_uploaded_form(&stores, &caller, task_id, form)
+        .await
+        .map_err(MarkerIngestResponse::failed)?;
+    Ok(Json(MarkerIngestResponse {
+        success: true,
+        error: None,
+        request_id: Some(task_id.to_string()),
+        request_check_url: Some(make_marker_check_url(task_id)),
+    }))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct MarkerRequestIdParams {
+    request_id: String,
+}
+
+/// Poll the result of a marker submission.
+async fn marker_get_status(
+    State(stores): State<Stores>,
+    caller: Caller,
+    UrlPath(MarkerRequestIdParams { request_id }): UrlPath<MarkerRequestIdParams>,
+) -> Result<Json<MarkerStatusResponse>, ApiError> {
+    let task_id: TaskID = request_id
+        .parse()
+        .map_err(|_| ApiError::NotFound(format!("Unknown request_id: {request_id}")))?;
+    visible_task(&stores, &caller, task_id)
+        .await
+        .map(|status| Json(status.into()))
+}
+
+/// Marker compatible router, meant to be nested under `/api/v1/`.
+pub fn router() -> ApiRouter<Stores> {
+    let ingest_routes = ApiRouter::new()
+        .api_route("/marker", post(marker_ingest))
+        .route_layer(upload_body_limit())
+        .route_layer(middleware::from_fn(limit_ingest))
+        .route_layer(middleware::from_fn(reject_while_shutting_down));
+    ApiRouter::new()
+        .api_route("/marker/{request_id}", get(marker_get_status))
+        .merge(ingest_routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use crate::api::auth::API_KEY_HEADER;
+    use crate::api::tests::{remove_upload, serve_api, stored_key, upload, upload_form};
+    use crate::logic::tests::memory_stores;
+    use crate::types::ApiKeyRole;
+
+    fn assert_marker_error(body: &serde_json::Value, message: &str) {
+        assert_eq!(body["success"], false, "{body}");
+        assert!(body.get("code").is_none(), "{body}");
+        let error = body["error"].as_str().unwrap();
+        assert!(error.contains(message), "{error}");
+    }
+
+    #[tokio::test]
+    async fn rejected_keys_get_marker_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        stored_key(&stores, "alice", ApiKeyRole::User, None).await;
+        let base_url = serve_api(stores).await;
+        let client = reqwest::Client::new();
+
+        let missing = client
+            .post(format!("{base_url}/api/v1/marker"))
+            .multipart(upload_form(&[]))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+        assert_marker_error(&missing.json().await.unwrap(), "Missing");
+
+        let (status, body) = upload(
+            &base_url,
+            "/api/v1/marker",
+            "crimson_nope",
+            upload_form(&[]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_marker_error(&body, "Invalid API key");
+
+        let poll = client
+            .get(format!("{base_url}/api/v1/marker/1"))
+            .header(API_KEY_HEADER, "crimson_nope")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(poll.status(), StatusCode::UNAUTHORIZED);
+        assert_marker_error(&poll.json().await.unwrap(), "Invalid API key");
+    }
+
+    #[tokio::test]
+    async fn submissions_over_quota_get_marker_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        let secret = stored_key(&stores, "alice", ApiKeyRole::User, Some(1)).await;
+        let base_url = serve_api(stores).await;
+
+        let (status, body) = upload(&base_url, "/api/v1/marker", &secret, upload_form(&[])).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["success"], true);
+        remove_upload(body["request_id"].as_str().unwrap().parse().unwrap());
+
+        let (status, body) = upload(&base_url, "/api/v1/marker", &secret, upload_form(&[])).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_marker_error(&body, "1 documents");
+    }
+}
@@ -1,59 +1,415 @@
+pub mod auth;
+pub mod error;
+pub mod events;
+pub mod marker;
+pub mod rate_limit;
+pub mod webhooks;
+
 use aide::axum::ApiRouter;
-use aide::axum::routing::{get, post};
+use aide::axum::routing::{delete, get, post};
 use axum::Json;
-use axum::extract::{Multipart, Path};
+use axum::extract::multipart::Field;
+use axum::extract::{DefaultBodyLimit, Multipart, Path as UrlPath, State};
+use axum::middleware;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::env;
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
 
-use crate::logic::{get_task_data_from_id, ingest_file_to_queue};
+use crate::common::shutdown::reject_while_shutting_down;
+use crate::logic::{
+    Stores, api_key_usage_today, cancel_task, find_quarantined, get_task_data_from_id, hash_file,
+    ingest_file_to_queue, make_upload_path, upload_file_to_store,
+};
+use crate::processing::check_conversion_options;
 use crate::types::{
-    DocStatus, DocStatusResponse, FileLocation, MarkdownConversionMethod, S3Location, StoreError,
-    TaskID,
+    ApiKeyInfo, ConversionOptions, DocStatus, DocStatusError, DocStatusResponse, FileLocation,
+    LocalPath, MarkdownConversionMethod, S3Location, TaskID, TaskPriority,
 };
 
-async fn pdf_ingest(mut multipart: Multipart) -> Result<Json<DocStatusResponse>, String> {
+use auth::Caller;
+use error::ApiError;
+use rate_limit::limit_ingest;
+use webhooks::check_callback_url;
+
+/// Largest request body the upload routes take, axum's default of 2MB is too little for
+/// scanned documents.
+pub static MAX_UPLOAD_BYTES: LazyLock<usize> = LazyLock::new(|| {
+    env::var("MAX_UPLOAD_BYTES")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(256 * 1024 * 1024)
+});
+
+/// Body limit for routes taking a document upload.
+pub fn upload_body_limit() -> DefaultBodyLimit {
+    DefaultBodyLimit::max(*MAX_UPLOAD_BYTES)
+}
+
+async fn pdf_ingest(
+    State(stores): State<Stores>,
+    caller: Caller,
+    multipart: Multipart,
+) -> Result<Json<DocStatusResponse>, ApiError> {
+    caller.check_quota(&stores).await?;
     let task_id: TaskID = make_task_id();
-    while let Some(field) = multipart.next_field().await.unwrap()
-        && let Some(name) = field.name()
+    let form = read_upload_form(task_id, multipart).await?;
+    let task_status = ingest_uploaded_form(&stores, &caller, task_id, form).await?;
+    Ok(Json(task_status.into()))
+}
+
+/// An upload written to local disk, along with the form fields that came with it.
+struct UploadedForm {
+    params: DocIngestParamsUpload,
+    upload: UploadCleanup,
+    upload_key: String,
+}
+
+/// Deletes an upload from local disk when dropped, unless it was kept with `keep` once the
+/// task is queued. Every way out of reading or ingesting an upload goes through it, so
+/// failures never leave the file behind.
+struct UploadCleanup {
+    local_path: Option<LocalPath>,
+}
+
+impl UploadCleanup {
+    fn new(local_path: LocalPath) -> Self {
+        UploadCleanup {
+            local_path: Some(local_path),
+        }
+    }
+
+    fn path(&self) -> &Path {
+        self.local_path
+            .as_deref()
+            .expect("Only taken when kept or dropped")
+    }
+
+    fn keep(mut self) {
+        self.local_path = None;
+    }
+}
+
+impl Drop for UploadCleanup {
+    fn drop(&mut self) {
+        if let Some(local_path) = self.local_path.take() {
+            let _ = std::fs::remove_file(&local_path);
+            // The task's upload directory, only removed once empty.
+            if let Some(parent) = local_path.parent() {
+                let _ = std::fs::remove_dir(parent);
+            }
+        }
+    }
+}
+
+/// Read every field of a multipart upload, streaming the file field to disk as it arrives.
+async fn read_upload_form(
+    task_id: TaskID,
+    mut multipart: Multipart,
+) -> Result<UploadedForm, ApiError> {
+    let mut params = DocIngestParamsUpload::default();
+    let mut uploaded: Option<(UploadCleanup, String)> = None;
+    while let Some(field) = multipart.next_field().await? {
+        let Some(name) = field.name().map(str::to_string) else {
+            continue;
+        };
+        match name.as_str() {
+            "file" => {
+                if uploaded.is_some() {
+                    return Err(ApiError::BadRequest(
+                        "Multipart had more than one file field".to_string(),
+                    ));
+                }
+                let file_name = sanitize_file_name(field.file_name());
+                params.original_filename = field.file_name().map(str::to_string);
+                params.content_type = field.content_type().map(str::to_string);
+                let upload = UploadCleanup::new(make_upload_path(task_id, &file_name));
+                save_upload_field(field, upload.path()).await?;
+                uploaded = Some((upload, format!("uploads/{task_id}/{file_name}")));
+            }
+            "conversion_method" => {
+                let text = field_text(field).await?;
+                params.conversion_method = Some(
+                    serde_json::from_value(serde_json::Value::String(text.clone())).map_err(
+                        |_| ApiError::BadRequest(format!("Unknown conversion_method: {text}")),
+                    )?,
+                );
+            }
+            "priority" => {
+                let text = field_text(field).await?;
+                params.priority = Some(
+                    serde_json::from_value(serde_json::Value::String(text.clone()))
+                        .map_err(|_| ApiError::BadRequest(format!("Unknown priority: {text}")))?,
+                );
+            }
+            "owner" => params.owner = Some(field_text(field).await?),
+            "batch" => params.batch = Some(field_text(field).await?),
+            // datalab calls it `webhook_url`.
+            "callback_url" | "webhook_url" => params.callback_url = Some(field_text(field).await?),
+            "langs" => params.langs = Some(field_text(field).await?),
+            "output_format" => params.output_format = Some(field_text(field).await?),
+            "force_ocr" => params.force_ocr = Some(parse_form_bool(&field_text(field).await?)?),
+            "paginate" => params.paginate = Some(parse_form_bool(&field_text(field).await?)?),
+            "use_llm" => params.use_llm = Some(parse_form_bool(&field_text(field).await?)?),
+            "strip_existing_ocr" => {
+                params.strip_existing_ocr = Some(parse_form_bool(&field_text(field).await?)?)
+            }
+            "disable_image_extraction" => {
+                params.disable_image_extraction = Some(parse_form_bool(&field_text(field).await?)?)
+            }
+            "max_pages" => {
+                let text = field_text(field).await?;
+                let max_pages = text.trim().parse().ok().filter(|max_pages| *max_pages > 0);
+                params.max_pages = Some(max_pages.ok_or_else(|| {
+                    ApiError::BadRequest(format!("max_pages must be a positive integer: {text}"))
+                })?);
+            }
+            _ => {}
+        }
+    }
+    let Some((upload, upload_key)) = uploaded else {
+        return Err(ApiError::BadRequest(
+            "Multipart was missing the file field".to_string(),
+        ));
+    };
+    Ok(UploadedForm {
+        params,
+        upload,
+        upload_key,
+    })
+}
+
+/// Validate the options of an upload, hand the file to the file store and enqueue it.
+async fn ingest_uploaded_form(
+    stores: &Stores,
+    caller: &Caller,
+    task_id: TaskID,
+    form: UploadedForm,
+) -> Result<DocStatus, ApiError> {
+    let UploadedForm {
+        params,
+        upload,
+        upload_key,
+    } = form;
+    if let Some(output_format) = &params.output_format
+        && output_format != "markdown"
     {
-        if name == "file" {
-            let _bytes = field.bytes().await.expect("should be bytes for pdf field");
-            let file_path = "/tmp/save/to/this/path".to_string() + &task_id.to_string();
-            let file_location = FileLocation::LocalPath(file_path.into());
-            let task_status = DocStatus::new_from_id_loc(
-                task_id,
-                file_location,
-                MarkdownConversionMethod::default(),
-            );
-            ingest_file_to_queue(task_status.clone()).await;
-            return Ok(Json(task_status.into()));
+        return Err(ApiError::BadRequest(format!(
+            "Unsupported output_format: {output_format}, only markdown is supported"
+        )));
+    }
+    let conversion_method = params.conversion_method.unwrap_or_default();
+    let conversion_options = ConversionOptions {
+        use_llm: params.use_llm.unwrap_or_default(),
+        strip_existing_ocr: params.strip_existing_ocr.unwrap_or_default(),
+        ..ConversionOptions::new(
+            params.langs.as_deref(),
+            params.force_ocr,
+            params.paginate,
+            params.disable_image_extraction,
+            params.max_pages,
+        )
+    };
+    check_conversion_options(&conversion_method, &conversion_options)
+        .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+    if let Some(callback_url) = &params.callback_url {
+        check_callback_url(caller, callback_url).await?;
+    }
+    let content_hash = hash_file(upload.path())
+        .await
+        .map_err(|err| ApiError::Internal(format!("Could not hash uploaded file: {err}")))?;
+    reject_if_quarantined(stores, &content_hash).await?;
+    let reserved_on = caller.reserve_document(stores).await?;
+    let queued = async {
+        let file_location =
+            upload_file_to_store(stores, upload.path().to_path_buf(), upload_key).await?;
+        let mut task_status = DocStatus::new_from_id_loc(
+            task_id,
+            file_location,
+            conversion_method,
+            conversion_options,
+        );
+        task_status.priority = params.priority.unwrap_or_default();
+        caller.claim(&mut task_status, params.owner);
+        task_status.batch = params.batch;
+        task_status.callback_url = params.callback_url;
+        task_status.original_filename = params.original_filename;
+        task_status.content_type = params.content_type;
+        task_status.content_hash = Some(content_hash);
+        ingest_file_to_queue(stores, task_status.clone()).await?;
+        Ok(task_status)
+    }
+    .await;
+    match queued {
+        Ok(task_status) => {
+            upload.keep();
+            Ok(task_status)
+        }
+        Err(err) => {
+            caller.refund_document(stores, reserved_on).await;
+            Err(err)
         }
     }
-    Err("Multipart was improperely formatted".into())
 }
+
+/// Charge the document to the caller's daily quota and enqueue it, refunding the charge if
+/// it couldn't be queued.
+async fn enqueue_task(
+    stores: &Stores,
+    caller: &Caller,
+    status: &DocStatus,
+) -> Result<(), ApiError> {
+    let reserved_on = caller.reserve_document(stores).await?;
+    if let Err(err) = ingest_file_to_queue(stores, status.clone()).await {
+        caller.refund_document(stores, reserved_on).await;
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Refuse documents that were quarantined for repeatedly crashing or timing out converters.
+async fn reject_if_quarantined(stores: &Stores, content_hash: &str) -> Result<(), ApiError> {
+    match find_quarantined(stores, content_hash).await? {
+        None => Ok(()),
+        Some(_) => Err(ApiError::Conflict(format!(
+            "Document {content_hash} is quarantined after repeatedly crashing or timing out converters"
+        ))),
+    }
+}
+
+/// Stream a multipart field to disk chunk by chunk, so large uploads never sit in memory.
+async fn save_upload_field(mut field: Field<'_>, local_path: &Path) -> Result<(), ApiError> {
+    let write_failed =
+        |err: std::io::Error| ApiError::Internal(format!("Could not write uploaded file: {err}"));
+    if let Some(parent) = local_path.parent() {
+        fs::create_dir_all(parent).await.map_err(write_failed)?;
+    }
+    let mut file = fs::File::create(local_path).await.map_err(write_failed)?;
+    while let Some(chunk) = field.chunk().await? {
+        file.write_all(&chunk).await.map_err(write_failed)?;
+    }
+    file.flush().await.map_err(write_failed)?;
+    Ok(())
+}
+
+async fn field_text(field: Field<'_>) -> Result<String, ApiError> {
+    Ok(field.text().await?)
+}
+
+/// Form encoders disagree on booleans, python for instance sends `True`/`False`.
+fn parse_form_bool(text: &str) -> Result<bool, ApiError> {
+    match text.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" | "" => Ok(false),
+        other => Err(ApiError::BadRequest(format!(
+            "Expected a boolean form value, got: {other}"
+        ))),
+    }
+}
+
+/// Only keep the final path component of a client supplied filename, so uploads can't
+/// escape their task directory.
+fn sanitize_file_name(file_name: Option<&str>) -> String {
+    file_name
+        .and_then(|name| Path::new(name).file_name())
+        .and_then(|name| name.to_str())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("upload.pdf")
+        .to_string()
+}
+
 async fn pdf_ingest_s3(
+    State(stores): State<Stores>,
+    caller: Caller,
     Json(ingest_params): Json<DocIngestParamsS3>,
-) -> Result<Json<DocStatusResponse>, String> {
+) -> Result<Json<DocStatusResponse>, ApiError> {
+    caller.check_quota(&stores).await?;
     let task_id: TaskID = make_task_id();
-    let s3_result: Result<S3Location, StoreError> = ingest_params.s3_uri.try_into();
-    let file_location = FileLocation::S3Location(s3_result.map_err(|err| err.to_string())?);
+    let s3_location = S3Location::try_from(ingest_params.s3_uri.clone()).map_err(|_| {
+        ApiError::BadRequest(format!(
+            "Invalid s3_uri, expected https://{{bucket}}.{{region}}.{{host}}/{{key}}: {}",
+            ingest_params.s3_uri
+        ))
+    })?;
+    let file_location = FileLocation::S3Location(s3_location);
     let conversion_method = ingest_params.conversion_method.unwrap_or_default();
-    let task_status = DocStatus::new_from_id_loc(task_id, file_location, conversion_method);
-    ingest_file_to_queue(task_status.clone()).await;
+    let conversion_options = ConversionOptions {
+        use_llm: ingest_params.use_llm.unwrap_or_default(),
+        strip_existing_ocr: ingest_params.strip_existing_ocr.unwrap_or_default(),
+        ..ConversionOptions::new(
+            ingest_params.langs.as_deref(),
+            ingest_params.force_ocr,
+            ingest_params.paginate,
+            ingest_params.disable_image_extraction,
+            ingest_params.max_pages,
+        )
+    };
+    check_conversion_options(&conversion_method, &conversion_options)
+        .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+    if let Some(callback_url) = &ingest_params.callback_url {
+        check_callback_url(&caller, callback_url).await?;
+    }
+    let mut task_status = DocStatus::new_from_id_loc(
+        task_id,
+        file_location,
+        conversion_method,
+        conversion_options,
+    );
+    task_status.priority = ingest_params.priority.unwrap_or_default();
+    caller.claim(&mut task_status, ingest_params.owner);
+    task_status.batch = ingest_params.batch;
+    task_status.callback_url = ingest_params.callback_url;
+    enqueue_task(&stores, &caller, &task_status).await?;
     Ok(Json(task_status.into()))
 }
 
 async fn pdf_ingest_debug_local_path(
+    State(stores): State<Stores>,
+    caller: Caller,
     Json(ingest_params): Json<DocIngestParamsDebugLocalPath>,
-) -> Result<Json<DocStatusResponse>, String> {
+) -> Result<Json<DocStatusResponse>, ApiError> {
+    caller.check_quota(&stores).await?;
     let task_id: TaskID = make_task_id();
-    let file_location = FileLocation::LocalPath(ingest_params.local_path);
     let conversion_method = ingest_params.conversion_method.unwrap_or_default();
-    let task_status = DocStatus::new_from_id_loc(task_id, file_location, conversion_method);
-    ingest_file_to_queue(task_status.clone()).await;
+    let conversion_options = ConversionOptions {
+        use_llm: ingest_params.use_llm.unwrap_or_default(),
+        strip_existing_ocr: ingest_params.strip_existing_ocr.unwrap_or_default(),
+        ..ConversionOptions::new(
+            ingest_params.langs.as_deref(),
+            ingest_params.force_ocr,
+            ingest_params.paginate,
+            ingest_params.disable_image_extraction,
+            ingest_params.max_pages,
+        )
+    };
+    check_conversion_options(&conversion_method, &conversion_options)
+        .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+    if let Some(callback_url) = &ingest_params.callback_url {
+        check_callback_url(&caller, callback_url).await?;
+    }
+    // A path that can't be read is left for the worker to report.
+    let content_hash = hash_file(&ingest_params.local_path).await.ok();
+    if let Some(content_hash) = &content_hash {
+        reject_if_quarantined(&stores, content_hash).await?;
+    }
+    let file_location = FileLocation::LocalPath(ingest_params.local_path);
+    let mut task_status = DocStatus::new_from_id_loc(
+        task_id,
+        file_location,
+        conversion_method,
+        conversion_options,
+    );
+    task_status.priority = ingest_params.priority.unwrap_or_default();
+    caller.claim(&mut task_status, ingest_params.owner);
+    task_status.batch = ingest_params.batch;
+    task_status.callback_url = ingest_params.callback_url;
+    task_status.content_hash = content_hash;
+    enqueue_task(&stores, &caller, &task_status).await?;
     Ok(Json(task_status.into()))
 }
 
@@ -63,22 +419,123 @@ struct TaskIDParams {
 }
 
 async fn pdf_get_status(
-    Path(TaskIDParams { task_id }): Path<TaskIDParams>,
-) -> Json<DocStatusResponse> {
-    Json(get_task_data_from_id(task_id).await.unwrap().into())
+    State(stores): State<Stores>,
+    caller: Caller,
+    UrlPath(TaskIDParams { task_id }): UrlPath<TaskIDParams>,
+) -> Result<Json<DocStatusResponse>, ApiError> {
+    visible_task(&stores, &caller, task_id)
+        .await
+        .map(|status| Json(status.into()))
+}
+
+/// Cancel a task that hasn't finished yet, aborting its conversion if one is running.
+async fn pdf_cancel_task(
+    State(stores): State<Stores>,
+    caller: Caller,
+    UrlPath(TaskIDParams { task_id }): UrlPath<TaskIDParams>,
+) -> Result<Json<DocStatusResponse>, ApiError> {
+    visible_task(&stores, &caller, task_id).await?;
+    let status = cancel_task(&stores, task_id).await?;
+    Ok(Json(status.into()))
+}
+
+/// Look up a task the caller may see. Other owners' tasks are reported as missing, so keys
+/// can't probe which ids exist.
+async fn visible_task(
+    stores: &Stores,
+    caller: &Caller,
+    task_id: TaskID,
+) -> Result<DocStatus, ApiError> {
+    match get_task_data_from_id(stores, task_id).await? {
+        status if caller.can_see(&status) => Ok(status),
+        _ => Err(DocStatusError::DocidNotFound.into()),
+    }
+}
+
+/// The caller's key with its quotas and what it used so far today.
+async fn get_usage(
+    State(stores): State<Stores>,
+    Caller(key): Caller,
+) -> Result<Json<ApiKeyInfo>, ApiError> {
+    let Some(key) = key else {
+        return Err(ApiError::NotFound(
+            "Requests aren't authenticated, so there is no API key to report on".to_string(),
+        ));
+    };
+    let usage_today = api_key_usage_today(&stores, &key.id).await?;
+    Ok(Json(ApiKeyInfo {
+        configured: auth::is_configured_key(&key.id),
+        key,
+        usage_today,
+    }))
 }
 
 /// Docs module router
-pub fn router() -> ApiRouter {
-    ApiRouter::new()
+pub fn router() -> ApiRouter<Stores> {
+    let upload_routes = ApiRouter::new()
         // .api_route("/ingest", post(pdf_ingest))
-        .api_route("/status/{task_id}", get(pdf_get_status))
         .api_route("/ingest/upload", post(pdf_ingest))
+        .route_layer(upload_body_limit());
+    let ingest_routes = upload_routes
         .api_route("/ingest/s3", post(pdf_ingest_s3))
+        .route_layer(middleware::from_fn(limit_ingest))
+        .route_layer(middleware::from_fn(reject_while_shutting_down));
+    ApiRouter::new()
+        .api_route("/status/{task_id}", get(pdf_get_status))
+        .api_route("/task/{task_id}", delete(pdf_cancel_task))
+        .api_route("/usage", get(get_usage))
+        .merge(events::router())
+        .merge(webhooks::router())
+        .merge(ingest_routes)
+}
+
+/// Routes that have the worker read paths off the server's own disk, so they are meant to sit
+/// behind `require_admin_key` rather than `require_api_key`. Nested under `/v1/` as well.
+pub fn debug_router() -> ApiRouter<Stores> {
+    ApiRouter::new()
         .api_route(
             "/ingest/debug_local_path",
             post(pdf_ingest_debug_local_path),
         )
+        .route_layer(middleware::from_fn(limit_ingest))
+        .route_layer(middleware::from_fn(reject_while_shutting_down))
+}
+
+/// Form fields accepted alongside the file on a multipart upload.
+#[derive(Default, Debug)]
+pub struct DocIngestParamsUpload {
+    /// Filename the client attached to the file field.
+    pub original_filename: Option<String>,
+    /// Content type the client attached to the file field.
+    pub content_type: Option<String>,
+    /// Optional comma-separated list of languages for OCR (e.g., "en,fr").
+    pub langs: Option<String>,
+    /// What method do you want to use to convert the markdown
+    pub conversion_method: Option<MarkdownConversionMethod>,
+    /// How urgently to convert it: `interactive`, `normal` (the default) or `bulk`.
+    pub priority: Option<TaskPriority>,
+    /// Who the task is converted for, conversions are shared fairly between owners. Only
+    /// admin keys may set it, other keys' tasks belong to the key's owner.
+    pub owner: Option<String>,
+    /// Label to group tasks under, `GET /v1/events?batch=` follows all of them at once.
+    pub batch: Option<String>,
+    /// Where to post the final status once the task completes or errors, see
+    /// `GET /v1/status/{task_id}/webhook` for how that went.
+    pub callback_url: Option<String>,
+    /// Force OCR on every page.
+    pub force_ocr: Option<bool>,
+    /// Paginate output with page delimiters.
+    pub paginate: Option<bool>,
+    /// Use an LLM to improve the accuracy of forms, tables and layout.
+    pub use_llm: Option<bool>,
+    /// Throw away any existing OCR text layer and redo OCR.
+    pub strip_existing_ocr: Option<bool>,
+    /// Disable image extraction.
+    pub disable_image_extraction: Option<bool>,
+    /// Maximum number of pages to process from the start.
+    pub max_pages: Option<u32>,
+    /// Requested output format, only `markdown` is produced.
+    pub output_format: Option<String>,
 }
 
 /// Parameters for ingesting a document.
@@ -109,11 +566,25 @@ pub struct DocIngestParamsS3 {
 
     /// What method do you want to use to convert the markdown
     pub conversion_method: Option<MarkdownConversionMethod>,
+    /// How urgently to convert it: `interactive`, `normal` (the default) or `bulk`.
+    pub priority: Option<TaskPriority>,
+    /// Who the task is converted for, conversions are shared fairly between owners. Only
+    /// admin keys may set it, other keys' tasks belong to the key's owner.
+    pub owner: Option<String>,
+    /// Label to group tasks under, `GET /v1/events?batch=` follows all of them at once.
+    pub batch: Option<String>,
+    /// Where to post the final status once the task completes or errors, see
+    /// `GET /v1/status/{task_id}/webhook` for how that went.
+    pub callback_url: Option<String>,
 
     /// Force OCR on every page.
     pub force_ocr: Option<bool>,
     /// Paginate output with page delimiters.
     pub paginate: Option<bool>,
+    /// Use an LLM to improve the accuracy of forms, tables and layout.
+    pub use_llm: Option<bool>,
+    /// Throw away any existing OCR text layer and redo OCR.
+    pub strip_existing_ocr: Option<bool>,
     /// Disable image extraction.
     pub disable_image_extraction: Option<bool>,
     /// Maximum number of pages to process from the start.
@@ -131,8 +602,22 @@ pub struct DocIngestParamsDebugLocalPath {
     pub force_ocr: Option<bool>,
     /// What method do you want to use to convert the markdown
     pub conversion_method: Option<MarkdownConversionMethod>,
+    /// How urgently to convert it: `interactive`, `normal` (the default) or `bulk`.
+    pub priority: Option<TaskPriority>,
+    /// Who the task is converted for, conversions are shared fairly between owners. Only
+    /// admin keys may set it, other keys' tasks belong to the key's owner.
+    pub owner: Option<String>,
+    /// Label to group tasks under, `GET /v1/events?batch=` follows all of them at once.
+    pub batch: Option<String>,
+    /// Where to post the final status once the task completes or errors, see
+    /// `GET /v1/status/{task_id}/webhook` for how that went.
+    pub callback_url: Option<String>,
     /// Paginate output with page delimiters.
     pub paginate: Option<bool>,
+    /// Use an LLM to improve the accuracy of forms, tables and layout.
+    pub use_llm: Option<bool>,
+    /// Throw away any existing OCR text layer and redo OCR.
+    pub strip_existing_ocr: Option<bool>,
     /// Disable image extraction.
     pub disable_image_extraction: Option<bool>,
     /// Maximum number of pages to process from the start.
@@ -147,3 +632,193 @@ fn make_task_id() -> TaskID {
         .expect("Failed to read random bytes");
     u64::from_ne_bytes(bytes)
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use axum::middleware::from_fn_with_state;
+    use reqwest::multipart::{Form, Part};
+    use serde_json::Value;
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::api::auth::{generate_api_key_secret, require_admin_key, require_api_key};
+    use crate::logic::tests::memory_stores;
+    use crate::logic::{hash_api_key, store_api_key};
+    use crate::types::{ApiKey, ApiKeyRole, MarkdownConversionMethod, unix_millis};
+
+    /// Serve the `/v1/`, `/api/v1/` and `/admin/` routes on a local port, guarded like in
+    /// `run`. Returns the base url.
+    pub(crate) async fn serve_api(stores: Stores) -> String {
+        let app: axum::Router = ApiRouter::new()
+            .nest(
+                "/v1/",
+                router().route_layer(from_fn_with_state(stores.clone(), require_api_key)),
+            )
+            .nest(
+                "/api/v1/",
+                marker::router()
+                    .route_layer(from_fn_with_state(stores.clone(), require_api_key))
+                    .route_layer(middleware::from_fn(marker::marker_error_shape)),
+            )
+            .nest(
+                "/admin/",
+                crate::admin::router()
+                    .route_layer(from_fn_with_state(stores.clone(), require_admin_key)),
+            )
+            .with_state(stores)
+            .into();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{address}")
+    }
+
+    /// Store a key for `owner`, returning the secret to send with requests.
+    pub(crate) async fn stored_key(
+        stores: &Stores,
+        owner: &str,
+        role: ApiKeyRole,
+        daily_documents: Option<u64>,
+    ) -> String {
+        let secret = generate_api_key_secret();
+        let key = ApiKey {
+            id: hash_api_key(&secret),
+            owner: owner.to_string(),
+            role,
+            daily_documents,
+            daily_pages: None,
+            created_at: unix_millis(SystemTime::now()),
+            webhook_secret: None,
+        };
+        store_api_key(stores, key).await.unwrap();
+        secret
+    }
+
+    /// A multipart upload of a small file named `doc.pdf`, with the given form fields.
+    pub(crate) fn upload_form(fields: &[(&'static str, &'static str)]) -> Form {
+        let file = Part::bytes(b"%PDF-1.4 test".to_vec()).file_name("doc.pdf");
+        fields
+            .iter()
+            .fold(Form::new().part("file", file), |form, (name, value)| {
+                form.text(*name, *value)
+            })
+    }
+
+    /// Upload a document with `secret`, returning the response status and body.
+    pub(crate) async fn upload(
+        base_url: &str,
+        route: &str,
+        secret: &str,
+        form: Form,
+    ) -> (reqwest::StatusCode, Value) {
+        let response = reqwest::Client::new()
+            .post(format!("{base_url}{route}"))
+            .header(auth::API_KEY_HEADER, secret)
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        (response.status(), response.json().await.unwrap())
+    }
+
+    /// Delete what an accepted upload left on disk.
+    pub(crate) fn remove_upload(task_id: TaskID) {
+        let upload_dir = make_upload_path(task_id, "doc.pdf");
+        let _ = std::fs::remove_dir_all(upload_dir.parent().unwrap());
+    }
+
+    pub(crate) fn task_id_of(body: &Value) -> TaskID {
+        body["request_id"].as_u64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploads_are_kept_on_disk_and_their_status_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        let secret = stored_key(&stores, "alice", ApiKeyRole::User, None).await;
+        let base_url = serve_api(stores.clone()).await;
+
+        let form = upload_form(&[("conversion_method", "Simple"), ("max_pages", "2")]);
+        let (status, body) = upload(&base_url, "/v1/ingest/upload", &secret, form).await;
+        assert_eq!(status, reqwest::StatusCode::OK, "{body}");
+        let task_id = task_id_of(&body);
+        let upload_path = make_upload_path(task_id, "doc.pdf");
+        assert_eq!(std::fs::read(&upload_path).unwrap(), b"%PDF-1.4 test");
+
+        let stored = stores.status_store.get_doc_status(task_id).await.unwrap();
+        assert!(
+            matches!(&stored.file_location, FileLocation::LocalPath(path) if *path == upload_path)
+        );
+        assert_eq!(stored.original_filename.as_deref(), Some("doc.pdf"));
+        assert_eq!(stored.owner.as_deref(), Some("alice"));
+        assert_eq!(stored.conversion_options.max_pages, Some(2));
+        assert!(stored.content_hash.is_some());
+        remove_upload(task_id);
+    }
+
+    #[tokio::test]
+    async fn rejected_uploads_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        let task_id = make_task_id();
+        let upload_path = make_upload_path(task_id, "doc.pdf");
+        std::fs::create_dir_all(upload_path.parent().unwrap()).unwrap();
+        std::fs::write(&upload_path, b"%PDF-1.4 test").unwrap();
+        let form = UploadedForm {
+            params: DocIngestParamsUpload {
+                conversion_method: Some(MarkdownConversionMethod::Simple),
+                force_ocr: Some(true),
+                ..DocIngestParamsUpload::default()
+            },
+            upload: UploadCleanup::new(upload_path.clone()),
+            upload_key: format!("uploads/{task_id}/doc.pdf"),
+        };
+
+        let err = ingest_uploaded_form(&stores, &Caller::default(), task_id, form)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ApiError::BadRequest(_)), "{err}");
+        assert!(!upload_path.exists());
+        assert!(!upload_path.parent().unwrap().exists());
+        assert!(stores.status_store.get_doc_status(task_id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unsupported_options_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        let secret = stored_key(&stores, "alice", ApiKeyRole::User, None).await;
+        let base_url = serve_api(stores).await;
+
+        let cases = [
+            (
+                &[("conversion_method", "Simple"), ("force_ocr", "true")][..],
+                "force_ocr",
+            ),
+            (
+                &[("conversion_method", "Simple"), ("langs", "de")][..],
+                "langs",
+            ),
+            (
+                &[("max_pages", "0")][..],
+                "max_pages must be a positive integer",
+            ),
+            (
+                &[("output_format", "html")][..],
+                "Unsupported output_format",
+            ),
+            (
+                &[("conversion_method", "Pandoc")][..],
+                "Unknown conversion_method",
+            ),
+        ];
+        for (fields, message) in cases {
+            let (status, body) =
+                upload(&base_url, "/v1/ingest/upload", &secret, upload_form(fields)).await;
+            assert_eq!(status, reqwest::StatusCode::BAD_REQUEST, "{fields:?}");
+            assert_eq!(body["code"], "bad_request");
+            let body_message = body["message"].as_str().unwrap();
+            assert!(body_message.contains(message), "{body_message}");
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Backpressure on the ingest routes: token buckets per API key and per client IP, and
+//! admission control refusing new documents while the queues are past a high-water mark.
+//!
+//! Buckets live in this process, with several API replicas each one enforces the limits on
+//! its own.
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, SocketAddr},
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use axum::extract::{ConnectInfo, Request};
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tokio::time::{Instant, MissedTickBehavior, interval};
+use tracing::warn;
+
+use crate::common::metrics::{
+    INGEST_REJECTED_IP_RATE, INGEST_REJECTED_KEY_RATE, INGEST_REJECTED_QUEUE_FULL, QUEUE_DEPTH,
+};
+use crate::logic::{Stores, queue_depths};
+use crate::types::ApiKey;
+
+use super::error::ApiError;
+
+/// A refill rate and how many requests may be made at once after idling.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    per_second: f64,
+    burst: f64,
+}
+
+/// Unlimited when the rate is unset or 0, the burst defaults to a second's worth.
+fn rate_limit_from_env(rate_var: &str, burst_var: &str) -> Option<RateLimit> {
+    let per_second = env::var(rate_var)
+        .ok()
+        .and_then(|val| val.parse::<f64>().ok())
+        .filter(|rate| *rate > 0.0)?;
+    let burst = env::var(burst_var)
+        .ok()
+        .and_then(|val| val.parse::<f64>().ok())
+        .unwrap_or(per_second.ceil())
+        .max(1.0);
+    Some(RateLimit { per_second, burst })
+}
+
+/// Ingest requests per second each API key may make.
+pub static KEY_RATE_LIMIT: LazyLock<Option<RateLimit>> =
+    LazyLock::new(|| rate_limit_from_env("INGEST_RATE_PER_KEY", "INGEST_BURST_PER_KEY"));
+
+/// Ingest requests per second each client IP may make.
+pub static IP_RATE_LIMIT: LazyLock<Option<RateLimit>> =
+    LazyLock::new(|| rate_limit_from_env("INGEST_RATE_PER_IP", "INGEST_BURST_PER_IP"));
+
+/// Tasks waiting across every queue past which ingests are refused, unlimited when unset or 0.
+pub static QUEUE_HIGH_WATER_MARK: LazyLock<Option<u64>> = LazyLock::new(|| {
+    env::var("QUEUE_HIGH_WATER_MARK")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .filter(|mark| *mark > 0)
+});
+
+/// `Retry-After` sent while the queues are past their high-water mark.
+pub static QUEUE_FULL_RETRY_AFTER: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        env::var("QUEUE_FULL_RETRY_AFTER_SECS")
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(30),
+    )
+});
+
+/// Take the client IP from the first `X-Forwarded-For` hop, only safe behind a proxy that
+/// sets it.
+pub static TRUST_FORWARDED_FOR: LazyLock<bool> = LazyLock::new(|| {
+    env::var("TRUST_FORWARDED_FOR")
+        .map(|val| val == "true" || val == "1")
+        .unwrap_or(false)
+});
+
+/// How often `QUEUE_DEPTH` is refreshed, admission control can overshoot by what arrives
+/// in between.
+const QUEUE_DEPTH_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Clients tracked before full buckets, which behave the same as missing ones, are dropped.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Token buckets by client, each starting out full.
+struct RateLimiter {
+    limit: Option<RateLimit>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    fn new(limit: Option<RateLimit>) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for `client`, or how long until one is available.
+    fn acquire(&self, client: &str) -> Result<(), Duration> {
+        let Some(limit) = self.limit else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let refill = |bucket: &mut Bucket| {
+            let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(limit.burst);
+            bucket.refilled_at = now;
+        };
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| {
+                refill(bucket);
+                bucket.tokens < limit.burst
+            });
+        }
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: limit.burst,
+            refilled_at: now,
+        });
+        refill(bucket);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / limit.per_second,
+            ))
+        }
+    }
+}
+
+static KEY_BUCKETS: LazyLock<RateLimiter> = LazyLock::new(|| RateLimiter::new(*KEY_RATE_LIMIT));
+static IP_BUCKETS: LazyLock<RateLimiter> = LazyLock::new(|| RateLimiter::new(*IP_RATE_LIMIT));
+
+fn too_many_requests(retry_after: Duration, message: &str) -> Response {
+    // Retry-After only takes whole seconds, rounding down would invite an early retry.
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    (
+        [(header::RETRY_AFTER, seconds.max(1).to_string())],
+        ApiError::TooManyRequests(message.to_string()),
+    )
+        .into_response()
+}
+
+fn client_ip(request: &Request) -> Option<IpAddr> {
+    if *TRUST_FORWARDED_FOR
+        && let Some(forwarded) = request
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .and_then(|hop| hop.trim().parse().ok())
+    {
+        return Some(forwarded);
+    }
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip())
+}
+
+/// Middleware for routes that create work, answering 429 with a `Retry-After` when the
+/// queues are full or the caller is over its rate limit.
+pub async fn limit_ingest(request: Request, next: Next) -> Response {
+    if let Some(high_water_mark) = *QUEUE_HIGH_WATER_MARK {
+        let depth: i64 = QUEUE_DEPTH.iter().map(|metric| metric.get()).sum();
+        if depth >= high_water_mark as i64 {
+            INGEST_REJECTED_QUEUE_FULL.inc();
+            return too_many_requests(
+                *QUEUE_FULL_RETRY_AFTER,
+                "The queue is full, try again later",
+            );
+        }
+    }
+    if let Some(ip) = client_ip(&request)
+        && let Err(retry_after) = IP_BUCKETS.acquire(&ip.to_string())
+    {
+        INGEST_REJECTED_IP_RATE.inc();
+        return too_many_requests(
+            retry_after,
+            "Too many documents submitted from this address",
+        );
+    }
+    if let Some(key) = request.extensions().get::<ApiKey>()
+        && let Err(retry_after) = KEY_BUCKETS.acquire(&key.id)
+    {
+        INGEST_REJECTED_KEY_RATE.inc();
+        return too_many_requests(
+            retry_after,
+            "Too many documents submitted with this API key",
+        );
+    }
+    next.run(request).await
+}
+
+/// Keep `QUEUE_DEPTH` current for admission control and the metrics.
+pub fn spawn_queue_depth_sampler(stores: Stores) {
+    tokio::spawn(async move {
+        let mut ticker = interval(QUEUE_DEPTH_SAMPLE_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            match queue_depths(&stores).await {
+                Ok(depths) => {
+                    for (metric, depth) in QUEUE_DEPTH.iter().zip(depths) {
+                        metric.set(depth as i64);
+                    }
+                }
+                Err(err) => warn!(%err, "Could not sample the queue depth"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_second: f64, burst: f64) -> RateLimiter {
+        RateLimiter::new(Some(RateLimit { per_second, burst }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn allows_a_burst_then_asks_to_wait_for_the_next_token() {
+        let limiter = limiter(2.0, 3.0);
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire("alice"), Ok(()));
+        }
+        assert_eq!(limiter.acquire("alice"), Err(Duration::from_millis(500)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_over_time_up_to_the_burst() {
+        let limiter = limiter(2.0, 3.0);
+        for _ in 0..3 {
+            limiter.acquire("alice").unwrap();
+        }
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(limiter.acquire("alice"), Ok(()));
+        assert!(limiter.acquire("alice").is_err());
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire("alice"), Ok(()));
+        }
+        assert!(limiter.acquire("alice").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_a_bucket_per_client() {
+        let limiter = limiter(1.0, 1.0);
+        assert_eq!(limiter.acquire("alice"), Ok(()));
+        assert!(limiter.acquire("alice").is_err());
+        assert_eq!(limiter.acquire("bob"), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn never_limits_without_a_rate() {
+        let limiter = RateLimiter::new(None);
+        for _ in 0..1000 {
+            assert_eq!(limiter.acquire("alice"), Ok(()));
+        }
+    }
+
+    #[test]
+    fn retry_after_rounds_up_to_whole_seconds() {
+        let retry_after = |response: Response| response.headers()[header::RETRY_AFTER].clone();
+        assert_eq!(
+            retry_after(too_many_requests(Duration::from_millis(200), "slow down")),
+            "1"
+        );
+        assert_eq!(
+            retry_after(too_many_requests(Duration::from_millis(2001), "slow down")),
+            "3"
+        );
+    }
+}
@@ -0,0 +1,501 @@
+//! Completion webhooks. Once a task given a `callback_url` completes or errors, its final
+//! status is posted there as json, retried with backoff until the callback answers with a 2xx.
+//!
+//! Each post carries `X-Crimson-Signature: sha256=<hex>`, an HMAC-SHA256 of
+//! `{X-Crimson-Timestamp}.{body}` keyed with the webhook secret of the API key that submitted
+//! the task. Receivers should check it and drop stale timestamps and repeated
+//! `X-Crimson-Delivery` ids.
+//!
+//! Callbacks must resolve to public addresses, so a task can't be used to reach services on
+//! the server's own network. Hosts in `WEBHOOK_ALLOWED_HOSTS` are exempt.
+//!
+//! Deliveries are kept in the status store, so any process can pick up one another left
+//! unfinished.
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::LazyLock,
+    time::{Duration, SystemTime},
+};
+
+use aide::axum::ApiRouter;
+use aide::axum::routing::get;
+use axum::Json;
+use axum::extract::{Path as UrlPath, State};
+use hmac::{Hmac, Mac};
+use reqwest::{Url, header};
+use sha2::Sha256;
+use tokio::time::{Instant, MissedTickBehavior, interval};
+use tracing::{info, warn};
+
+use crate::common::metrics::{
+    WEBHOOK_ATTEMPTS_DELIVERED, WEBHOOK_ATTEMPTS_FAILED, WEBHOOK_ATTEMPTS_RETRIED,
+};
+use crate::common::shutdown::SHUTDOWN;
+use crate::logic::{
+    Stores, claim_due_webhook_deliveries, get_task_data_from_id, get_webhook_delivery,
+    record_webhook_delivery,
+};
+use crate::types::{
+    CallbackUrlError, DocStatus, DocStatusError, DocStatusResponse, ProcessingStage,
+    WebhookAttempt, WebhookDelivery, WebhookDeliveryState, unix_millis,
+};
+
+use super::auth::{Caller, key_by_id};
+use super::error::ApiError;
+use super::{TaskIDParams, visible_task};
+
+pub const SIGNATURE_HEADER: &str = "X-Crimson-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Crimson-Timestamp";
+pub const DELIVERY_HEADER: &str = "X-Crimson-Delivery";
+pub const EVENT_HEADER: &str = "X-Crimson-Event";
+
+/// Attempts at a webhook before it is given up on.
+pub static WEBHOOK_MAX_ATTEMPTS: LazyLock<usize> = LazyLock::new(|| {
+    env::var("WEBHOOK_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .filter(|attempts| *attempts > 0)
+        .unwrap_or(8)
+});
+
+/// Delay before the first retry, doubling with every further attempt.
+pub static WEBHOOK_RETRY_BASE_DELAY: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        env::var("WEBHOOK_RETRY_BASE_SECS")
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(30),
+    )
+});
+
+/// Longest delay between two attempts.
+pub static WEBHOOK_RETRY_MAX_DELAY: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        env::var("WEBHOOK_RETRY_MAX_SECS")
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(60 * 60),
+    )
+});
+
+/// How long a callback gets to answer.
+pub static WEBHOOK_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        env::var("WEBHOOK_TIMEOUT_SECS")
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(10),
+    )
+});
+
+/// Signs the webhooks of tasks whose API key has no secret of its own, or that were submitted
+/// without a key.
+static WEBHOOK_SECRET: LazyLock<Option<String>> = LazyLock::new(|| {
+    env::var("WEBHOOK_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+});
+
+/// How often the dispatcher looks for due deliveries.
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most deliveries claimed at once.
+const DISPATCH_BATCH: usize = 16;
+
+/// Callback hosts allowed to resolve to loopback, private or otherwise non-public addresses,
+/// comma separated. For receivers on the server's own network.
+static WEBHOOK_ALLOWED_HOSTS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    env::var("WEBHOOK_ALLOWED_HOSTS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|host| host.trim().to_ascii_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect()
+});
+
+/// A callback url along with the addresses its host resolved to, the only ones posted to.
+struct CallbackTarget {
+    url: Url,
+    addresses: Vec<SocketAddr>,
+}
+
+/// Whether an address is reachable from the internet at large, rather than being loopback,
+/// private, link-local or reserved.
+fn is_public_address(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => is_public_ipv4(address),
+        IpAddr::V6(address) => match address.to_ipv4_mapped() {
+            Some(mapped) => is_public_ipv4(mapped),
+            None => is_public_ipv6(address),
+        },
+    }
+}
+
+fn is_public_ipv4(address: Ipv4Addr) -> bool {
+    let [a, b, c, _] = address.octets();
+    !(address.is_unspecified()
+        || address.is_loopback()
+        || address.is_private()
+        || address.is_link_local()
+        || address.is_broadcast()
+        || address.is_multicast()
+        || address.is_documentation()
+        // 0.0.0.0/8, shared address space 100.64.0.0/10, IETF protocol assignments
+        // 192.0.0.0/24, benchmarking 198.18.0.0/15 and reserved 240.0.0.0/4.
+        || a == 0
+        || (a == 100 && (b & 0xc0) == 64)
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (b & 0xfe) == 18)
+        || a >= 240)
+}
+
+fn is_public_ipv6(address: Ipv6Addr) -> bool {
+    let first = address.segments()[0];
+    !(address.is_unspecified()
+        || address.is_loopback()
+        || address.is_multicast()
+        // Unique local fc00::/7, link-local fe80::/10 and documentation 2001:db8::/32.
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || (first == 0x2001 && address.segments()[1] == 0x0db8))
+}
+
+/// Parse a callback url and resolve its host, refusing it unless every address is public or
+/// the host is in `WEBHOOK_ALLOWED_HOSTS`.
+async fn resolve_callback(callback_url: &str) -> Result<CallbackTarget, CallbackUrlError> {
+    let url = Url::parse(callback_url)
+        .map_err(|err| CallbackUrlError::Invalid(format!("Invalid callback_url: {err}")))?;
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return Err(CallbackUrlError::Invalid(format!(
+            "callback_url must be an http or https url: {callback_url}"
+        )));
+    };
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(CallbackUrlError::Invalid(format!(
+            "callback_url must be an http or https url: {callback_url}"
+        )));
+    }
+    // IPv6 hosts come bracketed.
+    let host_name = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase();
+    let addresses: Vec<SocketAddr> = match host_name.parse::<IpAddr>() {
+        Ok(address) => vec![SocketAddr::new(address, port)],
+        Err(_) => tokio::net::lookup_host((host_name.as_str(), port))
+            .await
+            .map_err(|source| CallbackUrlError::Unresolvable {
+                host: host_name.clone(),
+                source,
+            })?
+            .collect(),
+    };
+    if !WEBHOOK_ALLOWED_HOSTS.contains(&host_name)
+        && let Some(address) = addresses
+            .iter()
+            .map(SocketAddr::ip)
+            .find(|address| !is_public_address(*address))
+    {
+        return Err(CallbackUrlError::NotPublic {
+            host: host_name,
+            address,
+        });
+    }
+    Ok(CallbackTarget { url, addresses })
+}
+
+/// Refuse callbacks that aren't absolute http(s) urls or that point at non-public addresses.
+/// They are checked again on every delivery, DNS may have changed since. Callbacks are always
+/// signed, so the caller's key needs a webhook secret, or `WEBHOOK_SECRET` must be set.
+pub async fn check_callback_url(caller: &Caller, callback_url: &str) -> Result<(), ApiError> {
+    let key_secret = caller
+        .0
+        .as_ref()
+        .and_then(|key| key.webhook_secret.as_ref());
+    if key_secret.is_none() && WEBHOOK_SECRET.is_none() {
+        return Err(ApiError::BadRequest(
+            "callback_url needs a webhook secret to sign the callbacks with, give the API key a webhook_secret or set WEBHOOK_SECRET".to_string(),
+        ));
+    }
+    resolve_callback(callback_url)
+        .await
+        .map(|_| ())
+        .map_err(|err| ApiError::BadRequest(err.to_string()))
+}
+
+/// A client only connecting to the addresses the callback was checked against, so its host
+/// can't resolve somewhere else by the time the request is made. Redirects could point the
+/// callback anywhere, so they are reported rather than followed, and proxies are bypassed.
+fn callback_client(target: &CallbackTarget) -> reqwest::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(*WEBHOOK_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .no_proxy();
+    if let Some(host) = target.url.host_str()
+        && host.parse::<IpAddr>().is_err()
+    {
+        builder = builder.resolve_to_addrs(host, &target.addresses);
+    }
+    builder.build()
+}
+
+/// `sha256=` followed by the hex encoded HMAC-SHA256 of `{timestamp}.{body}`.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("sha256={hex}")
+}
+
+/// Exponential backoff before the attempt following the given one.
+fn retry_backoff(attempts: usize) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1) as u32);
+    (*WEBHOOK_RETRY_BASE_DELAY)
+        .saturating_mul(factor)
+        .min(*WEBHOOK_RETRY_MAX_DELAY)
+}
+
+/// Post the webhooks that come due until shutdown. Every process runs one, deliveries are
+/// claimed so only one of them makes each attempt.
+pub fn spawn_webhook_dispatcher(stores: Stores) {
+    tokio::spawn(async move {
+        let mut ticker = interval(DISPATCH_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = SHUTDOWN.cancelled() => return,
+                _ = ticker.tick() => {}
+            }
+            // A claim outlives the attempt, a process dying mid attempt only delays it.
+            let claim_for = *WEBHOOK_TIMEOUT + Duration::from_secs(30);
+            match claim_due_webhook_deliveries(&stores, claim_for, DISPATCH_BATCH).await {
+                Ok(deliveries) => {
+                    for delivery in deliveries {
+                        tokio::spawn(deliver(stores.clone(), delivery));
+                    }
+                }
+                Err(err) => warn!(%err, "Could not claim due webhook deliveries"),
+            }
+        }
+    });
+}
+
+/// The secret the task's webhooks are signed with, `None` to send them unsigned.
+async fn signing_secret(stores: &Stores, status: &DocStatus) -> Result<Option<String>, ApiError> {
+    let Some(api_key_id) = &status.api_key_id else {
+        return Ok(WEBHOOK_SECRET.clone());
+    };
+    Ok(key_by_id(stores, api_key_id)
+        .await?
+        .and_then(|key| key.webhook_secret)
+        .or_else(|| WEBHOOK_SECRET.clone()))
+}
+
+/// Make one attempt at a claimed delivery and store how it went. Store errors leave the
+/// claim to run out, so the attempt is made again later.
+async fn deliver(stores: Stores, mut delivery: WebhookDelivery) {
+    let task_id = delivery.task_id;
+    let status = match get_task_data_from_id(&stores, task_id).await {
+        Ok(status) => Some(status),
+        Err(DocStatusError::DocidNotFound) => None,
+        Err(err) => {
+            warn!(%err, task_id, "Could not read the task of a webhook delivery");
+            return;
+        }
+    };
+    let started = Instant::now();
+    let attempt = match status {
+        // Requeued since, it gets a new delivery once it finishes again.
+        Some(status) if status.status != delivery.status => Err(format!(
+            "The task is {:?} now, so its {:?} status is no longer current",
+            status.status, delivery.status
+        )),
+        None => Err("The task no longer exists".to_string()),
+        Some(status) => match signing_secret(&stores, &status).await {
+            Ok(secret) => match resolve_callback(&delivery.callback_url).await {
+                Ok(target) => Ok(post(&delivery, &target, status, secret.as_deref()).await),
+                // The name may resolve again later, an address that isn't public won't pass.
+                Err(err @ CallbackUrlError::Unresolvable { .. }) => Ok(Err(err.to_string())),
+                Err(err) => Err(err.to_string()),
+            },
+            Err(err) => {
+                warn!(%err, task_id, "Could not look up the webhook secret");
+                return;
+            }
+        },
+    };
+    let now = SystemTime::now();
+    let (status_code, error, retry) = match attempt {
+        Ok(Ok(code)) if (200..300).contains(&code) => (Some(code), None, false),
+        Ok(Ok(code)) => (Some(code), Some(format!("Callback answered {code}")), true),
+        Ok(Err(err)) => (None, Some(err), true),
+        Err(err) => (None, Some(err), false),
+    };
+    delivery.attempts.push(WebhookAttempt {
+        attempted_at: unix_millis(now),
+        status_code,
+        error: error.clone(),
+        duration_ms: started.elapsed().as_millis() as u64,
+    });
+    let attempts = delivery.attempts.len();
+    if error.is_none() {
+        WEBHOOK_ATTEMPTS_DELIVERED.inc();
+        info!(task_id, attempts, "Delivered webhook");
+        delivery.state = WebhookDeliveryState::Delivered;
+        delivery.next_attempt_at = None;
+    } else if retry && attempts < *WEBHOOK_MAX_ATTEMPTS {
+        WEBHOOK_ATTEMPTS_RETRIED.inc();
+        let delay = retry_backoff(attempts);
+        warn!(
+            ?error,
+            task_id,
+            attempts,
+            ?delay,
+            "Webhook failed, retrying later"
+        );
+        delivery.next_attempt_at = Some(unix_millis(now + delay));
+    } else {
+        WEBHOOK_ATTEMPTS_FAILED.inc();
+        warn!(?error, task_id, attempts, "Giving up on webhook");
+        delivery.state = WebhookDeliveryState::Failed;
+        delivery.next_attempt_at = None;
+    }
+    // Don't clobber the delivery of a later run of the task.
+    match get_webhook_delivery(&stores, task_id).await {
+        Ok(Some(current)) if current.id != delivery.id => return,
+        Ok(_) => {}
+        Err(err) => {
+            warn!(%err, task_id, "Could not re-read the webhook delivery");
+            return;
+        }
+    }
+    if let Err(err) = record_webhook_delivery(&stores, delivery).await {
+        warn!(%err, task_id, "Could not record the webhook attempt");
+    }
+}
+
+/// Post the final status, returning the status code the callback answered with.
+async fn post(
+    delivery: &WebhookDelivery,
+    target: &CallbackTarget,
+    status: DocStatus,
+    secret: Option<&str>,
+) -> Result<u16, String> {
+    let body =
+        serde_json::to_vec(&DocStatusResponse::from(status)).expect("statuses serialize to json");
+    let timestamp = unix_millis(SystemTime::now()) / 1000;
+    let event = match delivery.status {
+        ProcessingStage::Completed => "task.completed",
+        _ => "task.errored",
+    };
+    let client = callback_client(target)
+        .map_err(|err| format!("Could not build a client for the callback: {err}"))?;
+    let mut request = client
+        .post(target.url.clone())
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(DELIVERY_HEADER, &delivery.id)
+        .header(EVENT_HEADER, event)
+        .header(TIMESTAMP_HEADER, timestamp);
+    if let Some(secret) = secret {
+        request = request.header(SIGNATURE_HEADER, sign(secret, timestamp, &body));
+    }
+    match request.body(body).send().await {
+        Ok(response) => Ok(response.status().as_u16()),
+        Err(err) => Err(format!("Could not reach the callback: {err}")),
+    }
+}
+
+/// The task's webhook delivery with every attempt made at it.
+async fn get_task_webhook(
+    State(stores): State<Stores>,
+    caller: Caller,
+    UrlPath(TaskIDParams { task_id }): UrlPath<TaskIDParams>,
+) -> Result<Json<WebhookDelivery>, ApiError> {
+    visible_task(&stores, &caller, task_id).await?;
+    get_webhook_delivery(&stores, task_id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("Task {task_id} has no webhook delivery")))
+}
+
+/// Webhook routes, meant to be nested under `/v1/`.
+pub fn router() -> ApiRouter<Stores> {
+    ApiRouter::new().api_route("/status/{task_id}/webhook", get(get_task_webhook))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        let body = br#"{"status":"Completed"}"#;
+        assert_eq!(
+            sign("whsec_test", 1_700_000_000, body),
+            "sha256=463f5b3f93198ec2ffaacbca9c4e858f4bcb8e3abaddf695b834ab32c9d1d952"
+        );
+        assert_ne!(
+            sign("whsec_test", 1_700_000_001, body),
+            sign("whsec_test", 1_700_000_000, body)
+        );
+        assert_ne!(
+            sign("whsec_other", 1_700_000_000, body),
+            sign("whsec_test", 1_700_000_000, body)
+        );
+    }
+
+    #[test]
+    fn only_public_addresses_pass() {
+        for address in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert!(!is_public_address(address.parse().unwrap()), "{address}");
+        }
+        for address in ["93.184.216.34", "1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public_address(address.parse().unwrap()), "{address}");
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_callbacks_to_private_hosts() {
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://[::1]/hook",
+            "http://10.1.2.3/hook",
+        ] {
+            assert!(matches!(
+                resolve_callback(url).await,
+                Err(CallbackUrlError::NotPublic { .. })
+            ));
+        }
+        assert!(matches!(
+            resolve_callback("ftp://example.com/hook").await,
+            Err(CallbackUrlError::Invalid(_))
+        ));
+        let target = resolve_callback("https://93.184.216.34/hook")
+            .await
+            .unwrap();
+        assert_eq!(target.addresses, ["93.184.216.34:443".parse().unwrap()]);
+    }
+}
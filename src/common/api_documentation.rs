@@ -8,7 +8,8 @@ use aide::{
 };
 use axum::response::IntoResponse;
 
-use std::{convert::Infallible, sync::OnceLock};
+use std::net::SocketAddr;
+use std::sync::OnceLock;
 
 use aide::{axum::routing::get, swagger::Swagger};
 static PRESERIALIZED_API_STRING: OnceLock<String> = OnceLock::new();
@@ -31,15 +32,16 @@ pub enum ApiServeError {
     ApiSerializationFailure(#[from] serde_json::Error),
     #[error("Encounterd IO error while serving: {0}")]
     IOError(#[from] std::io::Error),
-    #[error("Server exited early with ok error code")]
-    ServerExitEarly,
 }
 
+/// Serve the app along with its generated docs until `shutdown` resolves, after which
+/// in flight requests are finished but no new connections are accepted.
 pub async fn generate_api_docs_and_serve(
     listener: TcpListener,
     app: ApiRouter,
     app_description: &str,
-) -> Result<Infallible, ApiServeError> {
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), ApiServeError> {
     let mut api = OpenApi {
         info: Info {
             description: Some(app_description.to_string()),
@@ -53,7 +55,8 @@ pub async fn generate_api_docs_and_serve(
         .route("/swagger", Swagger::new("/api.json").axum_route())
         // Generate the documentation.
         .finish_api(&mut api)
-        .into_make_service();
+        // Rate limits key on the client address.
+        .into_make_service_with_connect_info::<SocketAddr>();
 
     // No cached version exists, so we need to serialize and cache it
     match serde_json::to_string(&api) {
@@ -76,8 +79,8 @@ pub async fn generate_api_docs_and_serve(
             return Err(e.into());
         }
     }
-    match axum::serve(listener, full_service).await {
-        Ok(()) => Err(ApiServeError::ServerExitEarly),
-        Err(err) => Err(err.into()),
-    }
+    axum::serve(listener, full_service)
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    Ok(())
 }
@@ -0,0 +1,399 @@
+//! Process wide counters and gauges as OpenTelemetry instruments. They are exported over OTLP
+//! next to the traces when an exporter is configured, and rendered in the prometheus text
+//! format on `/metrics`.
+use std::env;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+
+use axum::http::header;
+use axum::response::IntoResponse;
+use opentelemetry::metrics::{self as otel, Meter};
+use opentelemetry::{KeyValue, global};
+use opentelemetry_otlp::MetricExporter;
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::metrics::data::{self, ResourceMetrics};
+use opentelemetry_sdk::metrics::reader::MetricReader;
+use opentelemetry_sdk::metrics::{
+    InstrumentKind, ManualReader, MetricResult, Pipeline, SdkMeterProvider, Temporality,
+};
+use tracing::warn;
+
+use crate::types::MarkdownConversionMethod;
+
+/// Name of the meter every instrument belongs to.
+const METER_NAME: &str = "crimson";
+
+/// The reader `/metrics` collects from, set by `init_meter_provider`.
+static SCRAPE_READER: OnceLock<Arc<ManualReader>> = OnceLock::new();
+
+/// A monotonic counter, one series of it when labeled.
+#[derive(Debug)]
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    /// Attribute of the series, `None` for an unlabeled counter.
+    attribute: Option<(&'static str, &'static str)>,
+    instrument: OnceLock<otel::Counter<u64>>,
+}
+
+impl Counter {
+    /// The `_total` suffix is added when rendered for prometheus.
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Counter {
+            name,
+            help,
+            attribute: None,
+            instrument: OnceLock::new(),
+        }
+    }
+
+    /// One series of a labeled counter, declared once per label under the same name.
+    pub const fn labeled(mut self, key: &'static str, value: &'static str) -> Self {
+        self.attribute = Some((key, value));
+        self
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    fn add(&self, value: u64) {
+        let instrument = self.instrument.get_or_init(|| {
+            global::meter(METER_NAME)
+                .u64_counter(self.name)
+                .with_description(self.help)
+                .build()
+        });
+        match self.attribute {
+            Some((key, label)) => instrument.add(value, &[KeyValue::new(key, label)]),
+            None => instrument.add(value, &[]),
+        }
+    }
+}
+
+/// A value this process reads back as well, for admission control and the worker pools.
+/// It is observed into an OpenTelemetry gauge on every collection.
+#[derive(Debug)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub const fn new() -> Self {
+        Gauge(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Increment the gauge until the returned guard drops, even if that's during a panic.
+    pub fn track(&'static self) -> GaugeGuard {
+        self.inc();
+        GaugeGuard(self)
+    }
+}
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Gauge::new()
+    }
+}
+
+pub struct GaugeGuard(&'static Gauge);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub static TASKS_STARTED: Counter = Counter::new(
+    "crimson_tasks_started",
+    "Task attempts picked up by this process.",
+);
+pub static TASKS_COMPLETED: Counter =
+    Counter::new("crimson_tasks_completed", "Tasks converted successfully.");
+pub static TASKS_CANCELLED: Counter = Counter::new(
+    "crimson_tasks_cancelled",
+    "Tasks dropped because they were cancelled through the API.",
+);
+pub static TASKS_RETRIED: Counter = Counter::new(
+    "crimson_tasks_retried",
+    "Task attempts that failed and were scheduled for a retry.",
+);
+pub static TASKS_FAILED: Counter = Counter::new(
+    "crimson_tasks_failed",
+    "Tasks that failed in a way retrying won't fix.",
+);
+pub static TASKS_DEAD_LETTERED: Counter = Counter::new(
+    "crimson_tasks_dead_lettered",
+    "Tasks moved to the dead letter queue after running out of attempts.",
+);
+pub static TASKS_QUARANTINED: Counter = Counter::new(
+    "crimson_tasks_quarantined",
+    "Tasks errored without converting because their document is quarantined.",
+);
+
+const INGEST_REJECTED_HELP: &str = "Ingest requests refused with 429, by what refused them.";
+
+pub static INGEST_REJECTED_KEY_RATE: Counter =
+    Counter::new("crimson_ingest_rejected", INGEST_REJECTED_HELP).labeled("reason", "key_rate");
+pub static INGEST_REJECTED_IP_RATE: Counter =
+    Counter::new("crimson_ingest_rejected", INGEST_REJECTED_HELP).labeled("reason", "ip_rate");
+pub static INGEST_REJECTED_QUEUE_FULL: Counter =
+    Counter::new("crimson_ingest_rejected", INGEST_REJECTED_HELP).labeled("reason", "queue_full");
+
+const WEBHOOK_ATTEMPTS_HELP: &str = "Attempts at posting completion webhooks, by how they went.";
+
+pub static WEBHOOK_ATTEMPTS_DELIVERED: Counter =
+    Counter::new("crimson_webhook_attempts", WEBHOOK_ATTEMPTS_HELP).labeled("outcome", "delivered");
+pub static WEBHOOK_ATTEMPTS_RETRIED: Counter =
+    Counter::new("crimson_webhook_attempts", WEBHOOK_ATTEMPTS_HELP).labeled("outcome", "retried");
+pub static WEBHOOK_ATTEMPTS_FAILED: Counter =
+    Counter::new("crimson_webhook_attempts", WEBHOOK_ATTEMPTS_HELP).labeled("outcome", "failed");
+
+/// Recorded as 0 on startup, so every series shows up before it first counts something.
+static ALL_COUNTERS: &[&Counter] = &[
+    &TASKS_STARTED,
+    &TASKS_COMPLETED,
+    &TASKS_CANCELLED,
+    &TASKS_RETRIED,
+    &TASKS_FAILED,
+    &TASKS_DEAD_LETTERED,
+    &TASKS_QUARANTINED,
+    &INGEST_REJECTED_KEY_RATE,
+    &INGEST_REJECTED_IP_RATE,
+    &INGEST_REJECTED_QUEUE_FULL,
+    &WEBHOOK_ATTEMPTS_DELIVERED,
+    &WEBHOOK_ATTEMPTS_RETRIED,
+    &WEBHOOK_ATTEMPTS_FAILED,
+];
+
+/// Tasks currently being converted by this process.
+pub static TASKS_IN_FLIGHT: Gauge = Gauge::new();
+/// Conversion slots of each worker pool, indexed like `MarkdownConversionMethod::ALL`.
+pub static WORKER_POOL_SIZE: [Gauge; 3] = [Gauge::new(), Gauge::new(), Gauge::new()];
+/// Conversions running in each worker pool, indexed like `MarkdownConversionMethod::ALL`.
+pub static WORKER_POOL_BUSY: [Gauge; 3] = [Gauge::new(), Gauge::new(), Gauge::new()];
+/// Tasks waiting in each method's queue as last sampled, indexed like
+/// `MarkdownConversionMethod::ALL`.
+pub static QUEUE_DEPTH: [Gauge; 3] = [Gauge::new(), Gauge::new(), Gauge::new()];
+
+fn observe_gauges(meter: &Meter) {
+    meter
+        .i64_observable_gauge("crimson_tasks_in_flight")
+        .with_description("Tasks currently being converted by this process.")
+        .with_callback(|observer| observer.observe(TASKS_IN_FLIGHT.get(), &[]))
+        .build();
+    observe_per_method(
+        meter,
+        "crimson_worker_pool_size",
+        "Conversion slots in the worker pool of each conversion method.",
+        &WORKER_POOL_SIZE,
+    );
+    observe_per_method(
+        meter,
+        "crimson_worker_pool_busy",
+        "Conversions running in the worker pool of each conversion method.",
+        &WORKER_POOL_BUSY,
+    );
+    observe_per_method(
+        meter,
+        "crimson_queue_depth",
+        "Tasks waiting in the queue of each conversion method, as last sampled.",
+        &QUEUE_DEPTH,
+    );
+}
+
+fn observe_per_method(
+    meter: &Meter,
+    name: &'static str,
+    help: &'static str,
+    gauges: &'static [Gauge; 3],
+) {
+    meter
+        .i64_observable_gauge(name)
+        .with_description(help)
+        .with_callback(move |observer| {
+            for (gauge, method) in gauges.iter().zip(MarkdownConversionMethod::ALL) {
+                observer.observe(gauge.get(), &[KeyValue::new("method", method.slug())]);
+            }
+        })
+        .build();
+}
+
+/// Lets `/metrics` collect from the same reader the meter provider owns.
+#[derive(Debug)]
+struct ScrapeReader(Arc<ManualReader>);
+
+impl MetricReader for ScrapeReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline);
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> MetricResult<()> {
+        self.0.collect(rm)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        self.0.shutdown()
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+/// OTLP exporter for the metrics, configured through the same `OTEL_EXPORTER_OTLP_*` env vars
+/// as the traces. `None` when neither an endpoint nor a protocol is set.
+fn otlp_metric_exporter() -> anyhow::Result<Option<MetricExporter>> {
+    let protocol = env::var("OTEL_EXPORTER_OTLP_METRICS_PROTOCOL")
+        .or_else(|_| env::var("OTEL_EXPORTER_OTLP_PROTOCOL"))
+        .ok();
+    let endpoint = env::var("OTEL_EXPORTER_OTLP_METRICS_ENDPOINT")
+        .or_else(|_| env::var("OTEL_EXPORTER_OTLP_ENDPOINT"))
+        .ok();
+    let exporter = match (protocol.as_deref(), endpoint) {
+        (None, None) => return Ok(None),
+        (Some("http/protobuf"), _) => MetricExporter::builder().with_http().build()?,
+        (None, Some(endpoint)) if endpoint.contains(":4318") => {
+            MetricExporter::builder().with_http().build()?
+        }
+        _ => MetricExporter::builder().with_tonic().build()?,
+    };
+    Ok(Some(exporter))
+}
+
+/// Install the global meter provider the instruments report to. Shut the returned provider
+/// down on exit so the last measurements are exported.
+pub fn init_meter_provider(resource: Resource) -> anyhow::Result<SdkMeterProvider> {
+    let reader = Arc::new(ManualReader::default());
+    let mut builder = SdkMeterProvider::builder()
+        .with_resource(resource)
+        .with_reader(ScrapeReader(reader.clone()));
+    if let Some(exporter) = otlp_metric_exporter()? {
+        builder = builder.with_periodic_exporter(exporter);
+    }
+    let provider = builder.build();
+    global::set_meter_provider(provider.clone());
+    let _ = SCRAPE_READER.set(reader);
+
+    observe_gauges(&global::meter(METER_NAME));
+    for counter in ALL_COUNTERS {
+        counter.add(0);
+    }
+    Ok(provider)
+}
+
+/// One metric with all its series, as rendered for prometheus.
+struct Family {
+    name: String,
+    help: String,
+    kind: &'static str,
+    /// Label set without braces and the value, per series.
+    series: Vec<(String, String)>,
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+fn label_set(attributes: &[KeyValue]) -> String {
+    attributes
+        .iter()
+        .map(|kv| format!(r#"{}="{}""#, kv.key, escape_label_value(&kv.value.as_str())))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Every metric in the prometheus text exposition format, as collected just now.
+pub fn render() -> String {
+    let Some(reader) = SCRAPE_READER.get() else {
+        return String::new();
+    };
+    let mut collected = ResourceMetrics {
+        resource: Resource::builder_empty().build(),
+        scope_metrics: Vec::new(),
+    };
+    if let Err(err) = reader.collect(&mut collected) {
+        warn!(%err, "Could not collect metrics");
+        return String::new();
+    }
+    let mut families: Vec<Family> = collected
+        .scope_metrics
+        .iter()
+        .flat_map(|scope| &scope.metrics)
+        .filter_map(|metric| {
+            let data = metric.data.as_any();
+            let (name, kind, series) = if let Some(sum) = data.downcast_ref::<data::Sum<u64>>() {
+                let series = sum
+                    .data_points
+                    .iter()
+                    .map(|point| (label_set(&point.attributes), point.value.to_string()));
+                (
+                    format!("{}_total", metric.name),
+                    "counter",
+                    series.collect(),
+                )
+            } else if let Some(gauge) = data.downcast_ref::<data::Gauge<i64>>() {
+                let series = gauge
+                    .data_points
+                    .iter()
+                    .map(|point| (label_set(&point.attributes), point.value.to_string()));
+                (metric.name.to_string(), "gauge", series.collect())
+            } else {
+                return None;
+            };
+            Some(Family {
+                name,
+                help: metric.description.to_string(),
+                kind,
+                series,
+            })
+        })
+        .collect();
+    families.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut out = String::new();
+    for mut family in families {
+        family.series.sort();
+        let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", family.name, family.kind);
+        for (labels, value) in family.series {
+            if labels.is_empty() {
+                let _ = writeln!(out, "{} {}", family.name, value);
+            } else {
+                let _ = writeln!(out, "{}{{{}}} {}", family.name, labels, value);
+            }
+        }
+    }
+    out
+}
+
+/// Scrape endpoint for prometheus.
+pub async fn serve_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render(),
+    )
+}
@@ -1,2 +1,4 @@
 pub mod api_documentation;
+pub mod metrics;
 pub mod otel_tracing;
+pub mod shutdown;
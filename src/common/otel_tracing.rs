@@ -1,12 +1,61 @@
 use aide::axum::ApiRouter;
 use axum_tracing_opentelemetry::middleware::{OtelAxumLayer, OtelInResponseLayer};
-use init_tracing_opentelemetry::tracing_subscriber_ext::init_subscribers_and_loglevel;
-use tracing::info;
+use init_tracing_opentelemetry::tracing_subscriber_ext::{
+    build_level_filter_layer, build_logger_text,
+};
+use init_tracing_opentelemetry::{init_propagator, otlp, resource::DetectResource};
+use opentelemetry::global;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing::{info, warn};
+use tracing_subscriber::layer::SubscriberExt;
 
+use super::metrics::init_meter_provider;
+
+/// The tracer and meter providers. `shutdown` them once the process is done, so the last
+/// spans and measurements are exported.
+pub struct Telemetry {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        if let Err(err) = self.tracer_provider.shutdown() {
+            warn!(%err, "Could not shut down the tracer provider");
+        }
+        if let Err(err) = self.meter_provider.shutdown() {
+            warn!(%err, "Could not shut down the meter provider");
+        }
+    }
+}
+
+/// Returns the wrapped router along with the telemetry providers to shut down on exit.
 pub fn initialize_tracing_and_wrap_router(
     make_api: impl FnOnce() -> ApiRouter,
-) -> anyhow::Result<ApiRouter> {
-    let _ = init_subscribers_and_loglevel("")?;
+) -> anyhow::Result<(ApiRouter, Telemetry)> {
+    // Log to the console while the providers are set up.
+    let setup_subscriber = tracing_subscriber::registry()
+        .with(build_level_filter_layer("")?)
+        .with(build_logger_text());
+    let setup_guard = tracing::subscriber::set_default(setup_subscriber);
+
+    let resource = DetectResource::default().build();
+    let tracer_provider = otlp::init_tracerprovider(resource.clone(), otlp::identity)?;
+    init_propagator()?;
+    global::set_tracer_provider(tracer_provider.clone());
+    let meter_provider = init_meter_provider(resource)?;
+    let otel_layer = tracing_opentelemetry::layer()
+        .with_error_records_to_exceptions(true)
+        .with_tracer(tracer_provider.tracer(""));
+
+    let subscriber = tracing_subscriber::registry()
+        .with(otel_layer)
+        .with(build_level_filter_layer("")?)
+        .with(build_logger_text());
+    drop(setup_guard);
+    tracing::subscriber::set_global_default(subscriber)?;
 
     info!("Tracing Subscriber is up and running, trying to create app");
     let api_router = make_api()
@@ -14,5 +63,9 @@ pub fn initialize_tracing_and_wrap_router(
         //start OpenTelemetry trace on incoming request
         .layer(OtelAxumLayer::default());
 
-    Ok(api_router)
+    let telemetry = Telemetry {
+        tracer_provider,
+        meter_provider,
+    };
+    Ok((api_router, telemetry))
 }
@@ -0,0 +1,56 @@
+//! Process wide shutdown signal, tripped by SIGTERM or ctrl-c.
+use std::sync::LazyLock;
+
+use axum::extract::Request;
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::api::error::ApiError;
+
+/// Cancelled once the process has been asked to stop.
+pub static SHUTDOWN: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+
+pub fn is_shutting_down() -> bool {
+    SHUTDOWN.is_cancelled()
+}
+
+/// Trip `SHUTDOWN` on the first SIGTERM or ctrl-c.
+pub fn listen_for_shutdown_signals() {
+    tokio::spawn(async {
+        let ctrl_c = tokio::signal::ctrl_c();
+        #[cfg(unix)]
+        {
+            let mut sigterm =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                    .expect("Should be able to listen for SIGTERM");
+            tokio::select! {
+                _ = ctrl_c => info!("Received ctrl-c, shutting down"),
+                _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = ctrl_c.await;
+            info!("Received ctrl-c, shutting down");
+        }
+        SHUTDOWN.cancel();
+    });
+}
+
+/// Middleware for routes that create work, answering 503 once shutdown has started so
+/// clients retry against another replica.
+pub async fn reject_while_shutting_down(request: Request, next: Next) -> Response {
+    if is_shutting_down() {
+        return (
+            [(header::RETRY_AFTER, "5")],
+            ApiError::Unavailable(
+                "Server is shutting down and not accepting new documents".to_string(),
+            ),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
@@ -1,18 +1,26 @@
+use async_trait::async_trait;
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, VecDeque},
     env,
     path::PathBuf,
     sync::{Arc, LazyLock},
+    time::{Duration, SystemTime},
 };
 use tokio::fs;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify, broadcast};
+use tokio::time::{Instant, sleep_until};
 
 use crate::types::{
-    DocStatus, DocStatusError, FileLocation, FileStoreImplementation, LocalPath, QueueError,
-    StatusStoreImplementation, StoreError, TaskID, TaskMessage, TaskQueueImplementation,
+    ApiKey, ApiKeyError, ApiKeyStoreImplementation, ApiKeyUsage, CancelTaskError, DeadLetter,
+    DocStatus, DocStatusError, DownloadedFile, FileLocation, FileStoreImplementation, LocalPath,
+    OwnerQueueDepth, ProcessingStage, QuarantineError, QuarantineStoreImplementation,
+    QuarantinedDocument, QueueError, StatusStoreImplementation, StoreError, TaskEvent,
+    TaskEventBusImplementation, TaskEventError, TaskID, TaskLease, TaskMessage, TaskPriority,
+    TaskQueueImplementation, WebhookDelivery,
 };
 
-use super::s3_stuff::make_s3_client;
+use super::s3_stuff::{download_s3_to_file, make_s3_client};
+use super::{LaneSchedule, OWNER_POLICY, OwnerSchedule, TASK_EVENT_BUFFER, next_wake, owner_depth};
 
 /// Local filesystem-based implementation of FileStore.
 #[derive(Debug, Clone)]
@@ -79,6 +87,7 @@ impl LocalFileStore {
     }
 }
 
+#[async_trait]
 impl FileStoreImplementation for LocalFileStore {
     async fn upload_from_file(
         &self,
@@ -88,40 +97,12 @@ impl FileStoreImplementation for LocalFileStore {
         Ok(FileLocation::LocalPath(local_path))
     }
 
-    async fn download_to_file(&self, src: &FileLocation) -> Result<LocalPath, StoreError> {
+    async fn download_to_file(&self, src: &FileLocation) -> Result<DownloadedFile, StoreError> {
         match src {
-            FileLocation::LocalPath(rel) => Ok(rel.clone()),
+            FileLocation::LocalPath(rel) => Ok(DownloadedFile::stored(rel.clone())),
             FileLocation::S3Location(s3_loc) => {
-                let bucket = &s3_loc.bucket;
-                let key = &s3_loc.key;
                 let client = make_s3_client(&self.s3_config, s3_loc).await;
-                // Download object
-                let resp = client
-                    .get_object()
-                    .bucket(bucket)
-                    .key(key)
-                    .send()
-                    .await
-                    .map_err(|err| StoreError::S3(err.into()))?;
-                // Read body
-                let data = resp
-                    .body
-                    .collect()
-                    .await
-                    .map_err(|_| StoreError::InvalidLocation)?;
-                let bytes = data.to_vec();
-                // Determine local path and write file
-                let rel_path = PathBuf::from(key);
-                let full_path = self.base_path.join(&rel_path);
-                if let Some(parent) = full_path.parent() {
-                    fs::create_dir_all(parent)
-                        .await
-                        .map_err(|_| StoreError::LocalFile)?;
-                }
-                fs::write(&full_path, &bytes)
-                    .await
-                    .map_err(|_| StoreError::LocalFile)?;
-                Ok(full_path)
+                download_s3_to_file(&client, s3_loc, &self.base_path).await
             }
         }
     }
@@ -143,29 +124,231 @@ impl FileStoreImplementation for LocalFileStore {
 /// In-memory FIFO task queue.
 #[derive(Debug, Clone)]
 pub struct InMemoryTaskQueue {
-    queue: Arc<Mutex<VecDeque<TaskMessage>>>,
+    queue: Arc<Mutex<InMemoryQueueState>>,
+    /// Woken whenever a task is pushed onto `pending`.
+    available: Arc<Notify>,
+    lanes: Arc<LaneSchedule>,
+    /// One per priority lane, ordered like `TaskPriority::ALL`.
+    owners: Arc<[OwnerSchedule; 3]>,
+}
+
+/// Queued tasks of one priority lane, FIFO per owner.
+type Lane = BTreeMap<Option<String>, VecDeque<TaskMessage>>;
+
+#[derive(Debug, Default)]
+struct InMemoryQueueState {
+    /// One lane per priority, ordered like `TaskPriority::ALL`.
+    pending: [Lane; 3],
+    /// Leased tasks by receipt, with their deadline.
+    leased: HashMap<String, (SystemTime, TaskMessage)>,
+    /// Oldest first.
+    dead: Vec<DeadLetter>,
+}
+
+impl InMemoryQueueState {
+    fn owner_queue(&mut self, task: &TaskMessage) -> &mut VecDeque<TaskMessage> {
+        self.pending[task.priority as usize]
+            .entry(task.owner.clone())
+            .or_default()
+    }
+
+    fn processing_by_owner(&self) -> HashMap<Option<String>, u64> {
+        let mut processing = HashMap::new();
+        for (_, task) in self.leased.values() {
+            *processing.entry(task.owner.clone()).or_default() += 1;
+        }
+        processing
+    }
+
+    /// Take the next task of the owner `schedule` picks from the lane.
+    fn pop_fairly(
+        &mut self,
+        priority: TaskPriority,
+        schedule: &OwnerSchedule,
+        processing: &HashMap<Option<String>, u64>,
+    ) -> Option<TaskMessage> {
+        let lane = &mut self.pending[priority as usize];
+        let waiting: Vec<Option<String>> = lane.keys().cloned().collect();
+        let owner = schedule.pick(&waiting, processing)?;
+        let queue = lane.get_mut(&owner)?;
+        let task = queue.pop_front();
+        if queue.is_empty() {
+            lane.remove(&owner);
+        }
+        task
+    }
+
+    fn requeue_expired(&mut self, now: SystemTime) {
+        let expired: Vec<String> = self
+            .leased
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(receipt, _)| receipt.clone())
+            .collect();
+        for receipt in expired {
+            if let Some((_, task)) = self.leased.remove(&receipt) {
+                self.owner_queue(&task).push_front(task);
+            }
+        }
+    }
 }
 
 impl InMemoryTaskQueue {
     /// Create a new empty InMemoryTaskQueue.
     pub fn new() -> Self {
         InMemoryTaskQueue {
-            queue: Arc::new(Mutex::new(VecDeque::new())),
+            queue: Arc::new(Mutex::new(InMemoryQueueState::default())),
+            available: Arc::new(Notify::new()),
+            lanes: Arc::new(LaneSchedule::default()),
+            owners: Arc::new(Default::default()),
         }
     }
 }
 
-// #[async_trait]
+#[async_trait]
 impl TaskQueueImplementation for InMemoryTaskQueue {
-    async fn enqueue(self, task: TaskMessage) -> Result<(), QueueError> {
+    async fn enqueue(&self, task: TaskMessage) -> Result<(), QueueError> {
         let mut q = self.queue.lock().await;
-        q.push_back(task);
+        q.owner_queue(&task).push_back(task);
+        self.available.notify_waiters();
         Ok(())
     }
 
-    async fn dequeue(self) -> Result<Option<TaskMessage>, QueueError> {
+    async fn dequeue(&self, lease_for: Duration) -> Result<Option<TaskLease>, QueueError> {
         let mut q = self.queue.lock().await;
-        Ok(q.pop_front())
+        let now = SystemTime::now();
+        q.requeue_expired(now);
+        let processing = q.processing_by_owner();
+        let Some(task) = self.lanes.next_order().into_iter().find_map(|priority| {
+            q.pop_fairly(priority, &self.owners[priority as usize], &processing)
+        }) else {
+            return Ok(None);
+        };
+        let lease = TaskLease {
+            task: task.clone(),
+            receipt: rand::random::<u64>().to_string(),
+            deadline: now + lease_for,
+        };
+        q.leased
+            .insert(lease.receipt.clone(), (lease.deadline, task));
+        Ok(Some(lease))
+    }
+
+    async fn dequeue_wait(
+        &self,
+        lease_for: Duration,
+        wait: Duration,
+    ) -> Result<Option<TaskLease>, QueueError> {
+        let give_up = Instant::now() + wait;
+        loop {
+            // Register before looking, so an enqueue in between still wakes us.
+            let notified = self.available.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(lease) = self.dequeue(lease_for).await? {
+                return Ok(Some(lease));
+            }
+            if Instant::now() >= give_up {
+                return Ok(None);
+            }
+            let next_deadline = {
+                let q = self.queue.lock().await;
+                q.leased.values().map(|(deadline, _)| *deadline).min()
+            };
+            tokio::select! {
+                _ = notified => {}
+                _ = sleep_until(next_wake(give_up, next_deadline)) => {}
+            }
+        }
+    }
+
+    async fn extend_lease(
+        &self,
+        lease: &mut TaskLease,
+        lease_for: Duration,
+    ) -> Result<(), QueueError> {
+        let mut q = self.queue.lock().await;
+        let now = SystemTime::now();
+        match q.leased.get_mut(&lease.receipt) {
+            Some((deadline, _)) => {
+                *deadline = now + lease_for;
+                lease.deadline = *deadline;
+                Ok(())
+            }
+            None => Err(QueueError::LeaseLost),
+        }
+    }
+
+    async fn ack(&self, lease: &TaskLease) -> Result<(), QueueError> {
+        let mut q = self.queue.lock().await;
+        q.leased
+            .remove(&lease.receipt)
+            .ok_or(QueueError::LeaseLost)?;
+        // The owner may have been held back by its processing limit.
+        if OWNER_POLICY.has_limits() {
+            self.available.notify_waiters();
+        }
+        Ok(())
+    }
+
+    async fn dead_letter(
+        &self,
+        lease: &TaskLease,
+        dead_letter: DeadLetter,
+    ) -> Result<(), QueueError> {
+        let mut q = self.queue.lock().await;
+        q.leased
+            .remove(&lease.receipt)
+            .ok_or(QueueError::LeaseLost)?;
+        q.dead.push(dead_letter);
+        if OWNER_POLICY.has_limits() {
+            self.available.notify_waiters();
+        }
+        Ok(())
+    }
+
+    async fn dead_letters(&self) -> Result<Vec<DeadLetter>, QueueError> {
+        Ok(self.queue.lock().await.dead.clone())
+    }
+
+    async fn requeue_dead_letter(&self, id: TaskID) -> Result<Option<DeadLetter>, QueueError> {
+        let mut q = self.queue.lock().await;
+        let Some(index) = q.dead.iter().position(|dead| dead.task.id == id) else {
+            return Ok(None);
+        };
+        let dead_letter = q.dead.remove(index);
+        q.owner_queue(&dead_letter.task)
+            .push_back(dead_letter.task.clone());
+        self.available.notify_waiters();
+        Ok(Some(dead_letter))
+    }
+
+    async fn owner_depths(&self) -> Result<Vec<OwnerQueueDepth>, QueueError> {
+        let q = self.queue.lock().await;
+        let mut depths = BTreeMap::new();
+        for (priority, lane) in TaskPriority::ALL.into_iter().zip(&q.pending) {
+            for (owner, tasks) in lane {
+                if let Some(task) = tasks.front() {
+                    *owner_depth(&mut depths, owner, task.conversion_method)
+                        .queued
+                        .entry(priority)
+                        .or_default() += tasks.len() as u64;
+                }
+            }
+        }
+        for (_, task) in q.leased.values() {
+            owner_depth(&mut depths, &task.owner, task.conversion_method).processing += 1;
+        }
+        Ok(depths.into_values().collect())
+    }
+
+    async fn depth(&self) -> Result<u64, QueueError> {
+        let q = self.queue.lock().await;
+        Ok(q.pending
+            .iter()
+            .flat_map(|lane| lane.values())
+            .map(|tasks| tasks.len() as u64)
+            .sum())
     }
 }
 
@@ -173,6 +356,7 @@ impl TaskQueueImplementation for InMemoryTaskQueue {
 #[derive(Debug, Clone)]
 pub struct InMemoryStatusStore {
     store: Arc<Mutex<HashMap<TaskID, DocStatus>>>,
+    webhooks: Arc<Mutex<HashMap<TaskID, WebhookDelivery>>>,
 }
 
 impl InMemoryStatusStore {
@@ -180,11 +364,12 @@ impl InMemoryStatusStore {
     pub fn new() -> Self {
         InMemoryStatusStore {
             store: Arc::new(Mutex::new(HashMap::new())),
+            webhooks: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 }
 
-// #[async_trait]
+#[async_trait]
 impl StatusStoreImplementation for InMemoryStatusStore {
     async fn set_doc_status(&self, status: DocStatus) -> Result<(), DocStatusError> {
         let mut m = self.store.lock().await;
@@ -200,4 +385,369 @@ impl StatusStoreImplementation for InMemoryStatusStore {
             Err(DocStatusError::DocidNotFound)
         }
     }
+
+    async fn cancel_doc_status(&self, id: TaskID) -> Result<DocStatus, CancelTaskError> {
+        let mut m = self.store.lock().await;
+        let status = m.get_mut(&id).ok_or(CancelTaskError::DocidNotFound)?;
+        if status.status.is_finished() {
+            return Err(CancelTaskError::AlreadyFinished(status.status));
+        }
+        status.status = ProcessingStage::Cancelled;
+        Ok(status.clone())
+    }
+
+    async fn finish_doc_status(&self, status: DocStatus) -> Result<bool, DocStatusError> {
+        let mut m = self.store.lock().await;
+        if m.get(&status.request_id)
+            .is_some_and(|stored| stored.status == ProcessingStage::Cancelled)
+        {
+            return Ok(false);
+        }
+        m.insert(status.request_id, status);
+        Ok(true)
+    }
+
+    async fn set_webhook_delivery(&self, delivery: WebhookDelivery) -> Result<(), DocStatusError> {
+        self.webhooks
+            .lock()
+            .await
+            .insert(delivery.task_id, delivery);
+        Ok(())
+    }
+
+    async fn get_webhook_delivery(
+        &self,
+        id: TaskID,
+    ) -> Result<Option<WebhookDelivery>, DocStatusError> {
+        Ok(self.webhooks.lock().await.get(&id).cloned())
+    }
+
+    async fn claim_due_webhook_deliveries(
+        &self,
+        now: i64,
+        claimed_until: i64,
+        limit: usize,
+    ) -> Result<Vec<WebhookDelivery>, DocStatusError> {
+        let mut webhooks = self.webhooks.lock().await;
+        let mut claimed = Vec::new();
+        for delivery in webhooks.values_mut() {
+            if claimed.len() == limit {
+                break;
+            }
+            if delivery.next_attempt_at.is_some_and(|due| due <= now) {
+                claimed.push(delivery.clone());
+                delivery.next_attempt_at = Some(claimed_until);
+            }
+        }
+        Ok(claimed)
+    }
+}
+
+/// In-memory quarantine store, strikes and quarantines are lost on restart.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryQuarantineStore {
+    state: Arc<Mutex<InMemoryQuarantineState>>,
+}
+
+#[derive(Debug, Default)]
+struct InMemoryQuarantineState {
+    strikes: HashMap<String, u32>,
+    quarantined: HashMap<String, QuarantinedDocument>,
+}
+
+impl InMemoryQuarantineStore {
+    pub fn new() -> Self {
+        InMemoryQuarantineStore::default()
+    }
+}
+
+#[async_trait]
+impl QuarantineStoreImplementation for InMemoryQuarantineStore {
+    async fn record_strike(&self, content_hash: &str) -> Result<u32, QuarantineError> {
+        let mut state = self.state.lock().await;
+        let strikes = state.strikes.entry(content_hash.to_string()).or_default();
+        *strikes += 1;
+        Ok(*strikes)
+    }
+
+    async fn quarantine(&self, document: QuarantinedDocument) -> Result<(), QuarantineError> {
+        let mut state = self.state.lock().await;
+        state
+            .quarantined
+            .insert(document.content_hash.clone(), document);
+        Ok(())
+    }
+
+    async fn get_quarantined(
+        &self,
+        content_hash: &str,
+    ) -> Result<Option<QuarantinedDocument>, QuarantineError> {
+        let state = self.state.lock().await;
+        Ok(state.quarantined.get(content_hash).cloned())
+    }
+
+    async fn quarantined(&self) -> Result<Vec<QuarantinedDocument>, QuarantineError> {
+        let state = self.state.lock().await;
+        let mut quarantined: Vec<_> = state.quarantined.values().cloned().collect();
+        quarantined.sort_by_key(|document| document.quarantined_at);
+        Ok(quarantined)
+    }
+
+    async fn release(
+        &self,
+        content_hash: &str,
+    ) -> Result<Option<QuarantinedDocument>, QuarantineError> {
+        let mut state = self.state.lock().await;
+        state.strikes.remove(content_hash);
+        Ok(state.quarantined.remove(content_hash))
+    }
+}
+
+/// Event bus reaching the subscribers of this process only.
+#[derive(Debug, Clone)]
+pub struct InMemoryTaskEventBus {
+    sender: broadcast::Sender<TaskEvent>,
+}
+
+impl InMemoryTaskEventBus {
+    pub fn new() -> Self {
+        InMemoryTaskEventBus {
+            sender: broadcast::channel(TASK_EVENT_BUFFER).0,
+        }
+    }
+}
+
+#[async_trait]
+impl TaskEventBusImplementation for InMemoryTaskEventBus {
+    async fn publish(&self, event: TaskEvent) -> Result<(), TaskEventError> {
+        // Nobody listening is fine, the event just goes nowhere.
+        let _ = self.sender.send(event);
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<TaskEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// In memory API key store, lost on restart.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryApiKeyStore {
+    state: Arc<Mutex<InMemoryApiKeyState>>,
+}
+
+#[derive(Debug, Default)]
+struct InMemoryApiKeyState {
+    keys: HashMap<String, ApiKey>,
+    usage: HashMap<(String, i64), ApiKeyUsage>,
+}
+
+impl InMemoryApiKeyStore {
+    pub fn new() -> Self {
+        InMemoryApiKeyStore::default()
+    }
+}
+
+#[async_trait]
+impl ApiKeyStoreImplementation for InMemoryApiKeyStore {
+    async fn get_key(&self, id: &str) -> Result<Option<ApiKey>, ApiKeyError> {
+        let state = self.state.lock().await;
+        Ok(state.keys.get(id).cloned())
+    }
+
+    async fn put_key(&self, key: ApiKey) -> Result<(), ApiKeyError> {
+        let mut state = self.state.lock().await;
+        state.keys.insert(key.id.clone(), key);
+        Ok(())
+    }
+
+    async fn keys(&self) -> Result<Vec<ApiKey>, ApiKeyError> {
+        let state = self.state.lock().await;
+        let mut keys: Vec<_> = state.keys.values().cloned().collect();
+        keys.sort_by_key(|key| key.created_at);
+        Ok(keys)
+    }
+
+    async fn revoke_key(&self, id: &str) -> Result<Option<ApiKey>, ApiKeyError> {
+        let mut state = self.state.lock().await;
+        Ok(state.keys.remove(id))
+    }
+
+    async fn record_usage(
+        &self,
+        id: &str,
+        day: i64,
+        usage: ApiKeyUsage,
+    ) -> Result<(), ApiKeyError> {
+        let mut state = self.state.lock().await;
+        // Past days are never read again.
+        state.usage.retain(|(_, used_on), _| *used_on >= day);
+        let used = state.usage.entry((id.to_string(), day)).or_default();
+        used.documents += usage.documents;
+        used.pages += usage.pages;
+        Ok(())
+    }
+
+    async fn usage(&self, id: &str, day: i64) -> Result<ApiKeyUsage, ApiKeyError> {
+        let state = self.state.lock().await;
+        Ok(state
+            .usage
+            .get(&(id.to_string(), day))
+            .copied()
+            .unwrap_or_default())
+    }
+
+    async fn reserve_document(
+        &self,
+        id: &str,
+        day: i64,
+        limit: Option<u64>,
+    ) -> Result<bool, ApiKeyError> {
+        let mut state = self.state.lock().await;
+        state.usage.retain(|(_, used_on), _| *used_on >= day);
+        let used = state.usage.entry((id.to_string(), day)).or_default();
+        if limit.is_some_and(|limit| used.documents >= limit) {
+            return Ok(false);
+        }
+        used.documents += 1;
+        Ok(true)
+    }
+
+    async fn refund_document(&self, id: &str, day: i64) -> Result<(), ApiKeyError> {
+        let mut state = self.state.lock().await;
+        if let Some(used) = state.usage.get_mut(&(id.to_string(), day)) {
+            used.documents = used.documents.saturating_sub(1);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{queued_task, test_s3_config};
+    use super::*;
+
+    const LEASE: Duration = Duration::from_secs(60);
+
+    async fn dequeued_id(queue: &InMemoryTaskQueue, lease_for: Duration) -> Option<TaskID> {
+        let lease = queue.dequeue(lease_for).await.unwrap();
+        lease.map(|lease| lease.task.id)
+    }
+
+    #[tokio::test]
+    async fn dequeues_in_order_and_leaves_leased_tasks_out_of_the_depth() {
+        let queue = InMemoryTaskQueue::new();
+        for id in 1..=3 {
+            queue.enqueue(queued_task(id)).await.unwrap();
+        }
+        assert_eq!(queue.depth().await.unwrap(), 3);
+        assert_eq!(dequeued_id(&queue, LEASE).await, Some(1));
+        assert_eq!(dequeued_id(&queue, LEASE).await, Some(2));
+        assert_eq!(queue.depth().await.unwrap(), 1);
+        assert_eq!(dequeued_id(&queue, LEASE).await, Some(3));
+        assert_eq!(dequeued_id(&queue, LEASE).await, None);
+    }
+
+    #[tokio::test]
+    async fn expired_leases_go_back_to_the_front_of_the_queue() {
+        let queue = InMemoryTaskQueue::new();
+        queue.enqueue(queued_task(1)).await.unwrap();
+        queue.enqueue(queued_task(2)).await.unwrap();
+        let mut expired = queue.dequeue(Duration::ZERO).await.unwrap().unwrap();
+        assert_eq!(dequeued_id(&queue, LEASE).await, Some(1));
+        assert!(matches!(
+            queue.extend_lease(&mut expired, LEASE).await,
+            Err(QueueError::LeaseLost)
+        ));
+        assert!(matches!(
+            queue.ack(&expired).await,
+            Err(QueueError::LeaseLost)
+        ));
+    }
+
+    #[tokio::test]
+    async fn extended_and_acked_leases_are_not_handed_out_again() {
+        let queue = InMemoryTaskQueue::new();
+        queue.enqueue(queued_task(1)).await.unwrap();
+        let mut lease = queue.dequeue(Duration::ZERO).await.unwrap().unwrap();
+        queue.extend_lease(&mut lease, LEASE).await.unwrap();
+        assert_eq!(dequeued_id(&queue, LEASE).await, None);
+        queue.ack(&lease).await.unwrap();
+        assert_eq!(dequeued_id(&queue, Duration::ZERO).await, None);
+        assert_eq!(queue.depth().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn dead_letters_can_be_requeued_once() {
+        let queue = InMemoryTaskQueue::new();
+        queue.enqueue(queued_task(7)).await.unwrap();
+        let lease = queue.dequeue(LEASE).await.unwrap().unwrap();
+        let dead_letter = DeadLetter {
+            task: lease.task.clone(),
+            error: "boom".to_string(),
+            attempts: 3,
+            dead_lettered_at: 0,
+        };
+        queue.dead_letter(&lease, dead_letter).await.unwrap();
+        assert_eq!(queue.dead_letters().await.unwrap().len(), 1);
+        assert_eq!(dequeued_id(&queue, LEASE).await, None);
+
+        let requeued = queue.requeue_dead_letter(7).await.unwrap();
+        assert_eq!(requeued.map(|dead| dead.error), Some("boom".to_string()));
+        assert!(queue.requeue_dead_letter(7).await.unwrap().is_none());
+        assert!(queue.dead_letters().await.unwrap().is_empty());
+        assert_eq!(dequeued_id(&queue, LEASE).await, Some(7));
+    }
+
+    #[tokio::test]
+    async fn waiting_dequeues_wake_up_on_enqueue() {
+        let queue = InMemoryTaskQueue::new();
+        let producer = queue.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            producer.enqueue(queued_task(1)).await.unwrap();
+        });
+        let started = Instant::now();
+        let lease = queue
+            .dequeue_wait(LEASE, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(lease.map(|lease| lease.task.id), Some(1));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn waiting_dequeues_pick_up_leases_that_run_out() {
+        let queue = InMemoryTaskQueue::new();
+        queue.enqueue(queued_task(1)).await.unwrap();
+        queue
+            .dequeue(Duration::from_millis(100))
+            .await
+            .unwrap()
+            .unwrap();
+        let lease = queue
+            .dequeue_wait(LEASE, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(lease.map(|lease| lease.task.id), Some(1));
+    }
+
+    #[tokio::test]
+    async fn only_temporary_downloads_are_deleted_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let stored = dir.path().join("stored.pdf");
+        std::fs::write(&stored, b"%PDF").unwrap();
+        let store = LocalFileStore::new(dir.path().to_path_buf(), test_s3_config());
+        let location = FileLocation::LocalPath(stored.clone());
+        drop(store.download_to_file(&location).await.unwrap());
+        assert!(stored.exists());
+
+        let upload_dir = dir.path().join("uploads").join("1");
+        std::fs::create_dir_all(&upload_dir).unwrap();
+        let copy = upload_dir.join("doc.pdf");
+        std::fs::write(&copy, b"%PDF").unwrap();
+        drop(DownloadedFile::temporary(copy.clone()));
+        assert!(!copy.exists());
+        assert!(!upload_dir.exists());
+    }
 }
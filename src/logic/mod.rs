@@ -1,81 +1,1147 @@
 // logic module grouping local_store and interface functions
 mod local_store;
+mod redis_store;
 mod s3_stuff;
+mod sqlite_store;
 
-use crate::logic::local_store::{InMemoryStatusStore, InMemoryTaskQueue, LocalFileStore};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::logic::local_store::{
+    InMemoryApiKeyStore, InMemoryQuarantineStore, InMemoryStatusStore, InMemoryTaskEventBus,
+    InMemoryTaskQueue, LOCAL_STORE_PATH, LocalFileStore,
+};
+use crate::logic::redis_store::{
+    RedisApiKeyStore, RedisConfigParams, RedisPool, RedisQuarantineStore, RedisStatusStore,
+    RedisTaskEventBus, RedisTaskQueue, method_key_prefix, split_shared_queue,
+};
+use crate::logic::s3_stuff::S3FileStore;
+use crate::logic::sqlite_store::{
+    SQLITE_PATH, SqliteApiKeyStore, SqliteDatabase, SqliteQuarantineStore, SqliteStatusStore,
+    SqliteTaskEventBus, SqliteTaskQueue,
+};
 use crate::types::{
-    DocStatus, DocStatusError, StatusStoreImplementation, TaskID, TaskMessage,
-    TaskQueueImplementation,
+    ApiKey, ApiKeyError, ApiKeyStoreImplementation, ApiKeyUsage, CancelTaskError, DeadLetter,
+    DocStatus, DocStatusError, FileLocation, FileStoreImplementation, IngestError, LocalPath,
+    MarkdownConversionMethod, OwnerQueueDepth, ProcessingStage, QuarantineError,
+    QuarantineStoreImplementation, QuarantinedDocument, QueueError, StatusStoreImplementation,
+    StoreError, TaskEvent, TaskEventBusImplementation, TaskID, TaskLease, TaskMessage,
+    TaskPriority, TaskQueueImplementation, WebhookDelivery, WebhookDeliveryState, unix_millis,
 };
-use once_cell::sync::Lazy;
+
+/// How long a dequeued task stays leased without a heartbeat before it is handed out again.
+pub static TASK_LEASE_DURATION: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        env::var("TASK_LEASE_SECS")
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(60),
+    )
+});
+
+/// Longest a worker blocks on an empty queue before checking back in.
+pub static DEQUEUE_WAIT: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        env::var("DEQUEUE_WAIT_SECS")
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(30),
+    )
+});
+
+/// Events each subscriber may fall behind by before it starts missing them.
+pub(crate) const TASK_EVENT_BUFFER: usize = 1024;
+
+/// How many crashed or timed out conversions of the same content quarantine it.
+pub static QUARANTINE_THRESHOLD: LazyLock<u32> = LazyLock::new(|| {
+    env::var("QUARANTINE_THRESHOLD")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(3)
+});
+
+/// Share of dequeues each priority lane gets first pick of, as `interactive,normal,bulk`.
+/// A lane with weight 0 only runs when the others are empty.
+pub static PRIORITY_LANE_WEIGHTS: LazyLock<[u32; 3]> = LazyLock::new(|| {
+    env::var("PRIORITY_LANE_WEIGHTS")
+        .ok()
+        .and_then(|val| {
+            let weights: Vec<u32> = val
+                .split(',')
+                .map(|weight| weight.trim().parse())
+                .collect::<Result<_, _>>()
+                .ok()?;
+            <[u32; 3]>::try_from(weights).ok()
+        })
+        .filter(|weights| weights.iter().any(|weight| *weight > 0))
+        .unwrap_or([6, 3, 1])
+});
+
+/// Owner specific settings from an env var formatted like `alice=3,bob=1`.
+fn owner_settings_from_env(var: &str) -> HashMap<String, u32> {
+    env::var(var)
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|entry| {
+            let (owner, value) = entry.split_once('=')?;
+            Some((owner.trim().to_string(), value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// How conversions are shared between the owners of queued tasks.
+#[derive(Debug, Clone)]
+pub struct OwnerPolicy {
+    /// Relative share of dequeues, owners not listed get `default_weight`.
+    pub weights: HashMap<String, u32>,
+    pub default_weight: u32,
+    /// Most tasks an owner may have leased from one method's queue at once.
+    pub processing_limits: HashMap<String, u32>,
+    /// Limit for owners not listed, `None` for no limit.
+    pub default_processing_limit: Option<u32>,
+}
+
+impl Default for OwnerPolicy {
+    fn default() -> Self {
+        OwnerPolicy {
+            weights: owner_settings_from_env("OWNER_WEIGHTS"),
+            default_weight: env::var("OWNER_DEFAULT_WEIGHT")
+                .ok()
+                .and_then(|val| val.parse().ok())
+                .unwrap_or(1),
+            processing_limits: owner_settings_from_env("OWNER_PROCESSING_LIMITS"),
+            default_processing_limit: env::var("OWNER_MAX_PROCESSING")
+                .ok()
+                .and_then(|val| val.parse().ok())
+                .filter(|limit| *limit > 0),
+        }
+    }
+}
+
+impl OwnerPolicy {
+    fn weight(&self, owner: Option<&str>) -> u32 {
+        owner
+            .and_then(|owner| self.weights.get(owner))
+            .copied()
+            .unwrap_or(self.default_weight)
+            .max(1)
+    }
+
+    fn processing_limit(&self, owner: Option<&str>) -> Option<u32> {
+        owner
+            .and_then(|owner| self.processing_limits.get(owner))
+            .copied()
+            .or(self.default_processing_limit)
+    }
+
+    /// Whether finishing a task can make another one dequeueable.
+    pub fn has_limits(&self) -> bool {
+        self.default_processing_limit.is_some() || !self.processing_limits.is_empty()
+    }
+
+    /// Whether the owner may lease another task with `processing` already leased.
+    pub fn may_lease(&self, owner: Option<&str>, processing: u64) -> bool {
+        self.processing_limit(owner)
+            .is_none_or(|limit| processing < u64::from(limit))
+    }
+}
+
+pub static OWNER_POLICY: LazyLock<OwnerPolicy> = LazyLock::new(OwnerPolicy::default);
+
+/// Which implementations back the file store, task queue and status store.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StoreBackend {
+    /// Local filesystem with an in memory queue and status store, lost on restart.
+    #[default]
+    Local,
+    /// Local filesystem with a sqlite database for the queue and statuses, survives restarts.
+    Sqlite,
+    /// S3 for files, redis for the queue and statuses, shared between replicas.
+    RedisS3,
+}
 
 /// A composite store bundling file storage, task queue, and status store.
-pub struct LocalStore {
-    pub file_store: LocalFileStore,
-    pub task_queue: InMemoryTaskQueue,
-    pub status_store: InMemoryStatusStore,
+///
+/// Cheap to clone, it gets handed to the API as axum state and to the worker.
+#[derive(Clone)]
+pub struct Stores {
+    pub file_store: Arc<dyn FileStoreImplementation>,
+    pub task_queues: TaskQueues,
+    pub status_store: Arc<dyn StatusStoreImplementation>,
+    pub quarantine_store: Arc<dyn QuarantineStoreImplementation>,
+    pub api_key_store: Arc<dyn ApiKeyStoreImplementation>,
+    pub event_bus: Arc<dyn TaskEventBusImplementation>,
+}
+
+/// One task queue per conversion method, so each worker pool only ever sees its own work.
+#[derive(Clone)]
+pub struct TaskQueues {
+    simple: Arc<dyn TaskQueueImplementation>,
+    marker: Arc<dyn TaskQueueImplementation>,
+    olmocr: Arc<dyn TaskQueueImplementation>,
 }
 
-/// Global static local store instance.
-static LOCAL_STORE: Lazy<LocalStore> = Lazy::new(|| {
-    // Base path for file storage, can be configured via environment variable.
-    LocalStore {
-        file_store: LocalFileStore::default(),
-        task_queue: InMemoryTaskQueue::new(),
-        status_store: InMemoryStatusStore::new(),
+impl TaskQueues {
+    fn new(
+        mut make_queue: impl FnMut(MarkdownConversionMethod) -> Arc<dyn TaskQueueImplementation>,
+    ) -> Self {
+        TaskQueues {
+            simple: make_queue(MarkdownConversionMethod::Simple),
+            marker: make_queue(MarkdownConversionMethod::Marker),
+            olmocr: make_queue(MarkdownConversionMethod::OlmOcr),
+        }
     }
-});
 
-/// Retrieve a reference to the global local store.
-pub fn get_local_store() -> &'static LocalStore {
-    &LOCAL_STORE
+    pub fn get(&self, method: &MarkdownConversionMethod) -> &Arc<dyn TaskQueueImplementation> {
+        match method {
+            MarkdownConversionMethod::Simple => &self.simple,
+            MarkdownConversionMethod::Marker => &self.marker,
+            MarkdownConversionMethod::OlmOcr => &self.olmocr,
+        }
+    }
+
+    pub fn all(&self) -> [&Arc<dyn TaskQueueImplementation>; 3] {
+        [&self.simple, &self.marker, &self.olmocr]
+    }
+}
+
+impl Stores {
+    /// The queue tasks converted with `method` go through.
+    pub fn task_queue(
+        &self,
+        method: &MarkdownConversionMethod,
+    ) -> &Arc<dyn TaskQueueImplementation> {
+        self.task_queues.get(method)
+    }
+
+    /// Build the store bundle for the given backend, connecting to any external services.
+    pub async fn from_backend(backend: StoreBackend) -> anyhow::Result<Self> {
+        match backend {
+            StoreBackend::Local => Ok(Stores::local()),
+            StoreBackend::Sqlite => Stores::sqlite(Path::new(&*SQLITE_PATH)).await,
+            StoreBackend::RedisS3 => Stores::redis_s3(&RedisConfigParams::default()).await,
+        }
+    }
+
+    /// Fresh local bundle, every call gets its own isolated queue and status store.
+    pub fn local() -> Self {
+        Stores {
+            file_store: Arc::new(LocalFileStore::default()),
+            task_queues: TaskQueues::new(|_| Arc::new(InMemoryTaskQueue::new())),
+            status_store: Arc::new(InMemoryStatusStore::new()),
+            quarantine_store: Arc::new(InMemoryQuarantineStore::new()),
+            api_key_store: Arc::new(InMemoryApiKeyStore::new()),
+            event_bus: Arc::new(InMemoryTaskEventBus::new()),
+        }
+    }
+
+    /// Local files with queue and statuses in the sqlite database at `path`, requeueing
+    /// whatever a previous run left unfinished.
+    pub async fn sqlite(path: &Path) -> anyhow::Result<Self> {
+        let database = SqliteDatabase::open(path).await?;
+        database.recover_interrupted_tasks().await?;
+        Ok(Stores {
+            file_store: Arc::new(LocalFileStore::default()),
+            task_queues: TaskQueues::new(|method| {
+                Arc::new(SqliteTaskQueue::new(database.clone(), method))
+            }),
+            status_store: Arc::new(SqliteStatusStore::new(database.clone())),
+            quarantine_store: Arc::new(SqliteQuarantineStore::new(database.clone())),
+            api_key_store: Arc::new(SqliteApiKeyStore::new(database.clone())),
+            event_bus: Arc::new(SqliteTaskEventBus::new(database)),
+        })
+    }
+
+    pub async fn redis_s3(redis_config: &RedisConfigParams) -> anyhow::Result<Self> {
+        let pool = RedisPool::connect(redis_config).await?;
+        split_shared_queue(&pool, &redis_config.key_prefix).await?;
+        Ok(Stores {
+            file_store: Arc::new(S3FileStore::default()),
+            task_queues: TaskQueues::new(|method| {
+                Arc::new(RedisTaskQueue::new(
+                    pool.clone(),
+                    &method_key_prefix(&redis_config.key_prefix, method),
+                    method,
+                ))
+            }),
+            status_store: Arc::new(RedisStatusStore::new(
+                pool.clone(),
+                &redis_config.key_prefix,
+            )),
+            quarantine_store: Arc::new(RedisQuarantineStore::new(
+                pool.clone(),
+                &redis_config.key_prefix,
+            )),
+            api_key_store: Arc::new(RedisApiKeyStore::new(
+                pool.clone(),
+                &redis_config.key_prefix,
+            )),
+            event_bus: Arc::new(RedisTaskEventBus::new(pool, &redis_config.key_prefix)),
+        })
+    }
+}
+
+/// When a waiting dequeue should look at the queue again: at `give_up`, or sooner if a lease
+/// runs out first and its task goes back on the queue.
+fn next_wake(give_up: Instant, next_lease_deadline: Option<SystemTime>) -> Instant {
+    let Some(deadline) = next_lease_deadline else {
+        return give_up;
+    };
+    let until = deadline
+        .duration_since(SystemTime::now())
+        .unwrap_or(Duration::ZERO);
+    give_up.min(Instant::now() + until)
+}
+
+/// Rotates which priority lane a queue tries first, so lower lanes get their share of
+/// dequeues even while the urgent ones are never empty.
+#[derive(Debug, Default)]
+pub struct LaneSchedule {
+    turn: AtomicU64,
+}
+
+impl LaneSchedule {
+    /// Lanes in the order the next dequeue should try them: the lane whose turn it is, then
+    /// the rest from most to least urgent.
+    pub fn next_order(&self) -> [TaskPriority; 3] {
+        self.next_order_weighted(*PRIORITY_LANE_WEIGHTS)
+    }
+
+    fn next_order_weighted(&self, weights: [u32; 3]) -> [TaskPriority; 3] {
+        let total: u64 = weights.iter().map(|weight| u64::from(*weight)).sum();
+        let mut slot = self.turn.fetch_add(1, Ordering::Relaxed) % total;
+        let mut preferred = TaskPriority::Interactive;
+        for (priority, weight) in TaskPriority::ALL.into_iter().zip(weights) {
+            if slot < u64::from(weight) {
+                preferred = priority;
+                break;
+            }
+            slot -= u64::from(weight);
+        }
+        let mut order = TaskPriority::ALL;
+        order.sort_by_key(|priority| (*priority != preferred, *priority));
+        order
+    }
+}
+
+/// Weighted fair queueing between the owners waiting in one priority lane.
+///
+/// Every owner has a virtual time that advances by `1 / weight` each time it is served, the
+/// owner furthest behind goes next. Owners that run out of queued tasks are forgotten, so
+/// idling doesn't bank credit for a later burst.
+#[derive(Debug, Default)]
+pub struct OwnerSchedule {
+    virtual_times: std::sync::Mutex<HashMap<Option<String>, f64>>,
+}
+
+impl OwnerSchedule {
+    /// Pick which of the `waiting` owners to serve and charge it for one task, skipping owners
+    /// at their processing limit. `None` if every waiting owner is at its limit.
+    pub fn pick(
+        &self,
+        waiting: &[Option<String>],
+        processing: &HashMap<Option<String>, u64>,
+    ) -> Option<Option<String>> {
+        self.pick_with_policy(&OWNER_POLICY, waiting, processing)
+    }
+
+    fn pick_with_policy(
+        &self,
+        policy: &OwnerPolicy,
+        waiting: &[Option<String>],
+        processing: &HashMap<Option<String>, u64>,
+    ) -> Option<Option<String>> {
+        let mut virtual_times = self
+            .virtual_times
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        virtual_times.retain(|owner, _| waiting.contains(owner));
+        // Newcomers start level with the owner furthest behind.
+        let floor = virtual_times
+            .values()
+            .copied()
+            .reduce(f64::min)
+            .unwrap_or(0.0);
+        for owner in waiting {
+            virtual_times.entry(owner.clone()).or_insert(floor);
+        }
+        let owner = waiting
+            .iter()
+            .filter(|owner| {
+                policy.may_lease(
+                    owner.as_deref(),
+                    processing.get(*owner).copied().unwrap_or(0),
+                )
+            })
+            .min_by(|a, b| {
+                virtual_times[*a]
+                    .total_cmp(&virtual_times[*b])
+                    .then_with(|| a.cmp(b))
+            })?
+            .clone();
+        if let Some(served) = virtual_times.get_mut(&owner) {
+            *served += 1.0 / f64::from(policy.weight(owner.as_deref()));
+        }
+        Some(owner)
+    }
+}
+
+/// The depth entry of an owner, created empty the first time the owner is seen.
+fn owner_depth<'a>(
+    depths: &'a mut BTreeMap<Option<String>, OwnerQueueDepth>,
+    owner: &Option<String>,
+    method: MarkdownConversionMethod,
+) -> &'a mut OwnerQueueDepth {
+    depths
+        .entry(owner.clone())
+        .or_insert_with(|| OwnerQueueDepth {
+            owner: owner.clone(),
+            conversion_method: method,
+            queued: HashMap::new(),
+            processing: 0,
+        })
+}
+
+/// Local path an uploaded file for the given task should be written to before
+/// it is handed to the file store.
+pub fn make_upload_path(id: TaskID, file_name: &str) -> LocalPath {
+    PathBuf::from(&*LOCAL_STORE_PATH)
+        .join("uploads")
+        .join(id.to_string())
+        .join(file_name)
+}
+
+/// Hand a file that was written locally over to the configured file store, dropping the
+/// local copy once it lives somewhere else.
+pub async fn upload_file_to_store(
+    stores: &Stores,
+    local_path: LocalPath,
+    upload_key: String,
+) -> Result<FileLocation, StoreError> {
+    let location = stores
+        .file_store
+        .upload_from_file(local_path.clone(), upload_key)
+        .await?;
+    if !matches!(location, FileLocation::LocalPath(_)) {
+        let _ = tokio::fs::remove_file(&local_path).await;
+    }
+    Ok(location)
 }
 
 /// Enqueue a new document processing task.
-pub async fn ingest_file_to_queue(status: DocStatus) {
+pub async fn ingest_file_to_queue(
+    stores: &Stores,
+    mut status: DocStatus,
+) -> Result<(), IngestError> {
     // Store initial status
-    let _ = LOCAL_STORE
-        .status_store
-        .set_doc_status(status.clone())
-        .await;
+    update_task_data(stores, status.clone()).await?;
     // Enqueue task for processing
     let message = TaskMessage {
         id: status.request_id,
         location: status.file_location.clone(),
+        conversion_method: status.conversion_method,
+        conversion_options: status.conversion_options.clone(),
+        priority: status.priority,
+        owner: status.owner.clone(),
     };
-    LOCAL_STORE
-        .task_queue
-        .clone()
+    if let Err(err) = stores
+        .task_queue(&message.conversion_method)
         .enqueue(message)
         .await
-        .expect("Ingest should just work");
-}
-
-/// Update an existing task's processing status.
-pub async fn update_task_data(status: DocStatus) -> Result<(), DocStatusError> {
-    LOCAL_STORE.status_store.set_doc_status(status).await
-}
-
-/// Dequeue the next file processing task, returning its DocStatus.
-pub async fn get_file_task_from_queue() -> Option<DocStatus> {
-    if let Ok(Some(task)) = LOCAL_STORE.task_queue.clone().dequeue().await {
-        // Retrieve status for this task
-        return Some(
-            LOCAL_STORE
-                .status_store
-                .get_doc_status(task.id)
-                .await
-                .unwrap_or_else(|err| {
-                    panic!(
-                        "DocStatus not found for dequeued TaskMessage, this shouldnt be possible: {err}",
-                    )
-                }),
-        );
+    {
+        // Nothing will ever pick the task up, so don't leave it looking queued. The request
+        // itself fails, so no webhook is scheduled for the caller's callback.
+        status.status = ProcessingStage::Errored;
+        status.error = Some(format!("Could not enqueue the task: {err}"));
+        let event = TaskEvent::from(&status);
+        match stores.status_store.set_doc_status(status).await {
+            Ok(()) => publish_task_event(stores, event).await,
+            Err(err) => {
+                warn!(%err, task_id = event.request_id, "Could not mark the unqueued task as errored")
+            }
+        }
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Update an existing task's processing status, and tell whoever follows the task. A task
+/// that completed or errored gets its webhook queued, if it was given a callback.
+pub async fn update_task_data(stores: &Stores, status: DocStatus) -> Result<(), DocStatusError> {
+    let (event, callback_url) = status_announcement(&status);
+    stores.status_store.set_doc_status(status).await?;
+    announce_status(stores, event, callback_url).await
+}
+
+/// Like `update_task_data`, but the status is only stored if the task wasn't cancelled
+/// meanwhile, checked and written in one step. Returns whether it was stored.
+pub async fn finish_task_data(stores: &Stores, status: DocStatus) -> Result<bool, DocStatusError> {
+    let (event, callback_url) = status_announcement(&status);
+    if !stores.status_store.finish_doc_status(status).await? {
+        return Ok(false);
+    }
+    announce_status(stores, event, callback_url).await?;
+    Ok(true)
+}
+
+/// The event for a new status, and the callback to post if it is a final one.
+fn status_announcement(status: &DocStatus) -> (TaskEvent, Option<String>) {
+    let callback_url = status.callback_url.clone().filter(|_| {
+        matches!(
+            status.status,
+            ProcessingStage::Completed | ProcessingStage::Errored
+        )
+    });
+    (TaskEvent::from(status), callback_url)
+}
+
+async fn announce_status(
+    stores: &Stores,
+    event: TaskEvent,
+    callback_url: Option<String>,
+) -> Result<(), DocStatusError> {
+    if let Some(callback_url) = callback_url {
+        schedule_webhook(stores, event.request_id, callback_url, event.status).await?;
+    }
+    publish_task_event(stores, event).await;
+    Ok(())
+}
+
+/// Tell whoever follows the task about its new status. Followers re-read the status now and
+/// then, so a lost event only delays them.
+async fn publish_task_event(stores: &Stores, event: TaskEvent) {
+    let task_id = event.request_id;
+    if let Err(err) = stores.event_bus.publish(event).await {
+        warn!(%err, task_id, "Could not publish the task event");
+    }
+}
+
+/// Queue a webhook reporting the task's final status, due right away. It replaces the one
+/// of an earlier run of the task.
+async fn schedule_webhook(
+    stores: &Stores,
+    task_id: TaskID,
+    callback_url: String,
+    status: ProcessingStage,
+) -> Result<(), DocStatusError> {
+    let now = unix_millis(SystemTime::now());
+    let delivery = WebhookDelivery {
+        id: format!("{task_id}-{now}"),
+        task_id,
+        callback_url,
+        status,
+        state: WebhookDeliveryState::Pending,
+        attempts: Vec::new(),
+        next_attempt_at: Some(now),
+        created_at: now,
+    };
+    stores.status_store.set_webhook_delivery(delivery).await
+}
+
+/// The task's latest webhook delivery and its attempts, `None` if it never had one.
+pub async fn get_webhook_delivery(
+    stores: &Stores,
+    id: TaskID,
+) -> Result<Option<WebhookDelivery>, DocStatusError> {
+    stores.status_store.get_webhook_delivery(id).await
+}
+
+/// Take the webhook deliveries that are due, holding each one for `claim_for` while it is
+/// attempted.
+pub async fn claim_due_webhook_deliveries(
+    stores: &Stores,
+    claim_for: Duration,
+    limit: usize,
+) -> Result<Vec<WebhookDelivery>, DocStatusError> {
+    let now = SystemTime::now();
+    stores
+        .status_store
+        .claim_due_webhook_deliveries(unix_millis(now), unix_millis(now + claim_for), limit)
+        .await
+}
+
+/// Store how a webhook delivery went, scheduling its next attempt if it has one.
+pub async fn record_webhook_delivery(
+    stores: &Stores,
+    delivery: WebhookDelivery,
+) -> Result<(), DocStatusError> {
+    stores.status_store.set_webhook_delivery(delivery).await
+}
+
+/// How long a task is held back when its status couldn't be read.
+const STATUS_UNAVAILABLE_DELAY: Duration = Duration::from_secs(5);
+
+/// Dequeue the next task for `method`, returning its DocStatus and the lease on it.
+///
+/// Waits up to `DEQUEUE_WAIT` for a task when the queue is empty.
+pub async fn get_file_task_from_queue(
+    stores: &Stores,
+    method: &MarkdownConversionMethod,
+) -> Result<Option<(DocStatus, TaskLease)>, QueueError> {
+    let Some(lease) = stores
+        .task_queue(method)
+        .dequeue_wait(*TASK_LEASE_DURATION, *DEQUEUE_WAIT)
+        .await?
+    else {
+        return Ok(None);
+    };
+    let task_id = lease.task.id;
+    let status = match stores.status_store.get_doc_status(task_id).await {
+        Ok(status) => status,
+        // Nothing could ever report on the task, so drop it rather than converting it.
+        Err(DocStatusError::DocidNotFound) => {
+            warn!(task_id, "Dequeued a task without a status, dropping it");
+            stores.task_queue(method).ack(&lease).await?;
+            return Ok(None);
+        }
+        Err(err) => {
+            warn!(%err, task_id, "Could not read the status of a dequeued task, handing it back");
+            if let Err(err) = retry_task_later(stores, &lease, STATUS_UNAVAILABLE_DELAY).await {
+                warn!(%err, task_id, "Could not hand the task back, it will be retried once its lease expires");
+            }
+            return Ok(None);
+        }
+    };
+    Ok(Some((status, lease)))
+}
+
+/// Keep holding a task that is still being worked on.
+pub async fn extend_task_lease(stores: &Stores, lease: &mut TaskLease) -> Result<(), QueueError> {
+    stores
+        .task_queue(&lease.task.conversion_method)
+        .extend_lease(lease, *TASK_LEASE_DURATION)
+        .await
+}
+
+/// Mark a leased task as done so it is never handed out again.
+pub async fn ack_task(stores: &Stores, lease: &TaskLease) -> Result<(), QueueError> {
+    stores
+        .task_queue(&lease.task.conversion_method)
+        .ack(lease)
+        .await
+}
+
+/// Hand a leased task back to be picked up again after `delay`.
+pub async fn retry_task_later(
+    stores: &Stores,
+    lease: &TaskLease,
+    delay: Duration,
+) -> Result<(), QueueError> {
+    stores
+        .task_queue(&lease.task.conversion_method)
+        .release(lease, delay)
+        .await
+}
+
+/// Park a task that ran out of attempts on the dead letter queue.
+pub async fn dead_letter_task(
+    stores: &Stores,
+    lease: &TaskLease,
+    error: String,
+    attempts: u32,
+) -> Result<(), QueueError> {
+    let dead_letter = DeadLetter {
+        task: lease.task.clone(),
+        error,
+        attempts,
+        dead_lettered_at: unix_millis(SystemTime::now()),
+    };
+    stores
+        .task_queue(&lease.task.conversion_method)
+        .dead_letter(lease, dead_letter)
+        .await
+}
+
+/// Dead letters of every method's queue, oldest first.
+pub async fn list_dead_letters(stores: &Stores) -> Result<Vec<DeadLetter>, QueueError> {
+    let mut dead_letters = Vec::new();
+    for queue in stores.task_queues.all() {
+        dead_letters.extend(queue.dead_letters().await?);
+    }
+    dead_letters.sort_by_key(|dead| dead.dead_lettered_at);
+    Ok(dead_letters)
+}
+
+/// Queued and processing tasks per owner in every method's queue.
+pub async fn list_owner_depths(stores: &Stores) -> Result<Vec<OwnerQueueDepth>, QueueError> {
+    let mut depths = Vec::new();
+    for queue in stores.task_queues.all() {
+        depths.extend(queue.owner_depths().await?);
+    }
+    Ok(depths)
+}
+
+/// Tasks waiting in each method's queue, indexed like `MarkdownConversionMethod::ALL`.
+pub async fn queue_depths(stores: &Stores) -> Result<[u64; 3], QueueError> {
+    let mut depths = [0; 3];
+    for (depth, queue) in depths.iter_mut().zip(stores.task_queues.all()) {
+        *depth = queue.depth().await?;
+    }
+    Ok(depths)
+}
+
+/// Give a dead lettered task a fresh set of attempts, `None` if it isn't dead lettered.
+pub async fn requeue_dead_letter(
+    stores: &Stores,
+    id: TaskID,
+) -> Result<Option<DocStatus>, IngestError> {
+    let Some(dead_letter) = list_dead_letters(stores)
+        .await?
+        .into_iter()
+        .find(|dead| dead.task.id == id)
+    else {
+        return Ok(None);
+    };
+    // Reset the status first, so a worker that dequeues it right away sees the fresh count.
+    // Put the dead lettered status back if the task didn't make it onto the queue after all.
+    let dead_status = get_task_data_from_id(stores, id).await?;
+    let mut status = dead_status.clone();
+    status.status = ProcessingStage::Waiting;
+    status.error = None;
+    status.attempts = 0;
+    update_task_data(stores, status.clone()).await?;
+    let requeued = stores
+        .task_queue(&dead_letter.task.conversion_method)
+        .requeue_dead_letter(id)
+        .await;
+    match requeued {
+        Ok(Some(_)) => Ok(Some(status)),
+        Ok(None) => {
+            update_task_data(stores, dead_status).await?;
+            Ok(None)
+        }
+        Err(err) => {
+            if let Err(restore_err) = update_task_data(stores, dead_status).await {
+                warn!(%restore_err, id, "Could not restore the status of a dead letter");
+            }
+            Err(err.into())
+        }
     }
-    None
 }
 
 /// Retrieve the stored DocStatus for a given task ID.
-pub async fn get_task_data_from_id(id: TaskID) -> Result<DocStatus, DocStatusError> {
-    LOCAL_STORE.status_store.get_doc_status(id).await
+pub async fn get_task_data_from_id(
+    stores: &Stores,
+    id: TaskID,
+) -> Result<DocStatus, DocStatusError> {
+    stores.status_store.get_doc_status(id).await
+}
+
+/// Mark an unfinished task cancelled. A worker that is running it notices and aborts, one
+/// that dequeues it later skips it.
+pub async fn cancel_task(stores: &Stores, id: TaskID) -> Result<DocStatus, CancelTaskError> {
+    let status = stores.status_store.cancel_doc_status(id).await?;
+    publish_task_event(stores, TaskEvent::from(&status)).await;
+    Ok(status)
+}
+
+/// Hex encoded sha256 of a file, read off the async threads.
+pub async fn hash_file(path: &Path) -> std::io::Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await
+    .expect("hashing a file should not panic")
+}
+
+pub async fn find_quarantined(
+    stores: &Stores,
+    content_hash: &str,
+) -> Result<Option<QuarantinedDocument>, QuarantineError> {
+    stores.quarantine_store.get_quarantined(content_hash).await
+}
+
+/// Count a crashed or timed out conversion against the task's content, quarantining it once
+/// it reaches `QUARANTINE_THRESHOLD`. Returns the quarantine entry if this strike was the last.
+pub async fn record_conversion_strike(
+    stores: &Stores,
+    status: &DocStatus,
+    local_path: &Path,
+    error: String,
+) -> anyhow::Result<Option<QuarantinedDocument>> {
+    let Some(content_hash) = &status.content_hash else {
+        return Ok(None);
+    };
+    let strikes = stores.quarantine_store.record_strike(content_hash).await?;
+    if strikes < *QUARANTINE_THRESHOLD {
+        return Ok(None);
+    }
+    // Keep a copy for inspection, the original may be cleaned up with its task.
+    let quarantine_path = PathBuf::from(&*LOCAL_STORE_PATH)
+        .join("quarantine")
+        .join(format!("{content_hash}.pdf"));
+    if let Some(parent) = quarantine_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::copy(local_path, &quarantine_path).await?;
+    // Failing to store the copy shouldn't let the document keep crashing workers.
+    let file_location = upload_file_to_store(
+        stores,
+        quarantine_path.clone(),
+        format!("quarantine/{content_hash}.pdf"),
+    )
+    .await
+    .unwrap_or_else(|err| {
+        warn!(%err, content_hash, "Could not store quarantined document, keeping the local copy");
+        FileLocation::LocalPath(quarantine_path)
+    });
+    let document = QuarantinedDocument {
+        content_hash: content_hash.clone(),
+        task_id: status.request_id,
+        file_location,
+        error,
+        strikes,
+        quarantined_at: unix_millis(SystemTime::now()),
+    };
+    stores.quarantine_store.quarantine(document.clone()).await?;
+    Ok(Some(document))
+}
+
+pub async fn list_quarantined(
+    stores: &Stores,
+) -> Result<Vec<QuarantinedDocument>, QuarantineError> {
+    stores.quarantine_store.quarantined().await
+}
+
+/// Accept the content again with a clean slate, `None` if it wasn't quarantined.
+pub async fn release_quarantined(
+    stores: &Stores,
+    content_hash: &str,
+) -> Result<Option<QuarantinedDocument>, QuarantineError> {
+    stores.quarantine_store.release(content_hash).await
+}
+
+/// Hex encoded sha256 of an API key secret, the id it is stored and looked up under.
+pub fn hash_api_key(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// Days since the unix epoch, API key quotas reset at midnight UTC.
+pub fn usage_day(time: SystemTime) -> i64 {
+    unix_millis(time).div_euclid(24 * 60 * 60 * 1000)
+}
+
+pub async fn find_api_key(stores: &Stores, id: &str) -> Result<Option<ApiKey>, ApiKeyError> {
+    stores.api_key_store.get_key(id).await
+}
+
+pub async fn list_api_keys(stores: &Stores) -> Result<Vec<ApiKey>, ApiKeyError> {
+    stores.api_key_store.keys().await
+}
+
+pub async fn store_api_key(stores: &Stores, key: ApiKey) -> Result<(), ApiKeyError> {
+    stores.api_key_store.put_key(key).await
+}
+
+/// Stop accepting the key, `None` if it wasn't stored.
+pub async fn revoke_api_key(stores: &Stores, id: &str) -> Result<Option<ApiKey>, ApiKeyError> {
+    stores.api_key_store.revoke_key(id).await
+}
+
+/// What the key used so far today.
+pub async fn api_key_usage_today(stores: &Stores, id: &str) -> Result<ApiKeyUsage, ApiKeyError> {
+    stores
+        .api_key_store
+        .usage(id, usage_day(SystemTime::now()))
+        .await
+}
+
+async fn record_api_key_usage(
+    stores: &Stores,
+    id: &str,
+    usage: ApiKeyUsage,
+) -> Result<(), ApiKeyError> {
+    stores
+        .api_key_store
+        .record_usage(id, usage_day(SystemTime::now()), usage)
+        .await
+}
+
+/// Count a new document against the key's usage today, unless it already used `limit`.
+/// Returns the day it was counted on, to refund it on, or `None` if it was refused.
+pub async fn reserve_api_key_document(
+    stores: &Stores,
+    id: &str,
+    limit: Option<u64>,
+) -> Result<Option<i64>, ApiKeyError> {
+    let day = usage_day(SystemTime::now());
+    let reserved = stores
+        .api_key_store
+        .reserve_document(id, day, limit)
+        .await?;
+    Ok(reserved.then_some(day))
+}
+
+/// Hand back a document reserved on `day` that never made it into the queue.
+pub async fn refund_api_key_document(
+    stores: &Stores,
+    id: &str,
+    day: i64,
+) -> Result<(), ApiKeyError> {
+    stores.api_key_store.refund_document(id, day).await
+}
+
+/// Charge the pages of a finished conversion to the key that submitted it.
+pub async fn charge_converted_pages(
+    stores: &Stores,
+    api_key_id: &str,
+    pages: u32,
+) -> Result<(), ApiKeyError> {
+    let charge = ApiKeyUsage {
+        documents: 0,
+        pages: u64::from(pages),
+    };
+    record_api_key_usage(stores, api_key_id, charge).await
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::logic::local_store::S3ConfigParams;
+
+    /// S3 settings that don't need any env vars, nothing is ever sent to the endpoint.
+    pub(crate) fn test_s3_config() -> S3ConfigParams {
+        S3ConfigParams {
+            endpoint: "http://127.0.0.1:9".to_string(),
+            region: "test".to_string(),
+            default_bucket: "test".to_string(),
+            access_key: "test".to_string(),
+            secret_key: "test".to_string(),
+        }
+    }
+
+    /// In-memory stores keeping files under `dir`.
+    pub(crate) fn memory_stores(dir: &Path) -> Stores {
+        Stores {
+            file_store: Arc::new(LocalFileStore::new(dir.to_path_buf(), test_s3_config())),
+            task_queues: TaskQueues::new(|_| Arc::new(InMemoryTaskQueue::new())),
+            status_store: Arc::new(InMemoryStatusStore::new()),
+            quarantine_store: Arc::new(InMemoryQuarantineStore::new()),
+            api_key_store: Arc::new(InMemoryApiKeyStore::new()),
+            event_bus: Arc::new(InMemoryTaskEventBus::new()),
+        }
+    }
+
+    /// A normal priority task without an owner, for the queue tests.
+    pub(super) fn queued_task(id: TaskID) -> TaskMessage {
+        TaskMessage {
+            id,
+            location: FileLocation::LocalPath(PathBuf::from(format!("/tmp/{id}.pdf"))),
+            conversion_method: MarkdownConversionMethod::Simple,
+            conversion_options: crate::types::ConversionOptions::default(),
+            priority: TaskPriority::Normal,
+            owner: None,
+        }
+    }
+
+    fn callback_status(id: TaskID, stage: ProcessingStage) -> DocStatus {
+        let task = queued_task(id);
+        let mut status = DocStatus::new_from_id_loc(
+            id,
+            task.location,
+            task.conversion_method,
+            task.conversion_options,
+        );
+        status.status = stage;
+        status.callback_url = Some("https://example.com/hook".to_string());
+        status
+    }
+
+    #[tokio::test]
+    async fn finishing_a_cancelled_task_keeps_it_cancelled_without_a_webhook() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        update_task_data(&stores, callback_status(1, ProcessingStage::Processing))
+            .await
+            .unwrap();
+        cancel_task(&stores, 1).await.unwrap();
+
+        let finished = finish_task_data(&stores, callback_status(1, ProcessingStage::Completed));
+        assert!(!finished.await.unwrap());
+        let status = get_task_data_from_id(&stores, 1).await.unwrap();
+        assert_eq!(status.status, ProcessingStage::Cancelled);
+        assert!(get_webhook_delivery(&stores, 1).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn finishing_a_running_task_stores_it_and_schedules_its_webhook() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = memory_stores(dir.path());
+        update_task_data(&stores, callback_status(1, ProcessingStage::Processing))
+            .await
+            .unwrap();
+
+        let finished = finish_task_data(&stores, callback_status(1, ProcessingStage::Completed));
+        assert!(finished.await.unwrap());
+        let status = get_task_data_from_id(&stores, 1).await.unwrap();
+        assert_eq!(status.status, ProcessingStage::Completed);
+        let delivery = get_webhook_delivery(&stores, 1).await.unwrap().unwrap();
+        assert_eq!(delivery.status, ProcessingStage::Completed);
+    }
+
+    #[tokio::test]
+    async fn failing_to_enqueue_errors_the_task_without_a_webhook() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("crimson.db");
+        let database = SqliteDatabase::open(&path).await.unwrap();
+        // Every enqueue fails once the queue's table is gone.
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch("DROP TABLE task_queue")
+            .unwrap();
+        let stores = Stores {
+            task_queues: TaskQueues::new(|method| {
+                Arc::new(SqliteTaskQueue::new(database.clone(), method))
+            }),
+            ..memory_stores(dir.path())
+        };
+
+        let ingested = ingest_file_to_queue(&stores, callback_status(1, ProcessingStage::Waiting));
+        assert!(matches!(ingested.await, Err(IngestError::Queue(_))));
+        let status = get_task_data_from_id(&stores, 1).await.unwrap();
+        assert_eq!(status.status, ProcessingStage::Errored);
+        assert!(status.error.unwrap().contains("Could not enqueue"));
+        assert!(get_webhook_delivery(&stores, 1).await.unwrap().is_none());
+    }
+
+    fn first_lanes(schedule: &LaneSchedule, weights: [u32; 3], dequeues: usize) -> Vec<usize> {
+        let mut counts = vec![0; 3];
+        for _ in 0..dequeues {
+            let order = schedule.next_order_weighted(weights);
+            let lane = TaskPriority::ALL
+                .iter()
+                .position(|p| *p == order[0])
+                .unwrap();
+            counts[lane] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn lane_schedule_shares_turns_by_weight() {
+        let schedule = LaneSchedule::default();
+        assert_eq!(first_lanes(&schedule, [6, 3, 1], 100), vec![60, 30, 10]);
+    }
+
+    #[test]
+    fn lane_schedule_falls_back_from_most_to_least_urgent() {
+        let schedule = LaneSchedule::default();
+        let orders: Vec<_> = (0..3)
+            .map(|_| schedule.next_order_weighted([1, 1, 1]))
+            .collect();
+        use TaskPriority::*;
+        assert_eq!(
+            orders,
+            vec![
+                [Interactive, Normal, Bulk],
+                [Normal, Interactive, Bulk],
+                [Bulk, Interactive, Normal],
+            ]
+        );
+    }
+
+    #[test]
+    fn lane_schedule_skips_lanes_weighted_zero() {
+        let schedule = LaneSchedule::default();
+        assert_eq!(first_lanes(&schedule, [0, 1, 0], 5), vec![0, 5, 0]);
+    }
+
+    fn owners(names: &[&str]) -> Vec<Option<String>> {
+        names.iter().map(|name| Some(name.to_string())).collect()
+    }
+
+    fn policy(weights: &[(&str, u32)], limits: &[(&str, u32)]) -> OwnerPolicy {
+        let settings = |pairs: &[(&str, u32)]| {
+            pairs
+                .iter()
+                .map(|(owner, value)| (owner.to_string(), *value))
+                .collect()
+        };
+        OwnerPolicy {
+            weights: settings(weights),
+            default_weight: 1,
+            processing_limits: settings(limits),
+            default_processing_limit: None,
+        }
+    }
+
+    fn picks(
+        schedule: &OwnerSchedule,
+        policy: &OwnerPolicy,
+        waiting: &[Option<String>],
+        count: usize,
+    ) -> Vec<String> {
+        (0..count)
+            .map(|_| {
+                schedule
+                    .pick_with_policy(policy, waiting, &HashMap::new())
+                    .flatten()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn owner_schedule_alternates_between_equal_owners() {
+        let schedule = OwnerSchedule::default();
+        let waiting = owners(&["alice", "bob"]);
+        assert_eq!(
+            picks(&schedule, &policy(&[], &[]), &waiting, 4),
+            ["alice", "bob", "alice", "bob"]
+        );
+    }
+
+    #[test]
+    fn owner_schedule_serves_owners_by_weight() {
+        let schedule = OwnerSchedule::default();
+        let policy = policy(&[("alice", 3)], &[]);
+        let served = picks(&schedule, &policy, &owners(&["alice", "bob"]), 8);
+        let alice = served.iter().filter(|owner| *owner == "alice").count();
+        assert_eq!((alice, served.len() - alice), (6, 2));
+    }
+
+    #[test]
+    fn owner_schedule_skips_owners_at_their_processing_limit() {
+        let schedule = OwnerSchedule::default();
+        let policy = policy(&[], &[("alice", 1)]);
+        let waiting = owners(&["alice", "bob"]);
+        let processing = HashMap::from([(Some("alice".to_string()), 1)]);
+        for _ in 0..3 {
+            assert_eq!(
+                schedule.pick_with_policy(&policy, &waiting, &processing),
+                Some(Some("bob".to_string()))
+            );
+        }
+        let processing =
+            HashMap::from([(Some("alice".to_string()), 1), (Some("bob".to_string()), 0)]);
+        assert_eq!(
+            schedule.pick_with_policy(&policy, &owners(&["alice"]), &processing),
+            None
+        );
+    }
+
+    #[test]
+    fn owner_schedule_gives_no_credit_for_idling() {
+        let schedule = OwnerSchedule::default();
+        let policy = policy(&[], &[]);
+        picks(&schedule, &policy, &owners(&["alice"]), 5);
+        // bob joins level with alice instead of owning the next five dequeues.
+        let served = picks(&schedule, &policy, &owners(&["alice", "bob"]), 4);
+        assert_eq!(served, ["alice", "bob", "alice", "bob"]);
+    }
 }
@@ -0,0 +1,1190 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use redis::{
+    AsyncCommands, Client, Script,
+    aio::{ConnectionManager, PubSub},
+};
+use tokio::{
+    sync::{Mutex, OnceCell, broadcast},
+    time::{Instant, sleep},
+};
+use tracing::{info, warn};
+
+use crate::types::{
+    ApiKey, ApiKeyError, ApiKeyStoreImplementation, ApiKeyUsage, CancelTaskError, DeadLetter,
+    DocStatus, DocStatusError, MarkdownConversionMethod, OwnerQueueDepth, ProcessingStage,
+    QuarantineError, QuarantineStoreImplementation, QuarantinedDocument, QueueError,
+    StatusStoreImplementation, TaskEvent, TaskEventBusImplementation, TaskEventError, TaskID,
+    TaskLease, TaskMessage, TaskPriority, TaskQueueImplementation, WebhookDelivery, unix_millis,
+};
+
+use super::{LaneSchedule, OWNER_POLICY, OwnerSchedule, TASK_EVENT_BUFFER, next_wake, owner_depth};
+
+pub static REDIS_URL: LazyLock<String> = LazyLock::new(|| {
+    env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string())
+});
+
+pub static REDIS_KEY_PREFIX: LazyLock<String> =
+    LazyLock::new(|| env::var("REDIS_KEY_PREFIX").unwrap_or_else(|_| "crimson".to_string()));
+
+pub static REDIS_POOL_SIZE: LazyLock<usize> = LazyLock::new(|| {
+    env::var("REDIS_POOL_SIZE")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(4)
+});
+
+#[derive(Debug, Clone)]
+pub struct RedisConfigParams {
+    pub url: String,
+    /// Every key crimson writes starts with this, so several deployments can share a server.
+    pub key_prefix: String,
+    pub pool_size: usize,
+}
+
+impl Default for RedisConfigParams {
+    fn default() -> Self {
+        RedisConfigParams {
+            url: (*REDIS_URL).clone(),
+            key_prefix: (*REDIS_KEY_PREFIX).clone(),
+            pool_size: *REDIS_POOL_SIZE,
+        }
+    }
+}
+
+/// Round robin pool of multiplexed connections, each of which reconnects on its own.
+#[derive(Clone)]
+pub struct RedisPool {
+    connections: Arc<Vec<ConnectionManager>>,
+    next: Arc<AtomicUsize>,
+    client: Client,
+}
+
+impl RedisPool {
+    pub async fn connect(config: &RedisConfigParams) -> Result<Self, redis::RedisError> {
+        let client = Client::open(config.url.as_str())?;
+        let mut connections = Vec::with_capacity(config.pool_size.max(1));
+        for _ in 0..config.pool_size.max(1) {
+            connections.push(ConnectionManager::new(client.clone()).await?);
+        }
+        Ok(RedisPool {
+            connections: Arc::new(connections),
+            next: Arc::new(AtomicUsize::new(0)),
+            client,
+        })
+    }
+
+    /// Open a connection of its own for blocking commands, which would stall everything queued
+    /// behind them on a shared one.
+    pub async fn blocking_connection(&self) -> Result<ConnectionManager, redis::RedisError> {
+        ConnectionManager::new(self.client.clone()).await
+    }
+
+    /// Open a connection for subscribing to channels, which can't run other commands.
+    pub async fn pubsub(&self) -> Result<PubSub, redis::RedisError> {
+        self.client.get_async_pubsub().await
+    }
+
+    /// Grab the next connection, clones share the underlying socket.
+    pub fn get(&self) -> ConnectionManager {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+        self.connections[index].clone()
+    }
+}
+
+/// Most wake up signals kept around for workers that weren't waiting when they were sent.
+const MAX_PENDING_SIGNALS: isize = 16;
+
+/// Moves expired leases back to the front of their owner's queue in their lane.
+///
+/// KEYS are the interactive, normal and bulk lanes, then the owner sets of those lanes, then
+/// the leases. Lease members are `{token}:{payload}`, the payload is only decoded to find
+/// where the task was queued.
+static REQUEUE_EXPIRED_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local lanes = {interactive = 1, normal = 2, bulk = 3}
+        local expired = redis.call('ZRANGEBYSCORE', KEYS[7], '-inf', ARGV[1])
+        for _, member in ipairs(expired) do
+            redis.call('ZREM', KEYS[7], member)
+            local split = string.find(member, ':', 1, true)
+            local payload = string.sub(member, split + 1)
+            local ok, task = pcall(cjson.decode, payload)
+            local lane = (ok and lanes[task.priority]) or 2
+            local owner = ok and task.owner
+            if type(owner) == 'string' then
+                redis.call('LPUSH', KEYS[lane] .. ':owner:' .. owner, payload)
+                redis.call('SADD', KEYS[lane + 3], owner)
+            else
+                redis.call('LPUSH', KEYS[lane], payload)
+            end
+        end
+        return #expired
+        ",
+    )
+});
+
+/// Leases the next task of one owner, dropping the owner from its lane's set once it has
+/// nothing left queued there.
+///
+/// KEYS are the owner's queue, the lane's owner set and the leases, ARGV the owner (empty for
+/// tasks without one), lease deadline and token.
+static LEASE_OWNER_TASK_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local payload = redis.call('LPOP', KEYS[1])
+        if ARGV[1] ~= '' and redis.call('LLEN', KEYS[1]) == 0 then
+            redis.call('SREM', KEYS[2], ARGV[1])
+        end
+        if not payload then
+            return false
+        end
+        redis.call('ZADD', KEYS[3], ARGV[2], ARGV[3] .. ':' .. payload)
+        return payload
+        ",
+    )
+});
+
+/// Replaces a value only if it is still the one that was read, so a write landing in between
+/// isn't overwritten. ARGV is the value read and its replacement.
+static COMPARE_AND_SET_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            redis.call('SET', KEYS[1], ARGV[2])
+            return 1
+        end
+        return 0
+        ",
+    )
+});
+
+/// Stores a status unless the one stored is cancelled. ARGV is the new status.
+static FINISH_STATUS_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local stored = redis.call('GET', KEYS[1])
+        if stored then
+            local ok, status = pcall(cjson.decode, stored)
+            if ok and status.status == 'Cancelled' then
+                return 0
+            end
+        end
+        redis.call('SET', KEYS[1], ARGV[1])
+        return 1
+        ",
+    )
+});
+
+/// Only extends a lease that hasn't been requeued yet.
+static EXTEND_LEASE_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('ZSCORE', KEYS[1], ARGV[1]) then
+            redis.call('ZADD', KEYS[1], ARGV[2], ARGV[1])
+            return 1
+        end
+        return 0
+        ",
+    )
+});
+
+/// Parks a task on the dead letter hash, provided its lease is still held.
+static DEAD_LETTER_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('ZREM', KEYS[1], ARGV[1]) == 0 then
+            return 0
+        end
+        redis.call('HSET', KEYS[2], ARGV[2], ARGV[3])
+        return 1
+        ",
+    )
+});
+
+/// Takes a task off the dead letter hash and queues it, unless someone beat us to it.
+static REQUEUE_DEAD_LETTER_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('HDEL', KEYS[1], ARGV[1]) == 0 then
+            return 0
+        end
+        redis.call('RPUSH', KEYS[2], ARGV[2])
+        if ARGV[3] ~= '' then
+            redis.call('SADD', KEYS[3], ARGV[3])
+        end
+        return 1
+        ",
+    )
+});
+
+/// Takes the webhook deliveries that are due and pushes them back until the claim runs out,
+/// dropping due entries whose delivery is gone.
+///
+/// KEYS are the due set and the deliveries hash, ARGV now, the claim's end and the limit.
+static CLAIM_WEBHOOKS_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[3])
+        local claimed = {}
+        for _, id in ipairs(due) do
+            local payload = redis.call('HGET', KEYS[2], id)
+            if payload then
+                redis.call('ZADD', KEYS[1], ARGV[2], id)
+                table.insert(claimed, payload)
+            else
+                redis.call('ZREM', KEYS[1], id)
+            end
+        end
+        return claimed
+        ",
+    )
+});
+
+/// Task queue stored in one redis list per priority lane and owner, shared by every replica
+/// pointed at the server.
+///
+/// Each lane keeps a set of the owners with tasks queued in it. Leased tasks sit in a sorted
+/// set scored by their deadline, dead letters in a hash by task id.
+/// Every enqueue also pushes onto a signal list that idle workers `BLPOP`.
+#[derive(Clone)]
+pub struct RedisTaskQueue {
+    pool: RedisPool,
+    /// Ordered like `TaskPriority::ALL`.
+    queue_keys: [String; 3],
+    /// Owners with tasks queued in each lane.
+    owner_set_keys: [String; 3],
+    leases_key: String,
+    dead_letters_key: String,
+    signal_key: String,
+    conversion_method: MarkdownConversionMethod,
+    lanes: Arc<LaneSchedule>,
+    owners: Arc<[OwnerSchedule; 3]>,
+    /// Opened on first wait, one per queue so waiters for different methods don't queue up
+    /// behind each other's BLPOP.
+    blocking: Arc<OnceCell<Mutex<ConnectionManager>>>,
+}
+
+impl RedisTaskQueue {
+    pub fn new(
+        pool: RedisPool,
+        key_prefix: &str,
+        conversion_method: MarkdownConversionMethod,
+    ) -> Self {
+        RedisTaskQueue {
+            pool,
+            conversion_method,
+            // The normal lane keeps the key from before lanes, so tasks queued by older
+            // versions are still picked up.
+            queue_keys: TaskPriority::ALL.map(|priority| match priority {
+                TaskPriority::Normal => format!("{key_prefix}:queue"),
+                _ => format!("{key_prefix}:queue:{}", priority.slug()),
+            }),
+            owner_set_keys: TaskPriority::ALL
+                .map(|priority| format!("{key_prefix}:owners:{}", priority.slug())),
+            leases_key: format!("{key_prefix}:leases"),
+            dead_letters_key: format!("{key_prefix}:dead_letters"),
+            signal_key: format!("{key_prefix}:queue_signal"),
+            lanes: Arc::new(LaneSchedule::default()),
+            owners: Arc::new(Default::default()),
+            blocking: Arc::new(OnceCell::new()),
+        }
+    }
+
+    /// Tasks without an owner use the lane's own list, where tasks queued before owners were.
+    fn queue_key(&self, priority: TaskPriority, owner: Option<&str>) -> String {
+        let lane = &self.queue_keys[priority as usize];
+        match owner {
+            Some(owner) => format!("{lane}:owner:{owner}"),
+            None => lane.clone(),
+        }
+    }
+
+    /// Queue a task and note its owner in the lane's set, both or neither.
+    async fn push_task(&self, task: &TaskMessage) -> Result<(), QueueError> {
+        let mut pipe = redis::pipe();
+        pipe.atomic().rpush(
+            self.queue_key(task.priority, task.owner.as_deref()),
+            serde_json::to_string(task)?,
+        );
+        if let Some(owner) = &task.owner {
+            pipe.sadd(&self.owner_set_keys[task.priority as usize], owner);
+        }
+        let _: () = pipe.query_async(&mut self.pool.get()).await?;
+        Ok(())
+    }
+
+    /// Owners with tasks queued in the lane.
+    async fn waiting_owners(
+        &self,
+        priority: TaskPriority,
+    ) -> Result<Vec<Option<String>>, QueueError> {
+        let (unowned, owners): (usize, Vec<String>) = redis::pipe()
+            .llen(self.queue_key(priority, None))
+            .smembers(&self.owner_set_keys[priority as usize])
+            .query_async(&mut self.pool.get())
+            .await?;
+        let mut waiting: Vec<Option<String>> = owners.into_iter().map(Some).collect();
+        if unowned > 0 {
+            waiting.push(None);
+        }
+        Ok(waiting)
+    }
+
+    /// Leased tasks, decoded from the lease members.
+    async fn leased_tasks(&self) -> Result<Vec<TaskMessage>, QueueError> {
+        let members: Vec<String> = self.pool.get().zrange(&self.leases_key, 0, -1).await?;
+        members
+            .iter()
+            .filter_map(|member| member.split_once(':'))
+            .map(|(_, payload)| Ok(serde_json::from_str(payload)?))
+            .collect()
+    }
+
+    /// Wake a worker blocked in `dequeue_wait`, on this replica or any other.
+    async fn signal_waiters(&self) -> Result<(), QueueError> {
+        let _: () = redis::pipe()
+            .rpush(&self.signal_key, 1)
+            .ignore()
+            .ltrim(&self.signal_key, -MAX_PENDING_SIGNALS, -1)
+            .ignore()
+            .query_async(&mut self.pool.get())
+            .await?;
+        Ok(())
+    }
+
+    async fn next_lease_deadline(&self) -> Result<Option<SystemTime>, QueueError> {
+        let earliest: Vec<(String, i64)> = self
+            .pool
+            .get()
+            .zrange_withscores(&self.leases_key, 0, 0)
+            .await?;
+        Ok(earliest
+            .first()
+            .map(|(_, millis)| UNIX_EPOCH + Duration::from_millis((*millis).max(0) as u64)))
+    }
+}
+
+#[async_trait]
+impl TaskQueueImplementation for RedisTaskQueue {
+    async fn enqueue(&self, task: TaskMessage) -> Result<(), QueueError> {
+        self.push_task(&task).await?;
+        self.signal_waiters().await
+    }
+
+    async fn dequeue(&self, lease_for: Duration) -> Result<Option<TaskLease>, QueueError> {
+        let now = SystemTime::now();
+        let deadline = now + lease_for;
+        let token = rand::random::<u64>().to_string();
+        let mut requeue = REQUEUE_EXPIRED_SCRIPT.prepare_invoke();
+        for key in self.queue_keys.iter().chain(&self.owner_set_keys) {
+            requeue.key(key);
+        }
+        let _: usize = requeue
+            .key(&self.leases_key)
+            .arg(unix_millis(now))
+            .invoke_async(&mut self.pool.get())
+            .await?;
+        let mut processing = HashMap::new();
+        for task in self.leased_tasks().await? {
+            *processing.entry(task.owner).or_default() += 1;
+        }
+        for priority in self.lanes.next_order() {
+            let waiting = self.waiting_owners(priority).await?;
+            let Some(owner) = self.owners[priority as usize].pick(&waiting, &processing) else {
+                continue;
+            };
+            // Another replica may have emptied the owner's queue since we looked.
+            let payload: Option<String> = LEASE_OWNER_TASK_SCRIPT
+                .key(self.queue_key(priority, owner.as_deref()))
+                .key(&self.owner_set_keys[priority as usize])
+                .key(&self.leases_key)
+                .arg(owner.as_deref().unwrap_or_default())
+                .arg(unix_millis(deadline))
+                .arg(&token)
+                .invoke_async(&mut self.pool.get())
+                .await?;
+            if let Some(payload) = payload {
+                return Ok(Some(TaskLease {
+                    task: serde_json::from_str(&payload)?,
+                    // The receipt is the sorted set member itself.
+                    receipt: format!("{token}:{payload}"),
+                    deadline,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn dequeue_wait(
+        &self,
+        lease_for: Duration,
+        wait: Duration,
+    ) -> Result<Option<TaskLease>, QueueError> {
+        let give_up = Instant::now() + wait;
+        loop {
+            if let Some(lease) = self.dequeue(lease_for).await? {
+                return Ok(Some(lease));
+            }
+            let now = Instant::now();
+            if now >= give_up {
+                return Ok(None);
+            }
+            let wake_at = next_wake(give_up, self.next_lease_deadline().await?);
+            // A zero timeout would block forever.
+            let timeout = (wake_at - now).max(Duration::from_millis(10));
+            let blocking = self
+                .blocking
+                .get_or_try_init(|| async { self.pool.blocking_connection().await.map(Mutex::new) })
+                .await?;
+            let mut connection = blocking.lock().await;
+            let _: Option<(String, String)> = connection
+                .blpop(&self.signal_key, timeout.as_secs_f64())
+                .await?;
+        }
+    }
+
+    async fn extend_lease(
+        &self,
+        lease: &mut TaskLease,
+        lease_for: Duration,
+    ) -> Result<(), QueueError> {
+        let deadline = SystemTime::now() + lease_for;
+        let extended: bool = EXTEND_LEASE_SCRIPT
+            .key(&self.leases_key)
+            .arg(&lease.receipt)
+            .arg(unix_millis(deadline))
+            .invoke_async(&mut self.pool.get())
+            .await?;
+        if !extended {
+            return Err(QueueError::LeaseLost);
+        }
+        lease.deadline = deadline;
+        Ok(())
+    }
+
+    async fn ack(&self, lease: &TaskLease) -> Result<(), QueueError> {
+        let removed: usize = self
+            .pool
+            .get()
+            .zrem(&self.leases_key, &lease.receipt)
+            .await?;
+        if removed == 0 {
+            return Err(QueueError::LeaseLost);
+        }
+        // The owner may have been held back by its processing limit.
+        if OWNER_POLICY.has_limits() {
+            self.signal_waiters().await?;
+        }
+        Ok(())
+    }
+
+    async fn dead_letter(
+        &self,
+        lease: &TaskLease,
+        dead_letter: DeadLetter,
+    ) -> Result<(), QueueError> {
+        let moved: bool = DEAD_LETTER_SCRIPT
+            .key(&self.leases_key)
+            .key(&self.dead_letters_key)
+            .arg(&lease.receipt)
+            .arg(dead_letter.task.id.to_string())
+            .arg(serde_json::to_string(&dead_letter)?)
+            .invoke_async(&mut self.pool.get())
+            .await?;
+        if !moved {
+            return Err(QueueError::LeaseLost);
+        }
+        if OWNER_POLICY.has_limits() {
+            self.signal_waiters().await?;
+        }
+        Ok(())
+    }
+
+    async fn dead_letters(&self) -> Result<Vec<DeadLetter>, QueueError> {
+        let payloads: Vec<String> = self.pool.get().hvals(&self.dead_letters_key).await?;
+        let mut dead_letters = payloads
+            .iter()
+            .map(|payload| serde_json::from_str(payload))
+            .collect::<Result<Vec<DeadLetter>, _>>()?;
+        dead_letters.sort_by_key(|dead| dead.dead_lettered_at);
+        Ok(dead_letters)
+    }
+
+    async fn requeue_dead_letter(&self, id: TaskID) -> Result<Option<DeadLetter>, QueueError> {
+        let payload: Option<String> = self
+            .pool
+            .get()
+            .hget(&self.dead_letters_key, id.to_string())
+            .await?;
+        let Some(payload) = payload else {
+            return Ok(None);
+        };
+        let dead_letter: DeadLetter = serde_json::from_str(&payload)?;
+        let requeued: bool = REQUEUE_DEAD_LETTER_SCRIPT
+            .key(&self.dead_letters_key)
+            .key(self.queue_key(dead_letter.task.priority, dead_letter.task.owner.as_deref()))
+            .key(&self.owner_set_keys[dead_letter.task.priority as usize])
+            .arg(id.to_string())
+            .arg(serde_json::to_string(&dead_letter.task)?)
+            .arg(dead_letter.task.owner.as_deref().unwrap_or_default())
+            .invoke_async(&mut self.pool.get())
+            .await?;
+        if requeued {
+            self.signal_waiters().await?;
+        }
+        Ok(requeued.then_some(dead_letter))
+    }
+
+    async fn owner_depths(&self) -> Result<Vec<OwnerQueueDepth>, QueueError> {
+        let mut depths = BTreeMap::new();
+        let mut connection = self.pool.get();
+        for priority in TaskPriority::ALL {
+            for owner in self.waiting_owners(priority).await? {
+                let queued: u64 = connection
+                    .llen(self.queue_key(priority, owner.as_deref()))
+                    .await?;
+                if queued > 0 {
+                    *owner_depth(&mut depths, &owner, self.conversion_method)
+                        .queued
+                        .entry(priority)
+                        .or_default() += queued;
+                }
+            }
+        }
+        for task in self.leased_tasks().await? {
+            owner_depth(&mut depths, &task.owner, task.conversion_method).processing += 1;
+        }
+        Ok(depths.into_values().collect())
+    }
+
+    async fn depth(&self) -> Result<u64, QueueError> {
+        let mut pipe = redis::pipe();
+        let mut queues = 0;
+        for priority in TaskPriority::ALL {
+            for owner in self.waiting_owners(priority).await? {
+                pipe.llen(self.queue_key(priority, owner.as_deref()));
+                queues += 1;
+            }
+        }
+        if queues == 0 {
+            return Ok(0);
+        }
+        let lengths: Vec<u64> = pipe.query_async(&mut self.pool.get()).await?;
+        Ok(lengths.into_iter().sum())
+    }
+}
+
+/// Key prefix of the queue for one conversion method.
+pub fn method_key_prefix(key_prefix: &str, method: MarkdownConversionMethod) -> String {
+    format!("{key_prefix}:{}", method.slug())
+}
+
+/// Queues used to be shared by every method under `{key_prefix}:queue`. Move whatever an
+/// older version left queued or dead lettered there onto the per method queues.
+pub async fn split_shared_queue(pool: &RedisPool, key_prefix: &str) -> Result<(), QueueError> {
+    let shared_queue = format!("{key_prefix}:queue");
+    let shared_dead_letters = format!("{key_prefix}:dead_letters");
+    let queue_for =
+        |method| RedisTaskQueue::new(pool.clone(), &method_key_prefix(key_prefix, method), method);
+    let mut connection = pool.get();
+    let mut moved = 0;
+    // Enqueue before popping, so a crash part way duplicates a task rather than losing it.
+    while let Some(payload) = connection
+        .lindex::<_, Option<String>>(&shared_queue, 0)
+        .await?
+    {
+        let task: TaskMessage = serde_json::from_str(&payload)?;
+        queue_for(task.conversion_method).enqueue(task).await?;
+        let _: Option<String> = connection.lpop(&shared_queue, None).await?;
+        moved += 1;
+    }
+    // Leases still running on an older replica are left for it to ack, expired ones would
+    // never be reclaimed from the shared set.
+    let shared_leases = format!("{key_prefix}:leases");
+    let expired: Vec<String> = connection
+        .zrangebyscore(&shared_leases, "-inf", unix_millis(SystemTime::now()))
+        .await?;
+    for member in expired {
+        let Some((_, payload)) = member.split_once(':') else {
+            continue;
+        };
+        let task: TaskMessage = serde_json::from_str(payload)?;
+        queue_for(task.conversion_method).enqueue(task).await?;
+        let _: () = connection.zrem(&shared_leases, &member).await?;
+        moved += 1;
+    }
+    let dead_letters: Vec<(String, String)> = connection.hgetall(&shared_dead_letters).await?;
+    for (id, payload) in dead_letters {
+        let dead_letter: DeadLetter = serde_json::from_str(&payload)?;
+        let queue = queue_for(dead_letter.task.conversion_method);
+        let _: () = connection
+            .hset(&queue.dead_letters_key, &id, &payload)
+            .await?;
+        let _: () = connection.hdel(&shared_dead_letters, &id).await?;
+        moved += 1;
+    }
+    if moved > 0 {
+        info!(
+            moved,
+            "Moved tasks from the shared queue onto per method queues"
+        );
+    }
+    Ok(())
+}
+
+/// Status store keeping one json encoded `DocStatus` per task. Webhook deliveries sit in a
+/// hash by task id, with the pending ones in a sorted set scored by when they are due.
+#[derive(Clone)]
+pub struct RedisStatusStore {
+    pool: RedisPool,
+    key_prefix: String,
+    webhooks_key: String,
+    webhooks_due_key: String,
+}
+
+impl RedisStatusStore {
+    pub fn new(pool: RedisPool, key_prefix: &str) -> Self {
+        RedisStatusStore {
+            pool,
+            key_prefix: format!("{key_prefix}:status"),
+            webhooks_key: format!("{key_prefix}:webhooks"),
+            webhooks_due_key: format!("{key_prefix}:webhooks:due"),
+        }
+    }
+
+    fn status_key(&self, id: TaskID) -> String {
+        format!("{}:{id}", self.key_prefix)
+    }
+}
+
+#[async_trait]
+impl StatusStoreImplementation for RedisStatusStore {
+    async fn set_doc_status(&self, status: DocStatus) -> Result<(), DocStatusError> {
+        let payload = serde_json::to_string(&status)?;
+        let _: () = self
+            .pool
+            .get()
+            .set(self.status_key(status.request_id), payload)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_doc_status(&self, id: TaskID) -> Result<DocStatus, DocStatusError> {
+        let payload: Option<String> = self.pool.get().get(self.status_key(id)).await?;
+        match payload {
+            Some(payload) => Ok(serde_json::from_str(&payload)?),
+            None => Err(DocStatusError::DocidNotFound),
+        }
+    }
+
+    async fn cancel_doc_status(&self, id: TaskID) -> Result<DocStatus, CancelTaskError> {
+        let key = self.status_key(id);
+        loop {
+            let payload: Option<String> = self.pool.get().get(&key).await?;
+            let Some(payload) = payload else {
+                return Err(CancelTaskError::DocidNotFound);
+            };
+            let mut status: DocStatus = serde_json::from_str(&payload)?;
+            if status.status.is_finished() {
+                return Err(CancelTaskError::AlreadyFinished(status.status));
+            }
+            status.status = ProcessingStage::Cancelled;
+            let swapped: bool = COMPARE_AND_SET_SCRIPT
+                .key(&key)
+                .arg(&payload)
+                .arg(serde_json::to_string(&status)?)
+                .invoke_async(&mut self.pool.get())
+                .await?;
+            if swapped {
+                return Ok(status);
+            }
+            // Written to since it was read, so check it again.
+        }
+    }
+
+    async fn finish_doc_status(&self, status: DocStatus) -> Result<bool, DocStatusError> {
+        let stored: bool = FINISH_STATUS_SCRIPT
+            .key(self.status_key(status.request_id))
+            .arg(serde_json::to_string(&status)?)
+            .invoke_async(&mut self.pool.get())
+            .await?;
+        Ok(stored)
+    }
+
+    async fn set_webhook_delivery(&self, delivery: WebhookDelivery) -> Result<(), DocStatusError> {
+        let payload = serde_json::to_string(&delivery)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset(&self.webhooks_key, delivery.task_id, payload)
+            .ignore();
+        match delivery.next_attempt_at {
+            Some(due) => pipe.zadd(&self.webhooks_due_key, delivery.task_id, due),
+            None => pipe.zrem(&self.webhooks_due_key, delivery.task_id),
+        }
+        .ignore();
+        let _: () = pipe.query_async(&mut self.pool.get()).await?;
+        Ok(())
+    }
+
+    async fn get_webhook_delivery(
+        &self,
+        id: TaskID,
+    ) -> Result<Option<WebhookDelivery>, DocStatusError> {
+        let payload: Option<String> = self.pool.get().hget(&self.webhooks_key, id).await?;
+        Ok(payload
+            .map(|payload| serde_json::from_str(&payload))
+            .transpose()?)
+    }
+
+    async fn claim_due_webhook_deliveries(
+        &self,
+        now: i64,
+        claimed_until: i64,
+        limit: usize,
+    ) -> Result<Vec<WebhookDelivery>, DocStatusError> {
+        let payloads: Vec<String> = CLAIM_WEBHOOKS_SCRIPT
+            .key(&self.webhooks_due_key)
+            .key(&self.webhooks_key)
+            .arg(now)
+            .arg(claimed_until)
+            .arg(limit)
+            .invoke_async(&mut self.pool.get())
+            .await?;
+        Ok(payloads
+            .iter()
+            .map(|payload| serde_json::from_str(payload))
+            .collect::<Result<_, _>>()?)
+    }
+}
+
+/// Quarantine store with strike counts and quarantined documents in two hashes by content hash.
+#[derive(Clone)]
+pub struct RedisQuarantineStore {
+    pool: RedisPool,
+    strikes_key: String,
+    quarantine_key: String,
+}
+
+impl RedisQuarantineStore {
+    pub fn new(pool: RedisPool, key_prefix: &str) -> Self {
+        RedisQuarantineStore {
+            pool,
+            strikes_key: format!("{key_prefix}:content_strikes"),
+            quarantine_key: format!("{key_prefix}:quarantine"),
+        }
+    }
+}
+
+#[async_trait]
+impl QuarantineStoreImplementation for RedisQuarantineStore {
+    async fn record_strike(&self, content_hash: &str) -> Result<u32, QuarantineError> {
+        Ok(self
+            .pool
+            .get()
+            .hincr(&self.strikes_key, content_hash, 1)
+            .await?)
+    }
+
+    async fn quarantine(&self, document: QuarantinedDocument) -> Result<(), QuarantineError> {
+        let payload = serde_json::to_string(&document)?;
+        let _: () = self
+            .pool
+            .get()
+            .hset(&self.quarantine_key, &document.content_hash, payload)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_quarantined(
+        &self,
+        content_hash: &str,
+    ) -> Result<Option<QuarantinedDocument>, QuarantineError> {
+        let payload: Option<String> = self
+            .pool
+            .get()
+            .hget(&self.quarantine_key, content_hash)
+            .await?;
+        Ok(payload
+            .map(|payload| serde_json::from_str(&payload))
+            .transpose()?)
+    }
+
+    async fn quarantined(&self) -> Result<Vec<QuarantinedDocument>, QuarantineError> {
+        let payloads: Vec<String> = self.pool.get().hvals(&self.quarantine_key).await?;
+        let mut quarantined = payloads
+            .iter()
+            .map(|payload| serde_json::from_str(payload))
+            .collect::<Result<Vec<QuarantinedDocument>, _>>()?;
+        quarantined.sort_by_key(|document| document.quarantined_at);
+        Ok(quarantined)
+    }
+
+    async fn release(
+        &self,
+        content_hash: &str,
+    ) -> Result<Option<QuarantinedDocument>, QuarantineError> {
+        let (payload, _, _): (Option<String>, usize, usize) = redis::pipe()
+            .atomic()
+            .hget(&self.quarantine_key, content_hash)
+            .hdel(&self.quarantine_key, content_hash)
+            .hdel(&self.strikes_key, content_hash)
+            .query_async(&mut self.pool.get())
+            .await?;
+        Ok(payload
+            .map(|payload| serde_json::from_str(&payload))
+            .transpose()?)
+    }
+}
+
+/// API keys in a hash by id, usage in a hash per day that expires once the day is over.
+#[derive(Clone)]
+pub struct RedisApiKeyStore {
+    pool: RedisPool,
+    keys_key: String,
+    usage_key_prefix: String,
+}
+
+/// How long a day's usage is kept, a little past the day so late charges still land.
+const API_KEY_USAGE_TTL_SECS: i64 = 2 * 24 * 60 * 60;
+
+impl RedisApiKeyStore {
+    pub fn new(pool: RedisPool, key_prefix: &str) -> Self {
+        RedisApiKeyStore {
+            pool,
+            keys_key: format!("{key_prefix}:api_keys"),
+            usage_key_prefix: format!("{key_prefix}:api_key_usage"),
+        }
+    }
+
+    fn usage_key(&self, day: i64) -> String {
+        format!("{}:{day}", self.usage_key_prefix)
+    }
+}
+
+#[async_trait]
+impl ApiKeyStoreImplementation for RedisApiKeyStore {
+    async fn get_key(&self, id: &str) -> Result<Option<ApiKey>, ApiKeyError> {
+        let payload: Option<String> = self.pool.get().hget(&self.keys_key, id).await?;
+        Ok(payload
+            .map(|payload| serde_json::from_str(&payload))
+            .transpose()?)
+    }
+
+    async fn put_key(&self, key: ApiKey) -> Result<(), ApiKeyError> {
+        let payload = serde_json::to_string(&key)?;
+        let _: () = self
+            .pool
+            .get()
+            .hset(&self.keys_key, &key.id, payload)
+            .await?;
+        Ok(())
+    }
+
+    async fn keys(&self) -> Result<Vec<ApiKey>, ApiKeyError> {
+        let payloads: Vec<String> = self.pool.get().hvals(&self.keys_key).await?;
+        let mut keys = payloads
+            .iter()
+            .map(|payload| serde_json::from_str(payload))
+            .collect::<Result<Vec<ApiKey>, _>>()?;
+        keys.sort_by_key(|key| key.created_at);
+        Ok(keys)
+    }
+
+    async fn revoke_key(&self, id: &str) -> Result<Option<ApiKey>, ApiKeyError> {
+        let (payload, _): (Option<String>, usize) = redis::pipe()
+            .atomic()
+            .hget(&self.keys_key, id)
+            .hdel(&self.keys_key, id)
+            .query_async(&mut self.pool.get())
+            .await?;
+        Ok(payload
+            .map(|payload| serde_json::from_str(&payload))
+            .transpose()?)
+    }
+
+    async fn record_usage(
+        &self,
+        id: &str,
+        day: i64,
+        usage: ApiKeyUsage,
+    ) -> Result<(), ApiKeyError> {
+        let usage_key = self.usage_key(day);
+        let _: () = redis::pipe()
+            .atomic()
+            .hincr(&usage_key, format!("{id}:documents"), usage.documents)
+            .ignore()
+            .hincr(&usage_key, format!("{id}:pages"), usage.pages)
+            .ignore()
+            .expire(&usage_key, API_KEY_USAGE_TTL_SECS)
+            .ignore()
+            .query_async(&mut self.pool.get())
+            .await?;
+        Ok(())
+    }
+
+    async fn usage(&self, id: &str, day: i64) -> Result<ApiKeyUsage, ApiKeyError> {
+        let (documents, pages): (Option<u64>, Option<u64>) = self
+            .pool
+            .get()
+            .hget(
+                self.usage_key(day),
+                &[format!("{id}:documents"), format!("{id}:pages")],
+            )
+            .await?;
+        Ok(ApiKeyUsage {
+            documents: documents.unwrap_or_default(),
+            pages: pages.unwrap_or_default(),
+        })
+    }
+
+    async fn reserve_document(
+        &self,
+        id: &str,
+        day: i64,
+        limit: Option<u64>,
+    ) -> Result<bool, ApiKeyError> {
+        let usage_key = self.usage_key(day);
+        let field = format!("{id}:documents");
+        let (used,): (u64,) = redis::pipe()
+            .atomic()
+            .hincr(&usage_key, &field, 1)
+            .expire(&usage_key, API_KEY_USAGE_TTL_SECS)
+            .ignore()
+            .query_async(&mut self.pool.get())
+            .await?;
+        if limit.is_some_and(|limit| used > limit) {
+            // Over the limit, take the document back out again.
+            let _: i64 = self.pool.get().hincr(&usage_key, &field, -1).await?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    async fn refund_document(&self, id: &str, day: i64) -> Result<(), ApiKeyError> {
+        let _: i64 = self
+            .pool
+            .get()
+            .hincr(self.usage_key(day), format!("{id}:documents"), -1)
+            .await?;
+        Ok(())
+    }
+}
+
+/// How long to wait before subscribing again after losing the connection.
+const TASK_EVENT_RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Event bus over redis pub/sub, reaching the subscribers of every replica. Events published
+/// while a replica is resubscribing are lost to it.
+#[derive(Clone)]
+pub struct RedisTaskEventBus {
+    pool: RedisPool,
+    channel: String,
+    sender: broadcast::Sender<TaskEvent>,
+}
+
+impl RedisTaskEventBus {
+    pub fn new(pool: RedisPool, key_prefix: &str) -> Self {
+        let bus = RedisTaskEventBus {
+            pool,
+            channel: format!("{key_prefix}:task_events"),
+            sender: broadcast::channel(TASK_EVENT_BUFFER).0,
+        };
+        tokio::spawn(bus.clone().listen());
+        bus
+    }
+
+    /// Forward the channel's messages to this process's subscribers, resubscribing whenever
+    /// the connection drops.
+    async fn listen(self) {
+        loop {
+            if let Err(err) = self.forward_messages().await {
+                warn!(%err, channel = self.channel, "Lost the task event subscription");
+            }
+            sleep(TASK_EVENT_RESUBSCRIBE_DELAY).await;
+        }
+    }
+
+    async fn forward_messages(&self) -> Result<(), redis::RedisError> {
+        let mut pubsub = self.pool.pubsub().await?;
+        pubsub.subscribe(&self.channel).await?;
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let payload: String = message.get_payload()?;
+            match serde_json::from_str(&payload) {
+                Ok(event) => {
+                    let _ = self.sender.send(event);
+                }
+                Err(err) => warn!(%err, "Skipping a malformed task event"),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TaskEventBusImplementation for RedisTaskEventBus {
+    async fn publish(&self, event: TaskEvent) -> Result<(), TaskEventError> {
+        let payload = serde_json::to_string(&event)?;
+        let _: i64 = self.pool.get().publish(&self.channel, payload).await?;
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<TaskEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// These run the Lua scripts against the server in `REDIS_TEST_URL`, so they are ignored by
+/// default. Run them with `REDIS_TEST_URL=redis://localhost:6379 cargo test -- --ignored`.
+/// Every test works under a key prefix of its own.
+#[cfg(test)]
+mod tests {
+    use super::super::tests::queued_task;
+    use super::*;
+    use crate::types::{ConversionOptions, FileLocation};
+
+    const LEASE: Duration = Duration::from_secs(60);
+
+    struct TestRedis {
+        pool: RedisPool,
+        key_prefix: String,
+    }
+
+    impl TestRedis {
+        async fn connect() -> Self {
+            let url = env::var("REDIS_TEST_URL")
+                .expect("The Redis tests need REDIS_TEST_URL, like redis://localhost:6379");
+            let config = RedisConfigParams {
+                url,
+                key_prefix: format!("crimson-test:{}", rand::random::<u64>()),
+                pool_size: 1,
+            };
+            let pool = RedisPool::connect(&config).await.unwrap();
+            TestRedis {
+                pool,
+                key_prefix: config.key_prefix,
+            }
+        }
+
+        fn queue(&self) -> RedisTaskQueue {
+            RedisTaskQueue::new(
+                self.pool.clone(),
+                &method_key_prefix(&self.key_prefix, MarkdownConversionMethod::Simple),
+                MarkdownConversionMethod::Simple,
+            )
+        }
+
+        async fn clean_up(self) {
+            let mut connection = self.pool.get();
+            let keys: Vec<String> = connection
+                .keys(format!("{}:*", self.key_prefix))
+                .await
+                .unwrap();
+            if !keys.is_empty() {
+                let _: () = connection.del(keys).await.unwrap();
+            }
+        }
+    }
+
+    async fn dequeued_id(queue: &RedisTaskQueue, lease_for: Duration) -> Option<TaskID> {
+        let lease = queue.dequeue(lease_for).await.unwrap();
+        lease.map(|lease| lease.task.id)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server in REDIS_TEST_URL"]
+    async fn expired_leases_are_handed_out_again() {
+        let redis = TestRedis::connect().await;
+        let queue = redis.queue();
+        queue.enqueue(queued_task(1)).await.unwrap();
+        queue.enqueue(queued_task(2)).await.unwrap();
+
+        let mut expired = queue.dequeue(Duration::ZERO).await.unwrap().unwrap();
+        assert_eq!(expired.task.id, 1);
+        let lease = queue.dequeue(LEASE).await.unwrap().unwrap();
+        assert_eq!(lease.task.id, 1);
+        assert!(matches!(
+            queue.extend_lease(&mut expired, LEASE).await,
+            Err(QueueError::LeaseLost)
+        ));
+        queue.ack(&lease).await.unwrap();
+        assert_eq!(dequeued_id(&queue, LEASE).await, Some(2));
+        assert_eq!(dequeued_id(&queue, LEASE).await, None);
+        redis.clean_up().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server in REDIS_TEST_URL"]
+    async fn dead_letters_can_be_requeued_once() {
+        let redis = TestRedis::connect().await;
+        let queue = redis.queue();
+        queue.enqueue(queued_task(7)).await.unwrap();
+        let lease = queue.dequeue(LEASE).await.unwrap().unwrap();
+        let dead_letter = DeadLetter {
+            task: lease.task.clone(),
+            error: "boom".to_string(),
+            attempts: 3,
+            dead_lettered_at: 0,
+        };
+        queue.dead_letter(&lease, dead_letter).await.unwrap();
+        assert_eq!(queue.dead_letters().await.unwrap().len(), 1);
+        assert_eq!(dequeued_id(&queue, LEASE).await, None);
+
+        let requeued = queue.requeue_dead_letter(7).await.unwrap();
+        assert_eq!(requeued.map(|dead| dead.error), Some("boom".to_string()));
+        assert!(queue.requeue_dead_letter(7).await.unwrap().is_none());
+        assert_eq!(dequeued_id(&queue, LEASE).await, Some(7));
+        redis.clean_up().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server in REDIS_TEST_URL"]
+    async fn cancelling_leaves_finished_tasks_alone() {
+        let redis = TestRedis::connect().await;
+        let statuses = RedisStatusStore::new(redis.pool.clone(), &redis.key_prefix);
+        let status = DocStatus::new_from_id_loc(
+            3,
+            FileLocation::LocalPath("/tmp/3.pdf".into()),
+            MarkdownConversionMethod::Simple,
+            ConversionOptions::default(),
+        );
+        statuses.set_doc_status(status.clone()).await.unwrap();
+
+        let cancelled = statuses.cancel_doc_status(3).await.unwrap();
+        assert_eq!(cancelled.status, ProcessingStage::Cancelled);
+        assert!(matches!(
+            statuses.cancel_doc_status(3).await,
+            Err(CancelTaskError::AlreadyFinished(ProcessingStage::Cancelled))
+        ));
+        assert!(matches!(
+            statuses.cancel_doc_status(4).await,
+            Err(CancelTaskError::DocidNotFound)
+        ));
+
+        let mut completed = status;
+        completed.status = ProcessingStage::Completed;
+        assert!(!statuses.finish_doc_status(completed.clone()).await.unwrap());
+        assert_eq!(
+            statuses.get_doc_status(3).await.unwrap().status,
+            ProcessingStage::Cancelled
+        );
+        completed.request_id = 5;
+        assert!(statuses.finish_doc_status(completed).await.unwrap());
+        assert_eq!(
+            statuses.get_doc_status(5).await.unwrap().status,
+            ProcessingStage::Completed
+        );
+        redis.clean_up().await;
+    }
+}
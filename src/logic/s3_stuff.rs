@@ -1,11 +1,43 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use async_trait::async_trait;
 use aws_config::BehaviorVersion;
 use aws_config::Region;
 use aws_sdk_s3::Client as S3Client;
 use aws_sdk_s3::config::Credentials;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use tokio::fs;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::OnceCell;
+use tracing::warn;
+
+use crate::types::{
+    DownloadedFile, FileLocation, FileStoreImplementation, LocalPath, S3Location, StoreError,
+};
+
+use super::local_store::{LOCAL_STORE_PATH, S3ConfigParams};
 
-use crate::types::S3Location;
+/// Files at least this large are uploaded in parts rather than with a single PUT.
+pub static S3_MULTIPART_THRESHOLD_BYTES: LazyLock<u64> = LazyLock::new(|| {
+    env::var("S3_MULTIPART_THRESHOLD_BYTES")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(32 * 1024 * 1024)
+});
 
-use super::local_store::S3ConfigParams;
+/// Size of every part but the last, S3 requires at least 5MiB.
+pub static S3_MULTIPART_PART_BYTES: LazyLock<usize> = LazyLock::new(|| {
+    env::var("S3_MULTIPART_PART_BYTES")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(8 * 1024 * 1024)
+        .max(5 * 1024 * 1024)
+});
 
 // Build a Region, Credentials and (if provided) custom Endpoint
 pub async fn make_s3_client(s3_config: &S3ConfigParams, s3_loc: &S3Location) -> S3Client {
@@ -27,3 +59,248 @@ pub async fn make_s3_client(s3_config: &S3ConfigParams, s3_loc: &S3Location) ->
     let sdk_config = cfg_loader.load().await;
     S3Client::new(&sdk_config)
 }
+
+/// Stream an object to `base_path/{key}`, deleted again once the returned file drops.
+pub async fn download_s3_to_file(
+    client: &S3Client,
+    s3_loc: &S3Location,
+    base_path: &Path,
+) -> Result<DownloadedFile, StoreError> {
+    let resp = client
+        .get_object()
+        .bucket(&s3_loc.bucket)
+        .key(&s3_loc.key)
+        .send()
+        .await
+        .map_err(|err| StoreError::S3(err.into()))?;
+    let full_path = base_path.join(&s3_loc.key);
+    if let Some(parent) = full_path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .map_err(|_| StoreError::LocalFile)?;
+    }
+    let mut file = fs::File::create(&full_path)
+        .await
+        .map_err(|_| StoreError::LocalFile)?;
+    // Cleans up a partly written file too.
+    let downloaded = DownloadedFile::temporary(full_path);
+    let mut body = resp.body.into_async_read();
+    io::copy(&mut body, &mut file).await.map_err(|err| {
+        warn!(%err, key = s3_loc.key, "Could not download object");
+        StoreError::LocalFile
+    })?;
+    file.flush().await.map_err(|_| StoreError::LocalFile)?;
+    Ok(downloaded)
+}
+
+/// File store that keeps every document in an S3 compatible bucket, so any replica can
+/// fetch what another one uploaded.
+#[derive(Debug, Clone)]
+pub struct S3FileStore {
+    /// Where downloaded objects are written for the converters to read.
+    base_path: PathBuf,
+    s3_config: S3ConfigParams,
+    client: OnceCell<S3Client>,
+}
+
+impl Default for S3FileStore {
+    fn default() -> Self {
+        S3FileStore::new(
+            (*LOCAL_STORE_PATH).clone().into(),
+            S3ConfigParams::default(),
+        )
+    }
+}
+
+impl S3FileStore {
+    pub fn new(base_path: PathBuf, s3_config: S3ConfigParams) -> Self {
+        S3FileStore {
+            base_path,
+            s3_config,
+            client: OnceCell::new(),
+        }
+    }
+
+    /// Location of `key` in the default bucket.
+    fn default_location(&self, key: String) -> S3Location {
+        S3Location {
+            key,
+            bucket: self.s3_config.default_bucket.clone(),
+            endpoint: self.s3_config.endpoint.clone(),
+            region: self.s3_config.region.clone(),
+        }
+    }
+
+    async fn default_client(&self) -> &S3Client {
+        self.client
+            .get_or_init(|| async {
+                make_s3_client(&self.s3_config, &self.default_location(String::new())).await
+            })
+            .await
+    }
+
+    /// Objects outside the configured endpoint need a client of their own.
+    async fn client_for(&self, s3_loc: &S3Location) -> S3Client {
+        if s3_loc.endpoint == self.s3_config.endpoint && s3_loc.region == self.s3_config.region {
+            self.default_client().await.clone()
+        } else {
+            make_s3_client(&self.s3_config, s3_loc).await
+        }
+    }
+
+    async fn multipart_upload(
+        &self,
+        client: &S3Client,
+        local_path: &LocalPath,
+        location: &S3Location,
+    ) -> Result<(), StoreError> {
+        let created = client
+            .create_multipart_upload()
+            .bucket(&location.bucket)
+            .key(&location.key)
+            .send()
+            .await
+            .map_err(|err| StoreError::S3(err.into()))?;
+        let upload_id = created.upload_id().ok_or(StoreError::InvalidLocation)?;
+
+        let result = self
+            .upload_parts(client, local_path, location, upload_id)
+            .await;
+        let parts = match result {
+            Ok(parts) => parts,
+            Err(err) => {
+                // Don't leave orphaned parts around to be billed for.
+                if let Err(abort_err) = client
+                    .abort_multipart_upload()
+                    .bucket(&location.bucket)
+                    .key(&location.key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                {
+                    warn!(%abort_err, key = location.key, "Failed to abort multipart upload");
+                }
+                return Err(err);
+            }
+        };
+        client
+            .complete_multipart_upload()
+            .bucket(&location.bucket)
+            .key(&location.key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|err| StoreError::S3(err.into()))?;
+        Ok(())
+    }
+
+    async fn upload_parts(
+        &self,
+        client: &S3Client,
+        local_path: &LocalPath,
+        location: &S3Location,
+        upload_id: &str,
+    ) -> Result<Vec<CompletedPart>, StoreError> {
+        let mut file = fs::File::open(local_path)
+            .await
+            .map_err(|_| StoreError::LocalFile)?;
+        let mut parts = Vec::new();
+        let mut part_number = 1;
+        loop {
+            let mut buffer = Vec::with_capacity(*S3_MULTIPART_PART_BYTES);
+            (&mut file)
+                .take(*S3_MULTIPART_PART_BYTES as u64)
+                .read_to_end(&mut buffer)
+                .await
+                .map_err(|_| StoreError::LocalFile)?;
+            if buffer.is_empty() {
+                break;
+            }
+            let uploaded = client
+                .upload_part()
+                .bucket(&location.bucket)
+                .key(&location.key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(buffer))
+                .send()
+                .await
+                .map_err(|err| StoreError::S3(err.into()))?;
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(uploaded.e_tag().map(str::to_string))
+                    .part_number(part_number)
+                    .build(),
+            );
+            part_number += 1;
+        }
+        Ok(parts)
+    }
+}
+
+#[async_trait]
+impl FileStoreImplementation for S3FileStore {
+    async fn upload_from_file(
+        &self,
+        local_path: LocalPath,
+        upload_key: String,
+    ) -> Result<FileLocation, StoreError> {
+        let location = self.default_location(upload_key);
+        let client = self.default_client().await;
+        let size = fs::metadata(&local_path)
+            .await
+            .map_err(|_| StoreError::LocalFile)?
+            .len();
+        if size >= *S3_MULTIPART_THRESHOLD_BYTES {
+            self.multipart_upload(client, &local_path, &location)
+                .await?;
+        } else {
+            let body = ByteStream::from_path(&local_path)
+                .await
+                .map_err(|_| StoreError::LocalFile)?;
+            client
+                .put_object()
+                .bucket(&location.bucket)
+                .key(&location.key)
+                .body(body)
+                .send()
+                .await
+                .map_err(|err| StoreError::S3(err.into()))?;
+        }
+        Ok(FileLocation::S3Location(location))
+    }
+
+    async fn download_to_file(&self, src: &FileLocation) -> Result<DownloadedFile, StoreError> {
+        match src {
+            FileLocation::LocalPath(path) => Ok(DownloadedFile::stored(path.clone())),
+            FileLocation::S3Location(s3_loc) => {
+                let client = self.client_for(s3_loc).await;
+                download_s3_to_file(&client, s3_loc, &self.base_path).await
+            }
+        }
+    }
+
+    async fn delete(&self, target: &FileLocation) -> Result<(), StoreError> {
+        match target {
+            FileLocation::S3Location(s3_loc) => {
+                let client = self.client_for(s3_loc).await;
+                client
+                    .delete_object()
+                    .bucket(&s3_loc.bucket)
+                    .key(&s3_loc.key)
+                    .send()
+                    .await
+                    .map_err(|err| StoreError::S3(err.into()))?;
+                Ok(())
+            }
+            FileLocation::LocalPath(path) => fs::remove_file(path)
+                .await
+                .map_err(|_| StoreError::LocalFile),
+        }
+    }
+}
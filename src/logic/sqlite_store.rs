@@ -0,0 +1,1363 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+use tokio::sync::{Notify, broadcast};
+use tokio::time::{Instant, sleep_until, timeout};
+use tracing::{info, warn};
+
+use crate::types::{
+    ApiKey, ApiKeyError, ApiKeyStoreImplementation, ApiKeyUsage, CancelTaskError, DeadLetter,
+    DocStatus, DocStatusError, MarkdownConversionMethod, OwnerQueueDepth, ProcessingStage,
+    QuarantineError, QuarantineStoreImplementation, QuarantinedDocument, QueueError,
+    StatusStoreImplementation, TaskEvent, TaskEventBusImplementation, TaskEventError, TaskID,
+    TaskLease, TaskMessage, TaskPriority, TaskQueueImplementation, WebhookDelivery, unix_millis,
+};
+
+use super::local_store::LOCAL_STORE_PATH;
+use super::{LaneSchedule, OWNER_POLICY, OwnerSchedule, TASK_EVENT_BUFFER, next_wake, owner_depth};
+
+pub static SQLITE_PATH: LazyLock<String> = LazyLock::new(|| {
+    env::var("SQLITE_PATH").unwrap_or_else(|_| {
+        PathBuf::from(&*LOCAL_STORE_PATH)
+            .join("crimson.sqlite3")
+            .to_string_lossy()
+            .into_owned()
+    })
+});
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS doc_status (
+    id TEXT PRIMARY KEY NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS task_queue (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL,
+    message TEXT NOT NULL,
+    lease_receipt TEXT,
+    lease_deadline INTEGER
+);
+-- A task is queued at most once, even when serve and worker share the file. Databases from
+-- before the index may hold duplicates, only the oldest row of each is kept.
+DELETE FROM task_queue WHERE seq NOT IN (SELECT MIN(seq) FROM task_queue GROUP BY id);
+CREATE UNIQUE INDEX IF NOT EXISTS task_queue_by_id ON task_queue (id);
+CREATE TABLE IF NOT EXISTS dead_letters (
+    id TEXT PRIMARY KEY NOT NULL,
+    data TEXT NOT NULL,
+    dead_lettered_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS content_strikes (
+    content_hash TEXT PRIMARY KEY NOT NULL,
+    strikes INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS quarantine (
+    content_hash TEXT PRIMARY KEY NOT NULL,
+    data TEXT NOT NULL,
+    quarantined_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS api_keys (
+    id TEXT PRIMARY KEY NOT NULL,
+    data TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS api_key_usage (
+    id TEXT NOT NULL,
+    day INTEGER NOT NULL,
+    documents INTEGER NOT NULL,
+    pages INTEGER NOT NULL,
+    PRIMARY KEY (id, day)
+);
+CREATE TABLE IF NOT EXISTS task_events (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    event TEXT NOT NULL,
+    published_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    task_id TEXT PRIMARY KEY NOT NULL,
+    data TEXT NOT NULL,
+    next_attempt_at INTEGER
+);
+CREATE INDEX IF NOT EXISTS webhook_deliveries_due
+    ON webhook_deliveries (next_attempt_at) WHERE next_attempt_at IS NOT NULL;
+";
+
+/// Columns added after the table was first created, brought into older databases on open.
+///
+/// `priority` is the lane's index in `TaskPriority::ALL`, rows queued before lanes are normal.
+/// `owner` copies the message's owner so the queue can be grouped by it.
+const TASK_QUEUE_MIGRATIONS: &[(&str, &str)] = &[
+    ("lease_receipt", "TEXT"),
+    ("lease_deadline", "INTEGER"),
+    ("priority", "INTEGER NOT NULL DEFAULT 1"),
+    ("owner", "TEXT"),
+];
+
+/// Indexes over migrated columns, created once the migrations have run.
+const TASK_QUEUE_INDEXES: &str = "
+DROP INDEX IF EXISTS task_queue_by_method;
+DROP INDEX IF EXISTS task_queue_by_lane;
+CREATE INDEX IF NOT EXISTS task_queue_by_owner
+    ON task_queue (json_extract(message, '$.conversion_method'), priority, owner, seq);
+";
+
+/// Single sqlite connection shared by the queue and status store.
+///
+/// rusqlite is blocking, so every query runs on the blocking thread pool.
+#[derive(Clone)]
+pub struct SqliteDatabase {
+    connection: Arc<Mutex<Connection>>,
+    /// Woken when this process queues a task, other processes are noticed through
+    /// `data_version`.
+    queue_changed: Arc<Notify>,
+}
+
+/// How often a waiting dequeue checks whether another process wrote to the database.
+const CROSS_PROCESS_POLL: Duration = Duration::from_millis(250);
+
+impl SqliteDatabase {
+    /// Open (or create) the database at `path` and make sure the tables exist.
+    pub async fn open(path: &Path) -> Result<Self, rusqlite::Error> {
+        let path = path.to_path_buf();
+        let connection = tokio::task::spawn_blocking(move || {
+            if let Some(parent) = path.parent() {
+                let _ = std::fs::create_dir_all(parent);
+            }
+            let connection = Connection::open(&path)?;
+            connection.pragma_update(None, "journal_mode", "WAL")?;
+            connection.pragma_update(None, "synchronous", "NORMAL")?;
+            connection.busy_timeout(Duration::from_secs(5))?;
+            connection.execute_batch(SCHEMA)?;
+            migrate_task_queue(&connection)?;
+            Ok::<_, rusqlite::Error>(connection)
+        })
+        .await
+        .expect("sqlite open should not panic")?;
+        Ok(SqliteDatabase {
+            connection: Arc::new(Mutex::new(connection)),
+            queue_changed: Arc::new(Notify::new()),
+        })
+    }
+
+    async fn run<T, E>(
+        &self,
+        query: impl FnOnce(&mut Connection) -> Result<T, E> + Send + 'static,
+    ) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            query(&mut connection)
+        })
+        .await
+        .expect("sqlite query should not panic")
+    }
+
+    /// sqlite's counter of commits made through other connections, along with the earliest
+    /// lease deadline, the two things that can make a task available without us hearing of it.
+    async fn queue_watermark(&self) -> Result<(i64, Option<SystemTime>), rusqlite::Error> {
+        self.run(|connection| {
+            let data_version = connection.query_row("PRAGMA data_version", [], |row| row.get(0))?;
+            let next_deadline: Option<i64> =
+                connection.query_row("SELECT MIN(lease_deadline) FROM task_queue", [], |row| {
+                    row.get(0)
+                })?;
+            let next_deadline = next_deadline
+                .map(|millis| UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64));
+            Ok((data_version, next_deadline))
+        })
+        .await
+    }
+
+    /// Put every unfinished task that isn't queued back on the queue.
+    ///
+    /// A task left in `Processing` was interrupted by a restart, and one in `Waiting` but
+    /// missing from the queue was dequeued right before it.
+    pub async fn recover_interrupted_tasks(&self) -> Result<usize, QueueError> {
+        self.run(|connection| {
+            let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let stranded: Vec<String> = {
+                let mut statement = tx.prepare(
+                    "SELECT data FROM doc_status
+                     WHERE json_extract(data, '$.status') IN ('Processing', 'Waiting')
+                     AND id NOT IN (SELECT id FROM task_queue)",
+                )?;
+                statement
+                    .query_map([], |row| row.get(0))?
+                    .collect::<Result<_, _>>()?
+            };
+            let mut requeued = 0;
+            for data in &stranded {
+                let mut status: DocStatus = serde_json::from_str(data)?;
+                status.status = ProcessingStage::Waiting;
+                let message = TaskMessage {
+                    id: status.request_id,
+                    location: status.file_location.clone(),
+                    conversion_method: status.conversion_method,
+                    conversion_options: status.conversion_options.clone(),
+                    priority: status.priority,
+                    owner: status.owner.clone(),
+                };
+                tx.execute(
+                    "UPDATE doc_status SET data = ?2 WHERE id = ?1",
+                    params![
+                        status.request_id.to_string(),
+                        serde_json::to_string(&status)?
+                    ],
+                )?;
+                requeued += tx.execute(
+                    "INSERT OR IGNORE INTO task_queue (id, message, priority, owner)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        message.id.to_string(),
+                        serde_json::to_string(&message)?,
+                        message.priority as i64,
+                        message.owner
+                    ],
+                )?;
+            }
+            tx.commit()?;
+            if requeued > 0 {
+                info!(count = requeued, "Requeued tasks interrupted by a restart");
+            }
+            Ok(requeued)
+        })
+        .await
+    }
+}
+
+fn migrate_task_queue(connection: &Connection) -> Result<(), rusqlite::Error> {
+    let existing: Vec<String> = connection
+        .prepare("SELECT name FROM pragma_table_info('task_queue')")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    for (column, kind) in TASK_QUEUE_MIGRATIONS {
+        if !existing.iter().any(|name| name == column) {
+            connection.execute_batch(&format!(
+                "ALTER TABLE task_queue ADD COLUMN {column} {kind}"
+            ))?;
+        }
+    }
+    connection.execute_batch(TASK_QUEUE_INDEXES)
+}
+
+/// Task queue persisted in sqlite, so queued work survives restarts.
+///
+/// Every method's queue shares the `task_queue` table, each only sees the rows whose message
+/// names its method. Within a method every priority lane is FIFO by `seq` for each owner.
+#[derive(Clone)]
+pub struct SqliteTaskQueue {
+    database: SqliteDatabase,
+    conversion_method: MarkdownConversionMethod,
+    /// The method as it appears in the json encoded messages.
+    method: String,
+    lanes: Arc<LaneSchedule>,
+    /// One per priority lane, ordered like `TaskPriority::ALL`.
+    owners: Arc<[OwnerSchedule; 3]>,
+}
+
+impl SqliteTaskQueue {
+    pub fn new(database: SqliteDatabase, method: MarkdownConversionMethod) -> Self {
+        let method_json = serde_json::to_value(method)
+            .ok()
+            .and_then(|value| value.as_str().map(str::to_string))
+            .expect("conversion methods serialize as strings");
+        SqliteTaskQueue {
+            database,
+            conversion_method: method,
+            method: method_json,
+            lanes: Arc::new(LaneSchedule::default()),
+            owners: Arc::new(Default::default()),
+        }
+    }
+}
+
+#[async_trait]
+impl TaskQueueImplementation for SqliteTaskQueue {
+    async fn enqueue(&self, task: TaskMessage) -> Result<(), QueueError> {
+        let payload = serde_json::to_string(&task)?;
+        self.database
+            .run(move |connection| {
+                connection.execute(
+                    "INSERT OR IGNORE INTO task_queue (id, message, priority, owner)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        task.id.to_string(),
+                        payload,
+                        task.priority as i64,
+                        task.owner
+                    ],
+                )?;
+                Ok::<_, QueueError>(())
+            })
+            .await?;
+        self.database.queue_changed.notify_waiters();
+        Ok(())
+    }
+
+    async fn dequeue(&self, lease_for: Duration) -> Result<Option<TaskLease>, QueueError> {
+        let method = self.method.clone();
+        let lanes = self.lanes.next_order();
+        let owners = self.owners.clone();
+        self.database
+            .run(move |connection| {
+                let now = SystemTime::now();
+                let deadline = now + lease_for;
+                let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                // Expired leases keep their seq, so they go back to where they were queued.
+                tx.execute(
+                    "UPDATE task_queue SET lease_receipt = NULL, lease_deadline = NULL
+                     WHERE lease_deadline <= ?1",
+                    params![unix_millis(now)],
+                )?;
+                let processing: HashMap<Option<String>, u64> = tx
+                    .prepare(
+                        "SELECT owner, COUNT(*) FROM task_queue
+                         WHERE lease_receipt IS NOT NULL
+                         AND json_extract(message, '$.conversion_method') = ?1
+                         GROUP BY owner",
+                    )?
+                    .query_map(params![method], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<Result<_, _>>()?;
+                let mut next: Option<(i64, String)> = None;
+                for priority in lanes {
+                    let waiting: Vec<Option<String>> = tx
+                        .prepare(
+                            "SELECT DISTINCT owner FROM task_queue
+                             WHERE lease_receipt IS NULL
+                             AND json_extract(message, '$.conversion_method') = ?1
+                             AND priority = ?2",
+                        )?
+                        .query_map(params![method, priority as i64], |row| row.get(0))?
+                        .collect::<Result<_, _>>()?;
+                    let Some(owner) = owners[priority as usize].pick(&waiting, &processing) else {
+                        continue;
+                    };
+                    next = tx
+                        .query_row(
+                            "SELECT seq, message FROM task_queue
+                             WHERE lease_receipt IS NULL
+                             AND json_extract(message, '$.conversion_method') = ?1
+                             AND priority = ?2 AND owner IS ?3
+                             ORDER BY seq LIMIT 1",
+                            params![method, priority as i64, owner],
+                            |row| Ok((row.get(0)?, row.get(1)?)),
+                        )
+                        .optional()?;
+                    if next.is_some() {
+                        break;
+                    }
+                }
+                let Some((seq, payload)) = next else {
+                    tx.commit()?;
+                    return Ok(None);
+                };
+                let receipt = rand::random::<u64>().to_string();
+                tx.execute(
+                    "UPDATE task_queue SET lease_receipt = ?2, lease_deadline = ?3 WHERE seq = ?1",
+                    params![seq, receipt, unix_millis(deadline)],
+                )?;
+                tx.commit()?;
+                Ok(Some(TaskLease {
+                    task: serde_json::from_str(&payload)?,
+                    receipt,
+                    deadline,
+                }))
+            })
+            .await
+    }
+
+    async fn dequeue_wait(
+        &self,
+        lease_for: Duration,
+        wait: Duration,
+    ) -> Result<Option<TaskLease>, QueueError> {
+        let give_up = Instant::now() + wait;
+        loop {
+            // Register before looking, so an enqueue in between still wakes us.
+            let notified = self.database.queue_changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(lease) = self.dequeue(lease_for).await? {
+                return Ok(Some(lease));
+            }
+            let (seen_version, next_deadline) = self.database.queue_watermark().await?;
+            let wake_at = next_wake(give_up, next_deadline);
+            loop {
+                if Instant::now() >= give_up {
+                    return Ok(None);
+                }
+                tokio::select! {
+                    _ = &mut notified => break,
+                    _ = sleep_until(wake_at.min(Instant::now() + CROSS_PROCESS_POLL)) => {}
+                }
+                if Instant::now() >= wake_at {
+                    break;
+                }
+                let (version, _) = self.database.queue_watermark().await?;
+                if version != seen_version {
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn extend_lease(
+        &self,
+        lease: &mut TaskLease,
+        lease_for: Duration,
+    ) -> Result<(), QueueError> {
+        let receipt = lease.receipt.clone();
+        let deadline = SystemTime::now() + lease_for;
+        let updated = self
+            .database
+            .run(move |connection| {
+                connection.execute(
+                    "UPDATE task_queue SET lease_deadline = ?2 WHERE lease_receipt = ?1",
+                    params![receipt, unix_millis(deadline)],
+                )
+            })
+            .await?;
+        if updated == 0 {
+            return Err(QueueError::LeaseLost);
+        }
+        lease.deadline = deadline;
+        Ok(())
+    }
+
+    async fn ack(&self, lease: &TaskLease) -> Result<(), QueueError> {
+        let receipt = lease.receipt.clone();
+        let deleted = self
+            .database
+            .run(move |connection| {
+                connection.execute(
+                    "DELETE FROM task_queue WHERE lease_receipt = ?1",
+                    params![receipt],
+                )
+            })
+            .await?;
+        if deleted == 0 {
+            return Err(QueueError::LeaseLost);
+        }
+        // The owner may have been held back by its processing limit.
+        if OWNER_POLICY.has_limits() {
+            self.database.queue_changed.notify_waiters();
+        }
+        Ok(())
+    }
+
+    async fn dead_letter(
+        &self,
+        lease: &TaskLease,
+        dead_letter: DeadLetter,
+    ) -> Result<(), QueueError> {
+        let receipt = lease.receipt.clone();
+        let payload = serde_json::to_string(&dead_letter)?;
+        self.database
+            .run(move |connection| {
+                let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let deleted = tx.execute(
+                    "DELETE FROM task_queue WHERE lease_receipt = ?1",
+                    params![receipt],
+                )?;
+                if deleted == 0 {
+                    return Err(QueueError::LeaseLost);
+                }
+                tx.execute(
+                    "INSERT OR REPLACE INTO dead_letters (id, data, dead_lettered_at)
+                     VALUES (?1, ?2, ?3)",
+                    params![
+                        dead_letter.task.id.to_string(),
+                        payload,
+                        dead_letter.dead_lettered_at
+                    ],
+                )?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn dead_letters(&self) -> Result<Vec<DeadLetter>, QueueError> {
+        let method = self.method.clone();
+        let payloads: Vec<String> = self
+            .database
+            .run(move |connection| {
+                connection
+                    .prepare(
+                        "SELECT data FROM dead_letters
+                         WHERE json_extract(data, '$.task.conversion_method') = ?1
+                         ORDER BY dead_lettered_at",
+                    )?
+                    .query_map(params![method], |row| row.get(0))?
+                    .collect::<Result<_, _>>()
+            })
+            .await?;
+        Ok(payloads
+            .iter()
+            .map(|payload| serde_json::from_str(payload))
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn requeue_dead_letter(&self, id: TaskID) -> Result<Option<DeadLetter>, QueueError> {
+        let requeued = self
+            .database
+            .run(move |connection| {
+                let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let payload: Option<String> = tx
+                    .query_row(
+                        "SELECT data FROM dead_letters WHERE id = ?1",
+                        params![id.to_string()],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let Some(payload) = payload else {
+                    return Ok(None);
+                };
+                let dead_letter: DeadLetter = serde_json::from_str(&payload)?;
+                tx.execute(
+                    "DELETE FROM dead_letters WHERE id = ?1",
+                    params![id.to_string()],
+                )?;
+                tx.execute(
+                    "INSERT OR IGNORE INTO task_queue (id, message, priority, owner)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        id.to_string(),
+                        serde_json::to_string(&dead_letter.task)?,
+                        dead_letter.task.priority as i64,
+                        dead_letter.task.owner
+                    ],
+                )?;
+                tx.commit()?;
+                Ok::<_, QueueError>(Some(dead_letter))
+            })
+            .await?;
+        if requeued.is_some() {
+            self.database.queue_changed.notify_waiters();
+        }
+        Ok(requeued)
+    }
+
+    async fn owner_depths(&self) -> Result<Vec<OwnerQueueDepth>, QueueError> {
+        let method = self.method.clone();
+        let rows: Vec<(Option<String>, i64, bool, u64)> = self
+            .database
+            .run(move |connection| {
+                connection
+                    .prepare(
+                        "SELECT owner, priority, lease_receipt IS NOT NULL, COUNT(*)
+                         FROM task_queue
+                         WHERE json_extract(message, '$.conversion_method') = ?1
+                         GROUP BY 1, 2, 3",
+                    )?
+                    .query_map(params![method], |row| {
+                        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                    })?
+                    .collect::<Result<_, _>>()
+            })
+            .await?;
+        let mut depths = BTreeMap::new();
+        for (owner, priority, leased, count) in rows {
+            let depth = owner_depth(&mut depths, &owner, self.conversion_method);
+            if leased {
+                depth.processing += count;
+            } else if let Some(priority) = TaskPriority::ALL.get(priority as usize) {
+                *depth.queued.entry(*priority).or_default() += count;
+            }
+        }
+        Ok(depths.into_values().collect())
+    }
+
+    async fn depth(&self) -> Result<u64, QueueError> {
+        let method = self.method.clone();
+        Ok(self
+            .database
+            .run(move |connection| {
+                connection.query_row(
+                    "SELECT COUNT(*) FROM task_queue
+                     WHERE json_extract(message, '$.conversion_method') = ?1
+                       AND lease_receipt IS NULL",
+                    params![method],
+                    |row| row.get(0),
+                )
+            })
+            .await?)
+    }
+}
+
+/// Status store keeping one json encoded `DocStatus` row per task.
+#[derive(Clone)]
+pub struct SqliteStatusStore {
+    database: SqliteDatabase,
+}
+
+impl SqliteStatusStore {
+    pub fn new(database: SqliteDatabase) -> Self {
+        SqliteStatusStore { database }
+    }
+}
+
+#[async_trait]
+impl StatusStoreImplementation for SqliteStatusStore {
+    async fn set_doc_status(&self, status: DocStatus) -> Result<(), DocStatusError> {
+        let payload = serde_json::to_string(&status)?;
+        self.database
+            .run(move |connection| {
+                connection.execute(
+                    "INSERT INTO doc_status (id, data) VALUES (?1, ?2)
+                     ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+                    params![status.request_id.to_string(), payload],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn get_doc_status(&self, id: TaskID) -> Result<DocStatus, DocStatusError> {
+        let payload: Option<String> = self
+            .database
+            .run(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT data FROM doc_status WHERE id = ?1",
+                        params![id.to_string()],
+                        |row| row.get(0),
+                    )
+                    .optional()
+            })
+            .await?;
+        match payload {
+            Some(payload) => Ok(serde_json::from_str(&payload)?),
+            None => Err(DocStatusError::DocidNotFound),
+        }
+    }
+
+    async fn cancel_doc_status(&self, id: TaskID) -> Result<DocStatus, CancelTaskError> {
+        self.database
+            .run(move |connection| {
+                let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let payload: Option<String> = tx
+                    .query_row(
+                        "SELECT data FROM doc_status WHERE id = ?1",
+                        params![id.to_string()],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let Some(payload) = payload else {
+                    return Err(CancelTaskError::DocidNotFound);
+                };
+                let mut status: DocStatus = serde_json::from_str(&payload)?;
+                if status.status.is_finished() {
+                    return Err(CancelTaskError::AlreadyFinished(status.status));
+                }
+                status.status = ProcessingStage::Cancelled;
+                tx.execute(
+                    "UPDATE doc_status SET data = ?2 WHERE id = ?1",
+                    params![id.to_string(), serde_json::to_string(&status)?],
+                )?;
+                tx.commit()?;
+                Ok(status)
+            })
+            .await
+    }
+
+    async fn finish_doc_status(&self, status: DocStatus) -> Result<bool, DocStatusError> {
+        let payload = serde_json::to_string(&status)?;
+        self.database
+            .run(move |connection| {
+                let changed = connection.execute(
+                    "INSERT INTO doc_status (id, data) VALUES (?1, ?2)
+                     ON CONFLICT (id) DO UPDATE SET data = excluded.data
+                     WHERE json_extract(doc_status.data, '$.status') <> 'Cancelled'",
+                    params![status.request_id.to_string(), payload],
+                )?;
+                Ok(changed == 1)
+            })
+            .await
+    }
+
+    async fn set_webhook_delivery(&self, delivery: WebhookDelivery) -> Result<(), DocStatusError> {
+        let payload = serde_json::to_string(&delivery)?;
+        self.database
+            .run(move |connection| {
+                connection.execute(
+                    "INSERT INTO webhook_deliveries (task_id, data, next_attempt_at)
+                     VALUES (?1, ?2, ?3)
+                     ON CONFLICT (task_id) DO UPDATE
+                     SET data = excluded.data, next_attempt_at = excluded.next_attempt_at",
+                    params![
+                        delivery.task_id.to_string(),
+                        payload,
+                        delivery.next_attempt_at
+                    ],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn get_webhook_delivery(
+        &self,
+        id: TaskID,
+    ) -> Result<Option<WebhookDelivery>, DocStatusError> {
+        let payload: Option<String> = self
+            .database
+            .run(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT data FROM webhook_deliveries WHERE task_id = ?1",
+                        params![id.to_string()],
+                        |row| row.get(0),
+                    )
+                    .optional()
+            })
+            .await?;
+        Ok(payload
+            .map(|payload| serde_json::from_str(&payload))
+            .transpose()?)
+    }
+
+    async fn claim_due_webhook_deliveries(
+        &self,
+        now: i64,
+        claimed_until: i64,
+        limit: usize,
+    ) -> Result<Vec<WebhookDelivery>, DocStatusError> {
+        let payloads: Vec<String> = self
+            .database
+            .run(move |connection| {
+                let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let due: Vec<(String, String)> = tx
+                    .prepare(
+                        "SELECT task_id, data FROM webhook_deliveries
+                         WHERE next_attempt_at <= ?1
+                         ORDER BY next_attempt_at LIMIT ?2",
+                    )?
+                    .query_map(params![now, limit as i64], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })?
+                    .collect::<Result<_, _>>()?;
+                for (task_id, _) in &due {
+                    tx.execute(
+                        "UPDATE webhook_deliveries SET next_attempt_at = ?2 WHERE task_id = ?1",
+                        params![task_id, claimed_until],
+                    )?;
+                }
+                tx.commit()?;
+                Ok::<_, rusqlite::Error>(due.into_iter().map(|(_, payload)| payload).collect())
+            })
+            .await?;
+        Ok(payloads
+            .iter()
+            .map(|payload| serde_json::from_str(payload))
+            .collect::<Result<_, _>>()?)
+    }
+}
+
+/// Quarantine store keeping strike counts and quarantined documents in their own tables.
+#[derive(Clone)]
+pub struct SqliteQuarantineStore {
+    database: SqliteDatabase,
+}
+
+impl SqliteQuarantineStore {
+    pub fn new(database: SqliteDatabase) -> Self {
+        SqliteQuarantineStore { database }
+    }
+}
+
+#[async_trait]
+impl QuarantineStoreImplementation for SqliteQuarantineStore {
+    async fn record_strike(&self, content_hash: &str) -> Result<u32, QuarantineError> {
+        let content_hash = content_hash.to_string();
+        self.database
+            .run(move |connection| {
+                Ok(connection.query_row(
+                    "INSERT INTO content_strikes (content_hash, strikes) VALUES (?1, 1)
+                     ON CONFLICT (content_hash) DO UPDATE SET strikes = strikes + 1
+                     RETURNING strikes",
+                    params![content_hash],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+    }
+
+    async fn quarantine(&self, document: QuarantinedDocument) -> Result<(), QuarantineError> {
+        let payload = serde_json::to_string(&document)?;
+        self.database
+            .run(move |connection| {
+                connection.execute(
+                    "INSERT OR REPLACE INTO quarantine (content_hash, data, quarantined_at)
+                     VALUES (?1, ?2, ?3)",
+                    params![document.content_hash, payload, document.quarantined_at],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn get_quarantined(
+        &self,
+        content_hash: &str,
+    ) -> Result<Option<QuarantinedDocument>, QuarantineError> {
+        let content_hash = content_hash.to_string();
+        let payload: Option<String> = self
+            .database
+            .run(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT data FROM quarantine WHERE content_hash = ?1",
+                        params![content_hash],
+                        |row| row.get(0),
+                    )
+                    .optional()
+            })
+            .await?;
+        Ok(payload
+            .map(|payload| serde_json::from_str(&payload))
+            .transpose()?)
+    }
+
+    async fn quarantined(&self) -> Result<Vec<QuarantinedDocument>, QuarantineError> {
+        let payloads: Vec<String> = self
+            .database
+            .run(|connection| {
+                connection
+                    .prepare("SELECT data FROM quarantine ORDER BY quarantined_at")?
+                    .query_map([], |row| row.get(0))?
+                    .collect::<Result<_, _>>()
+            })
+            .await?;
+        Ok(payloads
+            .iter()
+            .map(|payload| serde_json::from_str(payload))
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn release(
+        &self,
+        content_hash: &str,
+    ) -> Result<Option<QuarantinedDocument>, QuarantineError> {
+        let content_hash = content_hash.to_string();
+        let payload: Option<String> = self
+            .database
+            .run(move |connection| {
+                let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let payload = tx
+                    .query_row(
+                        "DELETE FROM quarantine WHERE content_hash = ?1 RETURNING data",
+                        params![content_hash],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                tx.execute(
+                    "DELETE FROM content_strikes WHERE content_hash = ?1",
+                    params![content_hash],
+                )?;
+                tx.commit()?;
+                Ok::<_, rusqlite::Error>(payload)
+            })
+            .await?;
+        Ok(payload
+            .map(|payload| serde_json::from_str(&payload))
+            .transpose()?)
+    }
+}
+
+/// API key store keeping keys and their daily usage in their own tables.
+#[derive(Clone)]
+pub struct SqliteApiKeyStore {
+    database: SqliteDatabase,
+}
+
+impl SqliteApiKeyStore {
+    pub fn new(database: SqliteDatabase) -> Self {
+        SqliteApiKeyStore { database }
+    }
+}
+
+#[async_trait]
+impl ApiKeyStoreImplementation for SqliteApiKeyStore {
+    async fn get_key(&self, id: &str) -> Result<Option<ApiKey>, ApiKeyError> {
+        let id = id.to_string();
+        let payload: Option<String> = self
+            .database
+            .run(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT data FROM api_keys WHERE id = ?1",
+                        params![id],
+                        |row| row.get(0),
+                    )
+                    .optional()
+            })
+            .await?;
+        Ok(payload
+            .map(|payload| serde_json::from_str(&payload))
+            .transpose()?)
+    }
+
+    async fn put_key(&self, key: ApiKey) -> Result<(), ApiKeyError> {
+        let payload = serde_json::to_string(&key)?;
+        self.database
+            .run(move |connection| {
+                connection.execute(
+                    "INSERT OR REPLACE INTO api_keys (id, data, created_at) VALUES (?1, ?2, ?3)",
+                    params![key.id, payload, key.created_at],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn keys(&self) -> Result<Vec<ApiKey>, ApiKeyError> {
+        let payloads: Vec<String> = self
+            .database
+            .run(|connection| {
+                connection
+                    .prepare("SELECT data FROM api_keys ORDER BY created_at")?
+                    .query_map([], |row| row.get(0))?
+                    .collect::<Result<_, _>>()
+            })
+            .await?;
+        Ok(payloads
+            .iter()
+            .map(|payload| serde_json::from_str(payload))
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn revoke_key(&self, id: &str) -> Result<Option<ApiKey>, ApiKeyError> {
+        let id = id.to_string();
+        let payload: Option<String> = self
+            .database
+            .run(move |connection| {
+                let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let payload = tx
+                    .query_row(
+                        "DELETE FROM api_keys WHERE id = ?1 RETURNING data",
+                        params![id],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                tx.execute("DELETE FROM api_key_usage WHERE id = ?1", params![id])?;
+                tx.commit()?;
+                Ok::<_, rusqlite::Error>(payload)
+            })
+            .await?;
+        Ok(payload
+            .map(|payload| serde_json::from_str(&payload))
+            .transpose()?)
+    }
+
+    async fn record_usage(
+        &self,
+        id: &str,
+        day: i64,
+        usage: ApiKeyUsage,
+    ) -> Result<(), ApiKeyError> {
+        let id = id.to_string();
+        self.database
+            .run(move |connection| {
+                let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                // Past days are never read again.
+                tx.execute("DELETE FROM api_key_usage WHERE day < ?1", params![day])?;
+                tx.execute(
+                    "INSERT INTO api_key_usage (id, day, documents, pages) VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (id, day) DO UPDATE SET
+                        documents = documents + excluded.documents,
+                        pages = pages + excluded.pages",
+                    params![id, day, usage.documents as i64, usage.pages as i64],
+                )?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn usage(&self, id: &str, day: i64) -> Result<ApiKeyUsage, ApiKeyError> {
+        let id = id.to_string();
+        let used: Option<(i64, i64)> = self
+            .database
+            .run(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT documents, pages FROM api_key_usage WHERE id = ?1 AND day = ?2",
+                        params![id, day],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()
+            })
+            .await?;
+        Ok(used
+            .map(|(documents, pages)| ApiKeyUsage {
+                documents: documents as u64,
+                pages: pages as u64,
+            })
+            .unwrap_or_default())
+    }
+
+    async fn reserve_document(
+        &self,
+        id: &str,
+        day: i64,
+        limit: Option<u64>,
+    ) -> Result<bool, ApiKeyError> {
+        let id = id.to_string();
+        let limit = limit.map(|limit| limit as i64);
+        self.database
+            .run(move |connection| {
+                let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                tx.execute("DELETE FROM api_key_usage WHERE day < ?1", params![day])?;
+                // Neither the insert nor the update happen once the limit is reached.
+                let counted = tx.execute(
+                    "INSERT INTO api_key_usage (id, day, documents, pages)
+                     SELECT ?1, ?2, 1, 0 WHERE ?3 IS NULL OR ?3 > 0
+                     ON CONFLICT (id, day) DO UPDATE SET documents = documents + 1
+                     WHERE ?3 IS NULL OR documents < ?3",
+                    params![id, day, limit],
+                )?;
+                tx.commit()?;
+                Ok(counted == 1)
+            })
+            .await
+    }
+
+    async fn refund_document(&self, id: &str, day: i64) -> Result<(), ApiKeyError> {
+        let id = id.to_string();
+        self.database
+            .run(move |connection| {
+                connection.execute(
+                    "UPDATE api_key_usage SET documents = documents - 1
+                     WHERE id = ?1 AND day = ?2 AND documents > 0",
+                    params![id, day],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+/// How long published events stay in the table for other processes to pick up.
+const TASK_EVENT_RETENTION: Duration = Duration::from_secs(60);
+
+/// Most events read from the table at once.
+const TASK_EVENT_READ_BATCH: usize = 500;
+
+/// Event bus going through the `task_events` table, so processes sharing the database see
+/// each other's events. Every process tails the table and hands new rows to its subscribers.
+#[derive(Clone)]
+pub struct SqliteTaskEventBus {
+    database: SqliteDatabase,
+    sender: broadcast::Sender<TaskEvent>,
+    /// Woken on publish, so events from this process don't wait for the next poll.
+    published: Arc<Notify>,
+}
+
+impl SqliteTaskEventBus {
+    pub fn new(database: SqliteDatabase) -> Self {
+        let bus = SqliteTaskEventBus {
+            database,
+            sender: broadcast::channel(TASK_EVENT_BUFFER).0,
+            published: Arc::new(Notify::new()),
+        };
+        tokio::spawn(bus.clone().tail());
+        bus
+    }
+
+    /// Hand every row published after startup to this process's subscribers, pruning rows
+    /// past their retention as it goes.
+    async fn tail(self) {
+        let mut cursor: Option<i64> = None;
+        let mut pruned_at = Instant::now();
+        loop {
+            match self.read_after(cursor).await {
+                Ok((last_seq, events)) => {
+                    for event in events {
+                        let _ = self.sender.send(event);
+                    }
+                    cursor = Some(last_seq);
+                }
+                Err(err) => warn!(%err, "Could not read task events"),
+            }
+            if pruned_at.elapsed() >= TASK_EVENT_RETENTION {
+                pruned_at = Instant::now();
+                if let Err(err) = self.prune().await {
+                    warn!(%err, "Could not prune task events");
+                }
+            }
+            let _ = timeout(CROSS_PROCESS_POLL, self.published.notified()).await;
+        }
+    }
+
+    /// Events after `cursor` and the sequence number to continue from, on the first call
+    /// only the position of the latest row.
+    async fn read_after(
+        &self,
+        cursor: Option<i64>,
+    ) -> Result<(i64, Vec<TaskEvent>), TaskEventError> {
+        self.database
+            .run(move |connection| {
+                let Some(cursor) = cursor else {
+                    let latest: Option<i64> =
+                        connection
+                            .query_row("SELECT MAX(seq) FROM task_events", [], |row| row.get(0))?;
+                    return Ok((latest.unwrap_or(0), Vec::new()));
+                };
+                let mut statement = connection.prepare_cached(
+                    "SELECT seq, event FROM task_events WHERE seq > ?1 ORDER BY seq LIMIT ?2",
+                )?;
+                let rows = statement
+                    .query_map(params![cursor, TASK_EVENT_READ_BATCH as i64], |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                let last_seq = rows.last().map_or(cursor, |(seq, _)| *seq);
+                let events = rows
+                    .iter()
+                    .map(|(_, event)| serde_json::from_str(event))
+                    .collect::<Result<Vec<TaskEvent>, _>>()?;
+                Ok((last_seq, events))
+            })
+            .await
+    }
+
+    async fn prune(&self) -> Result<(), TaskEventError> {
+        let cutoff = unix_millis(SystemTime::now() - TASK_EVENT_RETENTION);
+        self.database
+            .run(move |connection| {
+                connection.execute(
+                    "DELETE FROM task_events WHERE published_at < ?1",
+                    params![cutoff],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl TaskEventBusImplementation for SqliteTaskEventBus {
+    async fn publish(&self, event: TaskEvent) -> Result<(), TaskEventError> {
+        let payload = serde_json::to_string(&event)?;
+        let published_at = unix_millis(SystemTime::now());
+        self.database
+            .run(move |connection| {
+                connection.execute(
+                    "INSERT INTO task_events (event, published_at) VALUES (?1, ?2)",
+                    params![payload, published_at],
+                )?;
+                Ok::<_, TaskEventError>(())
+            })
+            .await?;
+        self.published.notify_one();
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<TaskEvent> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::queued_task;
+    use super::*;
+    use crate::types::ConversionOptions;
+
+    const LEASE: Duration = Duration::from_secs(60);
+
+    async fn dequeued_id(queue: &SqliteTaskQueue, lease_for: Duration) -> Option<TaskID> {
+        let lease = queue.dequeue(lease_for).await.unwrap();
+        lease.map(|lease| lease.task.id)
+    }
+
+    fn status(id: TaskID, stage: ProcessingStage) -> DocStatus {
+        let task = queued_task(id);
+        let mut status = DocStatus::new_from_id_loc(
+            id,
+            task.location,
+            task.conversion_method,
+            ConversionOptions::default(),
+        );
+        status.status = stage;
+        status
+    }
+
+    #[tokio::test]
+    async fn expired_leases_are_handed_out_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = SqliteDatabase::open(&dir.path().join("crimson.db"))
+            .await
+            .unwrap();
+        let queue = SqliteTaskQueue::new(database, MarkdownConversionMethod::Simple);
+        queue.enqueue(queued_task(1)).await.unwrap();
+        queue.enqueue(queued_task(2)).await.unwrap();
+
+        let mut expired = queue.dequeue(Duration::ZERO).await.unwrap().unwrap();
+        assert_eq!(expired.task.id, 1);
+        let lease = queue.dequeue(LEASE).await.unwrap().unwrap();
+        assert_eq!(lease.task.id, 1);
+        assert!(matches!(
+            queue.extend_lease(&mut expired, LEASE).await,
+            Err(QueueError::LeaseLost)
+        ));
+        assert!(matches!(
+            queue.ack(&expired).await,
+            Err(QueueError::LeaseLost)
+        ));
+        queue.ack(&lease).await.unwrap();
+        assert_eq!(dequeued_id(&queue, LEASE).await, Some(2));
+        assert_eq!(dequeued_id(&queue, LEASE).await, None);
+    }
+
+    #[tokio::test]
+    async fn tasks_are_queued_at_most_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("crimson.db");
+        let serve = SqliteDatabase::open(&path).await.unwrap();
+        let worker = SqliteDatabase::open(&path).await.unwrap();
+        let queue = SqliteTaskQueue::new(serve, MarkdownConversionMethod::Simple);
+        queue.enqueue(queued_task(1)).await.unwrap();
+        queue.enqueue(queued_task(1)).await.unwrap();
+        let worker_queue = SqliteTaskQueue::new(worker, MarkdownConversionMethod::Simple);
+        worker_queue.enqueue(queued_task(1)).await.unwrap();
+
+        assert_eq!(queue.depth().await.unwrap(), 1);
+        assert_eq!(dequeued_id(&queue, LEASE).await, Some(1));
+        assert_eq!(dequeued_id(&worker_queue, LEASE).await, None);
+    }
+
+    #[tokio::test]
+    async fn each_method_only_sees_its_own_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = SqliteDatabase::open(&dir.path().join("crimson.db"))
+            .await
+            .unwrap();
+        let simple = SqliteTaskQueue::new(database.clone(), MarkdownConversionMethod::Simple);
+        let marker = SqliteTaskQueue::new(database, MarkdownConversionMethod::Marker);
+        marker
+            .enqueue(TaskMessage {
+                conversion_method: MarkdownConversionMethod::Marker,
+                ..queued_task(1)
+            })
+            .await
+            .unwrap();
+        assert_eq!(simple.depth().await.unwrap(), 0);
+        assert_eq!(dequeued_id(&simple, LEASE).await, None);
+        assert_eq!(dequeued_id(&marker, LEASE).await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn dead_letters_can_be_requeued_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = SqliteDatabase::open(&dir.path().join("crimson.db"))
+            .await
+            .unwrap();
+        let queue = SqliteTaskQueue::new(database, MarkdownConversionMethod::Simple);
+        queue.enqueue(queued_task(7)).await.unwrap();
+        let lease = queue.dequeue(LEASE).await.unwrap().unwrap();
+        let dead_letter = DeadLetter {
+            task: lease.task.clone(),
+            error: "boom".to_string(),
+            attempts: 3,
+            dead_lettered_at: 0,
+        };
+        queue.dead_letter(&lease, dead_letter).await.unwrap();
+        assert_eq!(queue.dead_letters().await.unwrap().len(), 1);
+        assert_eq!(dequeued_id(&queue, LEASE).await, None);
+
+        let requeued = queue.requeue_dead_letter(7).await.unwrap();
+        assert_eq!(requeued.map(|dead| dead.error), Some("boom".to_string()));
+        assert!(queue.requeue_dead_letter(7).await.unwrap().is_none());
+        assert!(queue.dead_letters().await.unwrap().is_empty());
+        assert_eq!(dequeued_id(&queue, LEASE).await, Some(7));
+    }
+
+    #[tokio::test]
+    async fn finishing_leaves_cancelled_tasks_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = SqliteDatabase::open(&dir.path().join("crimson.db"))
+            .await
+            .unwrap();
+        let statuses = SqliteStatusStore::new(database);
+        statuses
+            .set_doc_status(status(1, ProcessingStage::Processing))
+            .await
+            .unwrap();
+        statuses
+            .set_doc_status(status(2, ProcessingStage::Processing))
+            .await
+            .unwrap();
+        statuses.cancel_doc_status(2).await.unwrap();
+
+        let completed = |id| statuses.finish_doc_status(status(id, ProcessingStage::Completed));
+        assert!(completed(1).await.unwrap());
+        assert!(!completed(2).await.unwrap());
+        assert_eq!(
+            statuses.get_doc_status(1).await.unwrap().status,
+            ProcessingStage::Completed
+        );
+        assert_eq!(
+            statuses.get_doc_status(2).await.unwrap().status,
+            ProcessingStage::Cancelled
+        );
+    }
+
+    #[tokio::test]
+    async fn restarts_keep_queued_tasks_and_requeue_interrupted_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("crimson.db");
+        {
+            let database = SqliteDatabase::open(&path).await.unwrap();
+            let queue = SqliteTaskQueue::new(database.clone(), MarkdownConversionMethod::Simple);
+            let statuses = SqliteStatusStore::new(database);
+            queue.enqueue(queued_task(1)).await.unwrap();
+            statuses
+                .set_doc_status(status(1, ProcessingStage::Waiting))
+                .await
+                .unwrap();
+            // 2 was being converted when the process died, 3 had only just been dequeued.
+            statuses
+                .set_doc_status(status(2, ProcessingStage::Processing))
+                .await
+                .unwrap();
+            statuses
+                .set_doc_status(status(3, ProcessingStage::Waiting))
+                .await
+                .unwrap();
+            statuses
+                .set_doc_status(status(4, ProcessingStage::Completed))
+                .await
+                .unwrap();
+        }
+
+        let database = SqliteDatabase::open(&path).await.unwrap();
+        assert_eq!(database.recover_interrupted_tasks().await.unwrap(), 2);
+        assert_eq!(database.recover_interrupted_tasks().await.unwrap(), 0);
+        let statuses = SqliteStatusStore::new(database.clone());
+        assert_eq!(
+            statuses.get_doc_status(2).await.unwrap().status,
+            ProcessingStage::Waiting
+        );
+        let queue = SqliteTaskQueue::new(database, MarkdownConversionMethod::Simple);
+        let mut ids = Vec::new();
+        while let Some(id) = dequeued_id(&queue, LEASE).await {
+            ids.push(id);
+        }
+        ids.sort();
+        assert_eq!(ids, [1, 2, 3]);
+    }
+}
@@ -1,20 +1,18 @@
 #![allow(dead_code)]
-use aide::{
-    axum::{ApiRouter, IntoApiResponse, routing::get},
-    openapi::OpenApi,
-};
-use axum::{Extension, Json};
-use axum_tracing_opentelemetry::middleware::{OtelAxumLayer, OtelInResponseLayer};
-use clap::Parser;
+use aide::axum::{ApiRouter, routing::get};
+use anyhow::bail;
+use api::auth::{require_admin_key, require_api_key};
+use axum::middleware::{from_fn, from_fn_with_state};
+use clap::{Parser, Subcommand};
 use common::{
     api_documentation::generate_api_docs_and_serve,
+    metrics::serve_metrics,
     otel_tracing::initialize_tracing_and_wrap_router,
+    shutdown::{SHUTDOWN, listen_for_shutdown_signals},
 };
+use logic::{StoreBackend, Stores};
 
-use std::{
-    convert::Infallible,
-    net::{Ipv4Addr, SocketAddr},
-};
+use std::net::{Ipv4Addr, SocketAddr};
 
 use tracing::{Instrument, info};
 
@@ -28,42 +26,130 @@ mod types;
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    mode: Option<Mode>,
     /// Port to listen on
-    #[arg(short, long, default_value_t = 14423)]
+    #[arg(short, long, global = true, default_value_t = 14423)]
     port: u16,
+    /// Storage backend for files, the task queue and task statuses
+    #[arg(long, global = true, env = "CRIMSON_BACKEND", value_enum, default_value_t = StoreBackend::Local)]
+    backend: StoreBackend,
+}
+
+#[derive(Subcommand, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Mode {
+    /// Serve the API only, leaving conversion to separate workers
+    Serve,
+    /// Convert tasks from the queue, listening only for health checks and metrics
+    Worker,
+    /// Serve the API and convert tasks in the same process
+    #[default]
+    All,
+    /// Run a single conversion read from stdin, used for the sandboxed conversion processes
+    #[command(hide = true)]
+    Convert,
 }
 
 mod common;
-#[tokio::main]
-async fn main() -> anyhow::Result<Infallible> {
+fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    // Conversion children set up their own single threaded runtime and skip everything else.
+    #[cfg(unix)]
+    if args.mode == Some(Mode::Convert) {
+        return processing::sandbox::run_sandboxed_conversion();
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(args))
+}
+
+async fn run(args: Args) -> anyhow::Result<()> {
+    let mode = args.mode.unwrap_or_default();
+    if mode == Mode::Convert {
+        bail!("`convert` is only used internally for sandboxed conversions");
+    }
+    if mode != Mode::All && args.backend == StoreBackend::Local {
+        bail!(
+            "`serve` and `worker` need a shared backend like sqlite or redis-s3, the local backend keeps the queue in this process's memory"
+        );
+    }
+    let stores = Stores::from_backend(args.backend).await?;
 
     // initialise our subscriber
-    let app_maker = || {
-        ApiRouter::new()
-            .api_route("/v1/health", get(health))
-            .nest("/v1/", api::router())
-            .nest("/admin/", admin::router())
+    let app_stores = stores.clone();
+    let app_maker = || match mode {
+        Mode::Worker => ApiRouter::new()
+            .api_route("/v1/health", get(worker_health))
+            .route("/metrics", axum::routing::get(serve_metrics))
+            .nest(
+                "/admin/",
+                admin::worker_pool_router()
+                    .route_layer(from_fn_with_state(app_stores.clone(), require_admin_key)),
+            ),
+        Mode::Serve | Mode::All | Mode::Convert => {
+            // Worker pools only exist in processes that run the worker.
+            let admin_router = if mode == Mode::All {
+                admin::router().merge(admin::worker_pool_router())
+            } else {
+                admin::router()
+            };
+            ApiRouter::new()
+                .api_route("/v1/health", get(health))
+                .route("/metrics", axum::routing::get(serve_metrics))
+                .nest(
+                    "/v1/",
+                    api::router()
+                        .route_layer(from_fn_with_state(app_stores.clone(), require_api_key))
+                        .merge(api::debug_router().route_layer(from_fn_with_state(
+                            app_stores.clone(),
+                            require_admin_key,
+                        ))),
+                )
+                .nest(
+                    "/api/v1/",
+                    api::marker::router()
+                        .route_layer(from_fn_with_state(app_stores.clone(), require_api_key))
+                        .route_layer(from_fn(api::marker::marker_error_shape)),
+                )
+                .nest(
+                    "/admin/",
+                    admin_router
+                        .route_layer(from_fn_with_state(app_stores.clone(), require_admin_key)),
+                )
+                .with_state(app_stores)
+        }
     };
     // Add HTTP tracing layer
     // include trace context as header into the response
 
-    let app = initialize_tracing_and_wrap_router(app_maker)?;
-    // Spawn background worker to process PDF tasks
-    // This worker runs indefinitely
-    info!("App Created, spawning background process:");
-    tokio::spawn(
-        async move {
-            processing::worker::start_worker().await;
+    // Shut down once serving is done, flushing the last spans and metrics.
+    let (app, telemetry) = initialize_tracing_and_wrap_router(app_maker)?;
+    api::auth::load_configured_keys()?;
+    api::rate_limit::spawn_queue_depth_sampler(stores.clone());
+    api::webhooks::spawn_webhook_dispatcher(stores.clone());
+    listen_for_shutdown_signals();
+    info!(backend = ?args.backend, ?mode, "App Created");
+    let worker = (mode != Mode::Serve).then(|| {
+        // Spawn background worker to process PDF tasks
+        // This worker runs until shutdown, then drains what it has in flight
+        tokio::spawn(processing::worker::start_worker(stores, SHUTDOWN.clone()).in_current_span())
+    });
+    // Keep answering status polls while the worker drains, ingests are already refused.
+    let server_shutdown = async move {
+        SHUTDOWN.cancelled().await;
+        if let Some(worker) = worker {
+            let _ = worker.await;
         }
-        .in_current_span(),
-    );
+    };
 
     // bind and serve
     let addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), args.port);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    let Err(serve_err) = generate_api_docs_and_serve(listener, app, "A PDF processing API").await;
-    Err(serve_err.into())
+    generate_api_docs_and_serve(listener, app, "A PDF processing API", server_shutdown).await?;
+    info!("Shut down cleanly");
+    telemetry.shutdown();
+    Ok(())
 }
 
 /// Get health of the API.
@@ -71,14 +157,38 @@ async fn health() -> &'static str {
     "Service is Healthy"
 }
 
+/// Get health of a worker only process.
+async fn worker_health() -> &'static str {
+    "Worker is Healthy"
+}
+
 mod admin {
-    use aide::axum::{ApiRouter, IntoApiResponse, routing::get};
+    use aide::axum::{
+        ApiRouter, IntoApiResponse,
+        routing::{delete, get, post, put},
+    };
     use axum::Json;
+    use axum::extract::{Path as UrlPath, State};
     use axum_tracing_opentelemetry::tracing_opentelemetry_instrumentation_sdk;
     use schemars::JsonSchema;
     use serde::{Deserialize, Serialize};
     use tracing::{debug, error, info, warn};
 
+    use crate::api::auth::{
+        configured_keys, generate_api_key_secret, generate_webhook_secret, is_configured_key,
+    };
+    use crate::api::error::ApiError;
+    use crate::logic::{
+        Stores, api_key_usage_today, find_api_key, hash_api_key, list_api_keys, list_dead_letters,
+        list_owner_depths, list_quarantined, release_quarantined, requeue_dead_letter,
+        revoke_api_key, store_api_key,
+    };
+    use crate::processing::worker::{MAX_POOL_SIZE, WORKER_POOLS, worker_pool};
+    use crate::types::{
+        ApiKey, ApiKeyInfo, ApiKeyRole, DeadLetter, DocStatusResponse, MarkdownConversionMethod,
+        OwnerQueueDepth, QuarantinedDocument, TaskID, WorkerPoolInfo, unix_millis,
+    };
+
     #[derive(Serialize, Deserialize, JsonSchema)]
     struct ServerInfo {
         name: String,
@@ -86,8 +196,258 @@ mod admin {
     }
 
     /// Expose admin routes
-    pub fn router() -> ApiRouter {
-        ApiRouter::new().api_route("/info", get(get_server_info))
+    pub fn router() -> ApiRouter<Stores> {
+        ApiRouter::new()
+            .api_route("/info", get(get_server_info))
+            .api_route("/api_keys", get(get_api_keys).post(post_api_key))
+            .api_route("/api_keys/{key_id}", delete(delete_api_key))
+            .api_route(
+                "/api_keys/{key_id}/webhook_secret",
+                post(post_rotate_webhook_secret),
+            )
+            .api_route("/dead_letters", get(get_dead_letters))
+            .api_route("/dead_letters/{task_id}", get(get_dead_letter))
+            .api_route(
+                "/dead_letters/{task_id}/requeue",
+                post(post_requeue_dead_letter),
+            )
+            .api_route("/owners", get(get_owner_depths))
+            .api_route("/quarantine", get(get_quarantined))
+            .api_route(
+                "/quarantine/{content_hash}/release",
+                post(post_release_quarantined),
+            )
+    }
+
+    /// Expose the worker pool routes, which need no stores so worker only processes serve them too
+    pub fn worker_pool_router<S: Clone + Send + Sync + 'static>() -> ApiRouter<S> {
+        ApiRouter::new()
+            .api_route("/worker_pools", get(get_worker_pools))
+            .api_route("/worker_pools/{method}", put(put_worker_pool_size))
+    }
+
+    #[derive(Deserialize, JsonSchema)]
+    struct TaskIdParams {
+        task_id: TaskID,
+    }
+
+    /// List tasks that ran out of attempts, oldest first.
+    async fn get_dead_letters(
+        State(stores): State<Stores>,
+    ) -> Result<Json<Vec<DeadLetter>>, ApiError> {
+        Ok(Json(list_dead_letters(&stores).await?))
+    }
+
+    /// Inspect a single dead lettered task, including the error from its final attempt.
+    async fn get_dead_letter(
+        State(stores): State<Stores>,
+        UrlPath(TaskIdParams { task_id }): UrlPath<TaskIdParams>,
+    ) -> Result<Json<DeadLetter>, ApiError> {
+        list_dead_letters(&stores)
+            .await?
+            .into_iter()
+            .find(|dead| dead.task.id == task_id)
+            .map(Json)
+            .ok_or_else(|| ApiError::NotFound(format!("Task {task_id} is not dead lettered")))
+    }
+
+    /// Put a dead lettered task back on the queue with a fresh set of attempts.
+    async fn post_requeue_dead_letter(
+        State(stores): State<Stores>,
+        UrlPath(TaskIdParams { task_id }): UrlPath<TaskIdParams>,
+    ) -> Result<Json<DocStatusResponse>, ApiError> {
+        match requeue_dead_letter(&stores, task_id).await? {
+            Some(status) => {
+                info!(task_id, "Requeued dead lettered task");
+                Ok(Json(status.into()))
+            }
+            None => Err(ApiError::NotFound(format!(
+                "Task {task_id} is not dead lettered"
+            ))),
+        }
+    }
+
+    /// Queued and processing tasks of every owner, per conversion method.
+    async fn get_owner_depths(
+        State(stores): State<Stores>,
+    ) -> Result<Json<Vec<OwnerQueueDepth>>, ApiError> {
+        Ok(Json(list_owner_depths(&stores).await?))
+    }
+
+    #[derive(Deserialize, JsonSchema)]
+    struct NewApiKey {
+        /// Tasks submitted with the key belong to this owner.
+        owner: String,
+        #[serde(default)]
+        role: ApiKeyRole,
+        /// Documents the key may submit per UTC day, unlimited when left out.
+        daily_documents: Option<u64>,
+        /// Pages the key may have converted per UTC day, unlimited when left out.
+        daily_pages: Option<u64>,
+    }
+
+    #[derive(Serialize, JsonSchema)]
+    struct CreatedApiKey {
+        /// The secret to send as `X-Api-Key`, only shown this once.
+        secret: String,
+        /// Signs the completion webhooks of the key's tasks, also shown on `/v1/usage`.
+        webhook_secret: String,
+        key: ApiKey,
+    }
+
+    #[derive(Serialize, JsonSchema)]
+    struct RotatedWebhookSecret {
+        key_id: String,
+        webhook_secret: String,
+    }
+
+    #[derive(Deserialize, JsonSchema)]
+    struct ApiKeyIdParams {
+        key_id: String,
+    }
+
+    /// List the configured and stored API keys with what each used so far today.
+    async fn get_api_keys(State(stores): State<Stores>) -> Result<Json<Vec<ApiKeyInfo>>, ApiError> {
+        let stored = list_api_keys(&stores).await?;
+        let mut keys = Vec::new();
+        for key in configured_keys().into_iter().chain(stored) {
+            keys.push(ApiKeyInfo {
+                configured: is_configured_key(&key.id),
+                usage_today: api_key_usage_today(&stores, &key.id).await?,
+                key: key.redacted(),
+            });
+        }
+        Ok(Json(keys))
+    }
+
+    /// Create an API key. The response holds the secret, which can't be retrieved again.
+    async fn post_api_key(
+        State(stores): State<Stores>,
+        Json(new_key): Json<NewApiKey>,
+    ) -> Result<Json<CreatedApiKey>, ApiError> {
+        if new_key.owner.is_empty() {
+            return Err(ApiError::BadRequest("API keys need an owner".to_string()));
+        }
+        let secret = generate_api_key_secret();
+        let webhook_secret = generate_webhook_secret();
+        let key = ApiKey {
+            id: hash_api_key(&secret),
+            owner: new_key.owner,
+            role: new_key.role,
+            daily_documents: new_key.daily_documents,
+            daily_pages: new_key.daily_pages,
+            created_at: unix_millis(std::time::SystemTime::now()),
+            webhook_secret: Some(webhook_secret.clone()),
+        };
+        store_api_key(&stores, key.clone()).await?;
+        info!(key_id = key.id, owner = key.owner, role = ?key.role, "Created API key");
+        Ok(Json(CreatedApiKey {
+            secret,
+            webhook_secret,
+            key: key.redacted(),
+        }))
+    }
+
+    /// Give a stored key a new webhook secret, webhooks sent from now on are signed with it.
+    async fn post_rotate_webhook_secret(
+        State(stores): State<Stores>,
+        UrlPath(ApiKeyIdParams { key_id }): UrlPath<ApiKeyIdParams>,
+    ) -> Result<Json<RotatedWebhookSecret>, ApiError> {
+        if is_configured_key(&key_id) {
+            return Err(ApiError::Conflict(format!(
+                "API key {key_id} comes from the config, set its webhook_secret there instead"
+            )));
+        }
+        let Some(mut key) = find_api_key(&stores, &key_id).await? else {
+            return Err(ApiError::NotFound(format!("No API key with id {key_id}")));
+        };
+        let webhook_secret = generate_webhook_secret();
+        key.webhook_secret = Some(webhook_secret.clone());
+        store_api_key(&stores, key).await?;
+        info!(key_id, "Rotated webhook secret");
+        Ok(Json(RotatedWebhookSecret {
+            key_id,
+            webhook_secret,
+        }))
+    }
+
+    /// Revoke a key created through the admin routes, it is refused from then on.
+    async fn delete_api_key(
+        State(stores): State<Stores>,
+        UrlPath(ApiKeyIdParams { key_id }): UrlPath<ApiKeyIdParams>,
+    ) -> Result<Json<ApiKey>, ApiError> {
+        if is_configured_key(&key_id) {
+            return Err(ApiError::Conflict(format!(
+                "API key {key_id} comes from the config, remove it there instead"
+            )));
+        }
+        match revoke_api_key(&stores, &key_id).await? {
+            Some(key) => {
+                info!(key_id, owner = key.owner, "Revoked API key");
+                Ok(Json(key.redacted()))
+            }
+            None => Err(ApiError::NotFound(format!("No API key with id {key_id}"))),
+        }
+    }
+
+    #[derive(Deserialize, JsonSchema)]
+    struct ContentHashParams {
+        content_hash: String,
+    }
+
+    /// List documents quarantined for repeatedly crashing or timing out converters, oldest first.
+    async fn get_quarantined(
+        State(stores): State<Stores>,
+    ) -> Result<Json<Vec<QuarantinedDocument>>, ApiError> {
+        Ok(Json(list_quarantined(&stores).await?))
+    }
+
+    /// Accept a quarantined document again, with its strike count reset.
+    async fn post_release_quarantined(
+        State(stores): State<Stores>,
+        UrlPath(ContentHashParams { content_hash }): UrlPath<ContentHashParams>,
+    ) -> Result<Json<QuarantinedDocument>, ApiError> {
+        match release_quarantined(&stores, &content_hash).await? {
+            Some(document) => {
+                info!(content_hash, "Released quarantined document");
+                Ok(Json(document))
+            }
+            None => Err(ApiError::NotFound(format!(
+                "Document {content_hash} is not quarantined"
+            ))),
+        }
+    }
+
+    #[derive(Deserialize, JsonSchema)]
+    struct MethodParams {
+        method: MarkdownConversionMethod,
+    }
+
+    #[derive(Deserialize, JsonSchema)]
+    struct WorkerPoolResize {
+        /// Conversions allowed to run at once, 0 pauses the method.
+        size: u32,
+    }
+
+    /// List this process's worker pools with their sizes and running conversions.
+    async fn get_worker_pools() -> Json<Vec<WorkerPoolInfo>> {
+        Json(WORKER_POOLS.iter().map(|pool| pool.info()).collect())
+    }
+
+    /// Change how many conversions of a method this process runs at once. Running conversions
+    /// past a smaller size are allowed to finish.
+    async fn put_worker_pool_size(
+        UrlPath(MethodParams { method }): UrlPath<MethodParams>,
+        Json(WorkerPoolResize { size }): Json<WorkerPoolResize>,
+    ) -> Result<Json<WorkerPoolInfo>, ApiError> {
+        if size > MAX_POOL_SIZE {
+            return Err(ApiError::BadRequest(format!(
+                "Worker pool size can be at most {MAX_POOL_SIZE}"
+            )));
+        }
+        let pool = worker_pool(method);
+        pool.resize(size);
+        Ok(Json(pool.info()))
     }
 
     /// Get static server info
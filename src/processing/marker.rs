@@ -0,0 +1,350 @@
+use std::{collections::HashMap, env, path::Path, sync::LazyLock, time::Duration};
+
+use anyhow::{anyhow, bail};
+use reqwest::multipart::{Form, Part};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::types::{ConversionOptions, ConversionOutput, RetryableError};
+
+pub static MARKER_BASE_URL: LazyLock<String> = LazyLock::new(|| {
+    env::var("MARKER_BASE_URL").unwrap_or_else(|_| "https://www.datalab.to".to_string())
+});
+
+pub static MARKER_API_KEY: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("MARKER_API_KEY").ok());
+
+pub static MARKER_POLL_INTERVAL_SECS: LazyLock<u64> = LazyLock::new(|| {
+    env::var("MARKER_POLL_INTERVAL_SECS")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(2)
+});
+
+pub static MARKER_MAX_POLLS: LazyLock<u32> = LazyLock::new(|| {
+    env::var("MARKER_MAX_POLLS")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(300)
+});
+
+#[derive(Debug, Clone)]
+pub struct MarkerConfig {
+    pub base_url: String,
+    pub api_key: Option<String>,
+    pub poll_interval: Duration,
+    pub max_polls: u32,
+}
+
+impl Default for MarkerConfig {
+    fn default() -> Self {
+        MarkerConfig {
+            base_url: (*MARKER_BASE_URL).clone(),
+            api_key: (*MARKER_API_KEY).clone(),
+            poll_interval: Duration::from_secs(*MARKER_POLL_INTERVAL_SECS),
+            max_polls: *MARKER_MAX_POLLS,
+        }
+    }
+}
+
+static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(120))
+        .build()
+        .expect("Should be able to build an http client")
+});
+
+#[derive(Deserialize, Debug)]
+struct MarkerSubmitResponse {
+    success: bool,
+    error: Option<String>,
+    request_id: Option<serde_json::Value>,
+    request_check_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MarkerCheckResponse {
+    status: String,
+    success: Option<bool>,
+    markdown: Option<String>,
+    images: Option<HashMap<String, String>>,
+    metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    error: Option<String>,
+    page_count: Option<u32>,
+}
+
+/// Submit the pdf to a datalab style marker API and poll `request_check_url` until it's done.
+pub async fn process_marker_pdf(
+    path: &Path,
+    options: &ConversionOptions,
+) -> anyhow::Result<ConversionOutput> {
+    convert_with_marker(&MarkerConfig::default(), path, options).await
+}
+
+async fn convert_with_marker(
+    config: &MarkerConfig,
+    path: &Path,
+    options: &ConversionOptions,
+) -> anyhow::Result<ConversionOutput> {
+    let base_url = config.base_url.trim_end_matches('/');
+
+    let file_bytes = tokio::fs::read(path)
+        .await
+        .map_err(|err| anyhow!("File access error: {err} (path: {path:?})"))?;
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("document.pdf")
+        .to_string();
+    let mut form = Form::new()
+        .part(
+            "file",
+            Part::bytes(file_bytes)
+                .file_name(file_name)
+                .mime_str("application/pdf")?,
+        )
+        .text("output_format", "markdown")
+        .text("force_ocr", options.force_ocr.to_string())
+        .text("paginate", options.paginate.to_string())
+        .text("use_llm", options.use_llm.to_string())
+        .text("strip_existing_ocr", options.strip_existing_ocr.to_string())
+        .text(
+            "disable_image_extraction",
+            options.disable_image_extraction.to_string(),
+        );
+    if !options.langs.is_empty() {
+        form = form.text("langs", options.langs.join(","));
+    }
+    if let Some(max_pages) = options.max_pages {
+        form = form.text("max_pages", max_pages.to_string());
+    }
+
+    let submit_url = format!("{base_url}/api/v1/marker");
+    let submitted: MarkerSubmitResponse =
+        with_api_key(HTTP_CLIENT.post(&submit_url), &config.api_key)
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+    if !submitted.success {
+        bail!(
+            "Marker rejected the document: {}",
+            submitted.error.unwrap_or_default()
+        );
+    }
+    let check_url = resolve_check_url(base_url, &submitted)?;
+    info!(%check_url, "Submitted pdf to marker, polling for result");
+
+    for _ in 0..config.max_polls {
+        tokio::time::sleep(config.poll_interval).await;
+        let checked: MarkerCheckResponse =
+            with_api_key(HTTP_CLIENT.get(&check_url), &config.api_key)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+        if matches!(checked.status.as_str(), "processing" | "queued") {
+            continue;
+        }
+        if checked.success != Some(true) {
+            bail!(
+                "Marker failed to convert the document: {}",
+                checked.error.unwrap_or(checked.status)
+            );
+        }
+        return Ok(ConversionOutput {
+            markdown: checked.markdown.unwrap_or_default(),
+            images: checked.images,
+            metadata: checked.metadata.map(flatten_metadata),
+            page_count: checked.page_count,
+        });
+    }
+    Err(RetryableError(format!(
+        "Marker did not finish after {} polls of {check_url}",
+        config.max_polls
+    ))
+    .into())
+}
+
+fn with_api_key(
+    request: reqwest::RequestBuilder,
+    api_key: &Option<String>,
+) -> reqwest::RequestBuilder {
+    match api_key {
+        Some(api_key) => request.header("X-Api-Key", api_key),
+        None => request,
+    }
+}
+
+/// `request_check_url` is meant to be absolute, but fall back to the documented route if a
+/// server hands back something relative.
+fn resolve_check_url(base_url: &str, submitted: &MarkerSubmitResponse) -> anyhow::Result<String> {
+    if let Some(check_url) = &submitted.request_check_url
+        && (check_url.starts_with("http://") || check_url.starts_with("https://"))
+    {
+        return Ok(check_url.clone());
+    }
+    let request_id = match &submitted.request_id {
+        Some(serde_json::Value::String(id)) => id.clone(),
+        Some(serde_json::Value::Number(id)) => id.to_string(),
+        _ => bail!("Marker response had neither a request_check_url nor a request_id"),
+    };
+    Ok(format!("{base_url}/api/v1/marker/{request_id}"))
+}
+
+/// `DocStatus.metadata` is a flat string map, so nested marker metadata gets stored as json.
+fn flatten_metadata(
+    metadata: serde_json::Map<String, serde_json::Value>,
+) -> HashMap<String, String> {
+    metadata
+        .into_iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(text) => (key, text),
+            other => (key, other.to_string()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::extract::{Multipart, Path as UrlPath, State};
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::processing::is_retryable;
+
+    #[derive(Clone, Default)]
+    struct MockMarker {
+        address: String,
+        /// Form fields of every submission.
+        submitted: Arc<Mutex<Vec<(String, String)>>>,
+        polls: Arc<Mutex<u32>>,
+    }
+
+    /// Documents named `stuck.pdf` never finish, the others finish on the third poll.
+    async fn submit(State(mock): State<MockMarker>, mut form: Multipart) -> Json<Value> {
+        let mut request_id = "done".to_string();
+        while let Some(field) = form.next_field().await.unwrap() {
+            let name = field.name().unwrap().to_string();
+            if name == "file" {
+                if field.file_name() == Some("stuck.pdf") {
+                    request_id = "stuck".to_string();
+                }
+                continue;
+            }
+            let value = field.text().await.unwrap();
+            mock.submitted.lock().unwrap().push((name, value));
+        }
+        Json(json!({
+            "success": true,
+            "request_id": request_id,
+            "request_check_url": format!("http://{}/api/v1/marker/{request_id}", mock.address),
+        }))
+    }
+
+    async fn check(State(mock): State<MockMarker>, UrlPath(id): UrlPath<String>) -> Json<Value> {
+        let polls = {
+            let mut polls = mock.polls.lock().unwrap();
+            *polls += 1;
+            *polls
+        };
+        if id == "stuck" || polls < 3 {
+            return Json(json!({"status": "processing"}));
+        }
+        Json(json!({
+            "status": "complete",
+            "success": true,
+            "markdown": "# Converted",
+            "page_count": 2,
+            "metadata": {"title": "Report", "toc": [{"page": 1}]},
+        }))
+    }
+
+    async fn mock_marker() -> (MarkerConfig, MockMarker) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mock = MockMarker {
+            address: address.clone(),
+            ..MockMarker::default()
+        };
+        let app = Router::new()
+            .route("/api/v1/marker", post(submit))
+            .route("/api/v1/marker/{id}", get(check))
+            .with_state(mock.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let config = MarkerConfig {
+            base_url: format!("http://{address}"),
+            api_key: Some("marker-key".to_string()),
+            poll_interval: Duration::from_millis(1),
+            max_polls: 5,
+        };
+        (config, mock)
+    }
+
+    fn pdf(dir: &Path, name: &str) -> std::path::PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, b"%PDF-1.4").unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn submits_the_options_and_polls_until_complete() {
+        let (config, mock) = mock_marker().await;
+        let dir = tempfile::tempdir().unwrap();
+        let options = ConversionOptions {
+            force_ocr: true,
+            max_pages: Some(2),
+            ..ConversionOptions::default()
+        };
+        let output = convert_with_marker(&config, &pdf(dir.path(), "report.pdf"), &options)
+            .await
+            .unwrap();
+        assert_eq!(output.markdown, "# Converted");
+        assert_eq!(output.page_count, Some(2));
+        assert_eq!(*mock.polls.lock().unwrap(), 3);
+        let submitted = mock.submitted.lock().unwrap().clone();
+        assert!(submitted.contains(&("force_ocr".to_string(), "true".to_string())));
+        assert!(submitted.contains(&("max_pages".to_string(), "2".to_string())));
+        let metadata = output.metadata.unwrap();
+        assert_eq!(metadata["title"], "Report");
+        assert_eq!(metadata["toc"], r#"[{"page":1}]"#);
+    }
+
+    #[tokio::test]
+    async fn running_out_of_polls_is_retryable() {
+        let (config, mock) = mock_marker().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = pdf(dir.path(), "stuck.pdf");
+        let err = convert_with_marker(&config, &path, &ConversionOptions::default())
+            .await
+            .unwrap_err();
+        assert!(is_retryable(&err));
+        assert!(err.to_string().contains("did not finish after 5 polls"));
+        assert_eq!(*mock.polls.lock().unwrap(), 5);
+    }
+
+    #[test]
+    fn flattens_nested_metadata_to_json() {
+        let metadata = json!({
+            "title": "Report",
+            "pages": 3,
+            "ocr": true,
+            "toc": [{"title": "Intro", "page": 1}],
+        });
+        let Value::Object(metadata) = metadata else {
+            unreachable!()
+        };
+        let flat = flatten_metadata(metadata);
+        assert_eq!(flat["title"], "Report");
+        assert_eq!(flat["pages"], "3");
+        assert_eq!(flat["ocr"], "true");
+        assert_eq!(flat["toc"], r#"[{"page":1,"title":"Intro"}]"#);
+    }
+}
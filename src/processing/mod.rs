@@ -1,30 +1,197 @@
+pub mod marker;
+pub mod olmocr;
+#[cfg(unix)]
+pub mod sandbox;
 pub mod worker;
 
-use std::path::Path;
+use std::{
+    env,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::Duration,
+};
 
 use anyhow::{anyhow, bail};
 use markdownify::pdf;
+use tokio::time::timeout;
 
-use crate::types::MarkdownConversionMethod;
+use crate::types::{
+    ConversionCrashed, ConversionOptions, ConversionOutput, MarkdownConversionMethod,
+    RetryableError, StoreError, UnsupportedOptionError,
+};
+
+fn timeout_from_env(var: &str, default_secs: u64) -> Duration {
+    Duration::from_secs(
+        env::var(var)
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(default_secs),
+    )
+}
+
+pub static SIMPLE_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| timeout_from_env("SIMPLE_TIMEOUT_SECS", 120));
+
+pub static MARKER_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| timeout_from_env("MARKER_TIMEOUT_SECS", 1800));
+
+pub static OLMOCR_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| timeout_from_env("OLMOCR_TIMEOUT_SECS", 1800));
+
+/// Longest a single conversion with the given method may run before it is abandoned.
+pub fn conversion_timeout(method: &MarkdownConversionMethod) -> Duration {
+    match method {
+        MarkdownConversionMethod::Simple => *SIMPLE_TIMEOUT,
+        MarkdownConversionMethod::Marker => *MARKER_TIMEOUT,
+        MarkdownConversionMethod::OlmOcr => *OLMOCR_TIMEOUT,
+    }
+}
 
 pub async fn process_pdf(
     local_path: &str,
     method: &MarkdownConversionMethod,
-) -> anyhow::Result<String> {
+    options: &ConversionOptions,
+) -> anyhow::Result<ConversionOutput> {
+    check_conversion_options(method, options)?;
+    let limit = conversion_timeout(method);
+    // Timing out drops the conversion, which kills the sandboxed child along with it.
+    #[cfg(unix)]
+    let conversion = sandbox::convert_in_sandbox(local_path.as_ref(), method, options);
+    #[cfg(not(unix))]
+    let conversion = convert_pdf(local_path, method, options);
+    timeout(limit, conversion).await.map_err(|elapsed| {
+        anyhow::Error::new(elapsed)
+            .context(format!("{method:?} conversion timed out after {limit:?}"))
+    })?
+}
+
+async fn convert_pdf(
+    local_path: &str,
+    method: &MarkdownConversionMethod,
+    options: &ConversionOptions,
+) -> anyhow::Result<ConversionOutput> {
     match method {
-        MarkdownConversionMethod::Simple => cheaply_process_pdf_path(local_path.as_ref()),
-        MarkdownConversionMethod::Marker => process_marker_pdf(local_path.as_ref()).await,
-        MarkdownConversionMethod::OlmOcr => olmocr_deepinfra_process(local_path).await,
+        // markdownify is synchronous and CPU bound, keep it off the async worker threads.
+        MarkdownConversionMethod::Simple => {
+            let path = PathBuf::from(local_path);
+            let options = options.clone();
+            tokio::task::spawn_blocking(move || cheaply_process_pdf_path(&path, &options)).await?
+        }
+        MarkdownConversionMethod::Marker => {
+            marker::process_marker_pdf(local_path.as_ref(), options).await
+        }
+        MarkdownConversionMethod::OlmOcr => {
+            olmocr::olmocr_deepinfra_process(local_path, options).await
+        }
     }
 }
 
-pub async fn olmocr_deepinfra_process(local_path: &str) -> anyhow::Result<String> {
-    todo!()
+/// Options each conversion method honors.
+fn supported_options(method: &MarkdownConversionMethod) -> &'static [&'static str] {
+    match method {
+        // markdownify only reads the embedded text layer, there is no OCR to tune.
+        MarkdownConversionMethod::Simple => &["paginate", "disable_image_extraction", "max_pages"],
+        MarkdownConversionMethod::Marker => &[
+            "langs",
+            "force_ocr",
+            "paginate",
+            "use_llm",
+            "strip_existing_ocr",
+            "disable_image_extraction",
+            "max_pages",
+        ],
+        // olmOCR OCRs rendered page images and nothing else, so it always forces OCR, never
+        // reads the text layer and has no images to extract. There is no LLM pass to add.
+        MarkdownConversionMethod::OlmOcr => &[
+            "langs",
+            "force_ocr",
+            "paginate",
+            "strip_existing_ocr",
+            "disable_image_extraction",
+            "max_pages",
+        ],
+    }
+}
+
+/// Reject options the chosen backend has no way of honoring, rather than silently ignoring
+/// them. Called on ingest so clients hear about it straight away, and again before converting.
+pub fn check_conversion_options(
+    method: &MarkdownConversionMethod,
+    options: &ConversionOptions,
+) -> Result<(), UnsupportedOptionError> {
+    let supported = supported_options(method);
+    match options
+        .set_options()
+        .into_iter()
+        .find(|option| !supported.contains(option))
+    {
+        Some(option) => Err(UnsupportedOptionError {
+            method: *method,
+            option,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Whether a failed task is worth another attempt later. Anything unrecognised, like a
+/// malformed pdf, is treated as permanent.
+pub fn is_retryable(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if cause.is::<RetryableError>() || cause.is::<tokio::time::error::Elapsed>() {
+            return true;
+        }
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            return err.is_timeout()
+                || err.is_connect()
+                || err.is_request()
+                || err.status().is_some_and(|status| {
+                    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+                });
+        }
+        if let Some(err) = cause.downcast_ref::<StoreError>() {
+            return matches!(err, StoreError::S3(err) if !matches!(err, aws_sdk_s3::Error::NoSuchKey(_)));
+        }
+        if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                err.kind(),
+                ErrorKind::TimedOut
+                    | ErrorKind::Interrupted
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+            );
+        }
+        false
+    })
+}
+
+/// Whether a failure counts towards quarantining the document, a crashed or timed out
+/// conversion rather than an error the backend reported.
+pub fn is_poison_pill(err: &anyhow::Error) -> bool {
+    err.chain()
+        .any(|cause| cause.is::<ConversionCrashed>() || cause.is::<tokio::time::error::Elapsed>())
+}
+
+/// Join per page markdown, separating pages with a marker style delimiter when paginating.
+pub fn join_pages(pages: impl IntoIterator<Item = String>, paginate: bool) -> String {
+    let mut result = String::new();
+    for (index, page) in pages.into_iter().enumerate() {
+        if paginate {
+            result.push_str(&format!("\n\n{{{index}}}{}\n\n", "-".repeat(48)));
+        } else if index > 0 {
+            result.push_str("\n\n");
+        }
+        result.push_str(page.trim());
+    }
+    result
 }
 
 /// Convert a PDF at the given path to Markdown string.
 /// Returns Err(String) on failure.
-pub fn cheaply_process_pdf_path(path: &Path) -> anyhow::Result<String> {
+pub fn cheaply_process_pdf_path(
+    path: &Path,
+    options: &ConversionOptions,
+) -> anyhow::Result<ConversionOutput> {
     // Check if path exists
     let metadata = std::fs::metadata(path)
         .map_err(|e| anyhow!("File access error: {} (path: {:?})", e, path))?;
@@ -39,12 +206,29 @@ pub fn cheaply_process_pdf_path(path: &Path) -> anyhow::Result<String> {
     //     return Err(format!("Invalid file extension for path: {:?}", path).into());
     // }
     let pdf_result = pdf::pdf_convert(path);
-    match pdf_result {
-        Ok(path) => Ok(path),
+    let markdown = match pdf_result {
+        Ok(markdown) => markdown,
         Err(err) => bail!("Encountered markdownify error: {err}"),
-    }
+    };
+    let pages: Vec<String> = split_markdownify_pages(&markdown)
+        .into_iter()
+        .take(options.max_pages.map_or(usize::MAX, |max| max as usize))
+        .collect();
+    Ok(ConversionOutput {
+        page_count: Some(pages.len() as u32),
+        ..join_pages(pages, options.paginate).into()
+    })
 }
 
-pub async fn process_marker_pdf(_path: &Path) -> anyhow::Result<String> {
-    bail!("Marker PDF Processing Not Implemented")
+/// markdownify prefixes every page with a `<!-- Page number: N -->` comment, split on those.
+fn split_markdownify_pages(markdown: &str) -> Vec<String> {
+    markdown
+        .split("<!-- Page number: ")
+        .skip(1)
+        .map(|page| {
+            page.split_once("-->")
+                .map_or(page, |(_, body)| body)
+                .to_string()
+        })
+        .collect()
 }
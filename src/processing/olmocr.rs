@@ -0,0 +1,401 @@
+use std::{env, io::Cursor, path::PathBuf, sync::LazyLock, time::Duration};
+
+use anyhow::{anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use futures::{StreamExt, TryStreamExt, stream};
+use image::ImageFormat;
+use pdfium_render::prelude::{PdfRenderConfig, Pdfium};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::types::{ConversionOptions, ConversionOutput, RetryableError};
+
+use super::{is_retryable, join_pages};
+
+pub static OLMOCR_BASE_URL: LazyLock<String> = LazyLock::new(|| {
+    env::var("OLMOCR_BASE_URL")
+        .unwrap_or_else(|_| "https://api.deepinfra.com/v1/openai".to_string())
+});
+
+pub static OLMOCR_MODEL: LazyLock<String> = LazyLock::new(|| {
+    env::var("OLMOCR_MODEL").unwrap_or_else(|_| "allenai/olmOCR-7B-0225-preview".to_string())
+});
+
+/// Falls back to `DEEPINFRA_API_KEY`, a local mock server usually doesn't need either.
+pub static OLMOCR_API_KEY: LazyLock<Option<String>> = LazyLock::new(|| {
+    env::var("OLMOCR_API_KEY")
+        .or_else(|_| env::var("DEEPINFRA_API_KEY"))
+        .ok()
+});
+
+pub static OLMOCR_PAGE_RETRIES: LazyLock<u32> = LazyLock::new(|| {
+    env::var("OLMOCR_PAGE_RETRIES")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(3)
+});
+
+/// Pages of one document sent to the endpoint at once. The number of documents converted
+/// at once is the worker pool size, `OLMOCR_WORKERS`.
+pub static OLMOCR_CONCURRENCY: LazyLock<usize> = LazyLock::new(|| {
+    env::var("OLMOCR_CONCURRENCY")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(4)
+});
+
+/// Longest side of the rendered page in pixels, olmOCR was trained on 1024.
+pub static OLMOCR_TARGET_DIM: LazyLock<u16> = LazyLock::new(|| {
+    env::var("OLMOCR_TARGET_DIM")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(1024)
+});
+
+/// Directory containing the pdfium shared library, the system library is used if unset.
+pub static PDFIUM_LIBRARY_PATH: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("PDFIUM_LIBRARY_PATH").ok());
+
+/// Wait before the first retry of a page, doubled on every further one.
+const PAGE_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait between retries of a page.
+const PAGE_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct OlmOcrConfig {
+    pub base_url: String,
+    pub model: String,
+    pub api_key: Option<String>,
+    pub page_retries: u32,
+    pub retry_base_delay: Duration,
+    pub concurrency: usize,
+    pub target_dim: u16,
+}
+
+impl Default for OlmOcrConfig {
+    fn default() -> Self {
+        OlmOcrConfig {
+            base_url: (*OLMOCR_BASE_URL).clone(),
+            model: (*OLMOCR_MODEL).clone(),
+            api_key: (*OLMOCR_API_KEY).clone(),
+            page_retries: *OLMOCR_PAGE_RETRIES,
+            retry_base_delay: PAGE_RETRY_BASE_DELAY,
+            concurrency: *OLMOCR_CONCURRENCY,
+            target_dim: *OLMOCR_TARGET_DIM,
+        }
+    }
+}
+
+static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(300))
+        .build()
+        .expect("Should be able to build an http client")
+});
+
+pub async fn olmocr_deepinfra_process(
+    local_path: &str,
+    options: &ConversionOptions,
+) -> anyhow::Result<ConversionOutput> {
+    let config = OlmOcrConfig::default();
+    let path = PathBuf::from(local_path);
+    let max_pages = options.max_pages;
+    let target_dim = config.target_dim;
+    let pages = tokio::task::spawn_blocking(move || render_pdf_pages(path, max_pages, target_dim))
+        .await??;
+    info!(
+        page_count = pages.len(),
+        "Rendered pdf pages, sending to olmocr"
+    );
+
+    let prompt = make_page_prompt(options);
+    let markdown_pages = convert_pages(&config, &prompt, pages).await?;
+    Ok(ConversionOutput {
+        page_count: Some(markdown_pages.len() as u32),
+        ..join_pages(markdown_pages, options.paginate).into()
+    })
+}
+
+/// Render every page (up to `max_pages`) to a png, pdfium is blocking so run this off the
+/// async runtime.
+fn render_pdf_pages(
+    path: PathBuf,
+    max_pages: Option<u32>,
+    target_dim: u16,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    let bindings = match &*PDFIUM_LIBRARY_PATH {
+        Some(dir) => Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path(dir)),
+        None => Pdfium::bind_to_system_library(),
+    }
+    .map_err(|err| anyhow!("Could not load pdfium: {err}"))?;
+    let pdfium = Pdfium::new(bindings);
+    let document = pdfium
+        .load_pdf_from_file(&path, None)
+        .map_err(|err| anyhow!("Could not open pdf {path:?}: {err}"))?;
+    let render_config = PdfRenderConfig::new()
+        .set_target_width(target_dim.into())
+        .set_maximum_height(target_dim.into());
+
+    let page_limit = max_pages.map_or(usize::MAX, |max| max as usize);
+    let mut pages = Vec::new();
+    for page in document.pages().iter().take(page_limit) {
+        let image = page
+            .render_with_config(&render_config)
+            .map_err(|err| anyhow!("Could not render pdf page: {err}"))?
+            .as_image();
+        let mut png = Vec::new();
+        image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+        pages.push(png);
+    }
+    Ok(pages)
+}
+
+fn make_page_prompt(options: &ConversionOptions) -> String {
+    let mut prompt = "Below is the image of one page of a document. \
+        Just return the plain text representation of this document as if you were reading it naturally. \
+        Convert tables to markdown and equations to LaTeX. \
+        Do not hallucinate."
+        .to_string();
+    if !options.langs.is_empty() {
+        prompt.push_str(&format!(
+            " The document is written in: {}.",
+            options.langs.join(", ")
+        ));
+    }
+    prompt
+}
+
+/// Convert the rendered pages in order, failing the document if any page fails for good.
+async fn convert_pages(
+    config: &OlmOcrConfig,
+    prompt: &str,
+    pages: Vec<Vec<u8>>,
+) -> anyhow::Result<Vec<String>> {
+    stream::iter(pages.into_iter().enumerate())
+        .map(|(page_index, png)| async move {
+            convert_page_with_retries(config, prompt, page_index, &png).await
+        })
+        .buffered(config.concurrency.max(1))
+        .try_collect()
+        .await
+}
+
+/// Retries rate limits, server errors and failed connections. Anything else, like a bad API
+/// key or a rejected request, won't go better the next time.
+async fn convert_page_with_retries(
+    config: &OlmOcrConfig,
+    prompt: &str,
+    page_index: usize,
+    png: &[u8],
+) -> anyhow::Result<String> {
+    let image_url = format!("data:image/png;base64,{}", BASE64.encode(png));
+    let mut attempt = 0;
+    loop {
+        match convert_page(config, prompt, &image_url).await {
+            Ok(markdown) => return Ok(markdown),
+            Err(err) if attempt < config.page_retries && is_retryable(&err) => {
+                let delay = config
+                    .retry_base_delay
+                    .saturating_mul(2u32.saturating_pow(attempt))
+                    .min(PAGE_RETRY_MAX_DELAY);
+                attempt += 1;
+                warn!(%err, page_index, attempt, ?delay, "olmocr page conversion failed, retrying");
+                tokio::time::sleep(delay).await;
+            }
+            Err(err) => {
+                return Err(err.context(format!(
+                    "olmocr failed on page {page_index} after {attempt} retries"
+                )));
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionResponse {
+    choices: Vec<ChatCompletionChoice>,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionChoice {
+    message: ChatCompletionMessage,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionMessage {
+    content: Option<String>,
+}
+
+/// The finetuned olmOCR checkpoints answer with a json object, other models answer in plain text.
+#[derive(Deserialize)]
+struct OlmOcrPageResponse {
+    natural_text: Option<String>,
+}
+
+async fn convert_page(
+    config: &OlmOcrConfig,
+    prompt: &str,
+    image_url: &str,
+) -> anyhow::Result<String> {
+    let url = format!("{}/chat/completions", config.base_url.trim_end_matches('/'));
+    let body = json!({
+        "model": config.model,
+        "temperature": 0.1,
+        "max_tokens": 4096,
+        "messages": [{
+            "role": "user",
+            "content": [
+                {"type": "text", "text": prompt},
+                {"type": "image_url", "image_url": {"url": image_url}},
+            ],
+        }],
+    });
+    let mut request = HTTP_CLIENT.post(&url).json(&body);
+    if let Some(api_key) = &config.api_key {
+        request = request.bearer_auth(api_key);
+    }
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        let message = format!("olmocr endpoint returned {status}: {text}");
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            return Err(RetryableError(message).into());
+        }
+        bail!(message);
+    }
+    let completion: ChatCompletionResponse = response.json().await?;
+    let content = completion
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .ok_or_else(|| anyhow!("olmocr endpoint returned no content"))?;
+    match serde_json::from_str::<OlmOcrPageResponse>(&content) {
+        Ok(page) => Ok(page.natural_text.unwrap_or_default()),
+        Err(_) => Ok(content),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use axum::{Json, Router, extract::State, http::StatusCode, routing::post};
+    use serde_json::Value;
+
+    use super::*;
+
+    /// Requests the mock endpoint got, by the page it was sent.
+    type Requests = Arc<Mutex<HashMap<String, u32>>>;
+
+    /// Pages are sent as their name instead of a png. `flaky` pages fail twice with a 503,
+    /// `busy` ones are always rate limited and `bad` ones are rejected with a 400.
+    async fn chat_completions(
+        State(requests): State<Requests>,
+        Json(body): Json<Value>,
+    ) -> (StatusCode, Json<Value>) {
+        let image_url = body["messages"][0]["content"][1]["image_url"]["url"]
+            .as_str()
+            .unwrap();
+        let encoded = image_url.trim_start_matches("data:image/png;base64,");
+        let page = String::from_utf8(BASE64.decode(encoded).unwrap()).unwrap();
+        let seen = {
+            let mut requests = requests.lock().unwrap();
+            let seen = requests.entry(page.clone()).or_default();
+            *seen += 1;
+            *seen
+        };
+        let failure = match page.as_str() {
+            "flaky" if seen <= 2 => Some(StatusCode::SERVICE_UNAVAILABLE),
+            "busy" => Some(StatusCode::TOO_MANY_REQUESTS),
+            "bad" => Some(StatusCode::BAD_REQUEST),
+            _ => None,
+        };
+        if let Some(status) = failure {
+            return (status, Json(json!({"error": "nope"})));
+        }
+        let content = json!({"natural_text": format!("text of {page}")}).to_string();
+        let completion = json!({"choices": [{"message": {"content": content}}]});
+        (StatusCode::OK, Json(completion))
+    }
+
+    async fn mock_endpoint() -> (OlmOcrConfig, Requests) {
+        let requests = Requests::default();
+        let app = Router::new()
+            .route("/chat/completions", post(chat_completions))
+            .with_state(requests.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let config = OlmOcrConfig {
+            base_url: format!("http://{address}/"),
+            model: "olmocr-test".to_string(),
+            api_key: None,
+            page_retries: 3,
+            retry_base_delay: Duration::from_millis(1),
+            concurrency: 2,
+            target_dim: 1024,
+        };
+        (config, requests)
+    }
+
+    fn pages(names: &[&str]) -> Vec<Vec<u8>> {
+        names.iter().map(|name| name.as_bytes().to_vec()).collect()
+    }
+
+    fn requests_for(requests: &Requests, page: &str) -> u32 {
+        requests.lock().unwrap().get(page).copied().unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn pages_come_back_in_order_after_retrying_server_errors() {
+        let (config, requests) = mock_endpoint().await;
+        let markdown = convert_pages(&config, "prompt", pages(&["one", "flaky", "three"]))
+            .await
+            .unwrap();
+        assert_eq!(markdown, ["text of one", "text of flaky", "text of three"]);
+        assert_eq!(requests_for(&requests, "flaky"), 3);
+        assert_eq!(requests_for(&requests, "one"), 1);
+    }
+
+    #[tokio::test]
+    async fn a_page_that_keeps_failing_fails_the_document_as_retryable() {
+        let (config, requests) = mock_endpoint().await;
+        let err = convert_pages(&config, "prompt", pages(&["one", "busy"]))
+            .await
+            .unwrap_err();
+        assert!(is_retryable(&err));
+        assert!(format!("{err:#}").contains("page 1 after 3 retries"));
+        assert_eq!(requests_for(&requests, "busy"), 4);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (config, requests) = mock_endpoint().await;
+        let err = convert_pages(&config, "prompt", pages(&["bad", "two"]))
+            .await
+            .unwrap_err();
+        assert!(!is_retryable(&err));
+        assert!(format!("{err:#}").contains("400 Bad Request"));
+        assert_eq!(requests_for(&requests, "bad"), 1);
+    }
+
+    #[tokio::test]
+    async fn unreachable_endpoints_are_retried() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let (mut config, _) = mock_endpoint().await;
+        config.base_url = format!("http://{address}");
+        config.page_retries = 1;
+        let err = convert_pages(&config, "prompt", pages(&["one"]))
+            .await
+            .unwrap_err();
+        assert!(is_retryable(&err));
+        assert!(format!("{err:#}").contains("after 1 retries"));
+    }
+}
@@ -0,0 +1,333 @@
+//! Runs conversions in a child process under resource limits, so a pdf that crashes or
+//! exhausts native pdf code only takes down that one child.
+//!
+//! The parent re-executes this binary with the hidden `convert` subcommand, writes a
+//! `SandboxRequest` to its stdin and reads a `SandboxReply` back from its stdout.
+//!
+//! On Linux the child confines itself with Landlock before converting: it may only write
+//! beneath its own working directory, and only read that, the system libraries and
+//! `SANDBOX_READ_PATHS`. Kernels without Landlock run it unconfined, with a warning.
+use std::{
+    env,
+    fs::File,
+    io::{Read, Write},
+    os::fd::FromRawFd,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::LazyLock,
+};
+
+use anyhow::{Context, anyhow};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, process::Command};
+use tracing::{debug, warn};
+
+use crate::processing::{convert_pdf, is_retryable, olmocr::PDFIUM_LIBRARY_PATH};
+use crate::types::{
+    ConversionCrashed, ConversionOptions, ConversionOutput, MarkdownConversionMethod,
+    RetryableError,
+};
+
+/// Resource limits applied to every conversion process.
+#[derive(Debug, Clone, Copy)]
+pub struct SandboxLimits {
+    /// Address space cap, allocations past it fail.
+    pub memory_bytes: u64,
+    /// CPU time cap, the process gets SIGXCPU once it is used up.
+    pub cpu_secs: u64,
+    /// Maximum number of open file descriptors.
+    pub open_files: u64,
+}
+
+fn limit_from_env(var: &str, default: u64) -> u64 {
+    env::var(var)
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(default)
+}
+
+impl Default for SandboxLimits {
+    fn default() -> Self {
+        SandboxLimits {
+            memory_bytes: limit_from_env("SANDBOX_MEMORY_MB", 4096) * 1024 * 1024,
+            cpu_secs: limit_from_env("SANDBOX_CPU_SECS", 600),
+            open_files: limit_from_env("SANDBOX_OPEN_FILES", 256),
+        }
+    }
+}
+
+pub static SANDBOX_LIMITS: LazyLock<SandboxLimits> = LazyLock::new(SandboxLimits::default);
+
+/// Parent directory of the per conversion working directories.
+pub static SANDBOX_ROOT: LazyLock<PathBuf> = LazyLock::new(|| {
+    env::var("SANDBOX_ROOT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| env::temp_dir().join("crimson-sandbox"))
+});
+
+/// Paths the child may read beneath besides its working directory: system libraries and the
+/// few files name resolution and TLS look at. Missing ones are skipped.
+const SYSTEM_READ_PATHS: &[&str] = &[
+    "/usr",
+    "/lib",
+    "/lib64",
+    "/lib32",
+    "/etc/ld.so.cache",
+    "/etc/ld.so.conf",
+    "/etc/ld.so.conf.d",
+    "/etc/resolv.conf",
+    "/etc/hosts",
+    "/etc/host.conf",
+    "/etc/nsswitch.conf",
+    "/etc/gai.conf",
+    "/etc/localtime",
+    "/etc/ssl",
+    "/etc/pki",
+    "/etc/ca-certificates",
+    "/dev/null",
+    "/dev/urandom",
+    "/dev/random",
+    "/proc/self",
+    "/sys/fs/cgroup",
+    "/sys/devices/system/cpu",
+];
+
+/// Further paths the child may read, colon separated, for libraries installed elsewhere.
+static SANDBOX_READ_PATHS: LazyLock<Vec<PathBuf>> = LazyLock::new(|| {
+    env::var_os("SANDBOX_READ_PATHS")
+        .map(|paths| env::split_paths(&paths).collect())
+        .unwrap_or_default()
+});
+
+/// What the child should convert, sent on its stdin.
+#[derive(Serialize, Deserialize, Debug)]
+struct SandboxRequest {
+    input: PathBuf,
+    method: MarkdownConversionMethod,
+    options: ConversionOptions,
+}
+
+/// How the conversion went, sent back on the child's stdout.
+#[derive(Serialize, Deserialize, Debug)]
+enum SandboxReply {
+    Converted(ConversionOutput),
+    Failed { error: String, retryable: bool },
+}
+
+/// Working directory of a single conversion, removed again when dropped so it is cleaned
+/// up even when the conversion is timed out or cancelled.
+struct SandboxDir(PathBuf);
+
+impl SandboxDir {
+    async fn create() -> std::io::Result<Self> {
+        let path = SANDBOX_ROOT.join(format!("{:016x}", rand::random::<u64>()));
+        tokio::fs::create_dir_all(&path).await?;
+        Ok(SandboxDir(path))
+    }
+}
+
+impl Drop for SandboxDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Convert a pdf in a fresh child process working in its own temp directory, see the module
+/// docs for how it is confined. Dropping the returned future kills the child.
+pub async fn convert_in_sandbox(
+    local_path: &Path,
+    method: &MarkdownConversionMethod,
+    options: &ConversionOptions,
+) -> anyhow::Result<ConversionOutput> {
+    let dir = SandboxDir::create()
+        .await
+        .context("Could not create sandbox directory")?;
+    let file_name = local_path
+        .file_name()
+        .ok_or_else(|| anyhow!("Path is not a file: {local_path:?}"))?;
+    let input = dir.0.join(file_name);
+    tokio::fs::copy(local_path, &input)
+        .await
+        .with_context(|| format!("Could not copy {local_path:?} into the sandbox"))?;
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .arg("convert")
+        .current_dir(&dir.0)
+        .env("TMPDIR", &dir.0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    // The child runs from the sandbox directory, so a relative library path would break.
+    if let Some(pdfium_dir) = &*PDFIUM_LIBRARY_PATH {
+        command.env("PDFIUM_LIBRARY_PATH", std::path::absolute(pdfium_dir)?);
+    }
+    let limits = *SANDBOX_LIMITS;
+    // SAFETY: only async signal safe libc calls run between fork and exec.
+    unsafe {
+        command.pre_exec(move || apply_limits(limits));
+    }
+    let mut child = command
+        .spawn()
+        .context("Could not start conversion process")?;
+
+    let request = serde_json::to_vec(&SandboxRequest {
+        input,
+        method: *method,
+        options: options.clone(),
+    })?;
+    let mut stdin = child.stdin.take().expect("Child stdin should be piped");
+    // A child that dies before reading this is reported through its exit status below.
+    let _ = stdin.write_all(&request).await;
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(ConversionCrashed(format!(
+            "Conversion process {}{}",
+            describe_exit(output.status),
+            stderr_tail(&output.stderr)
+        ))
+        .into());
+    }
+    debug!(stderr = %String::from_utf8_lossy(&output.stderr), "Conversion process finished");
+    let reply: SandboxReply = serde_json::from_slice(&output.stdout)
+        .context("Conversion process sent back an unreadable result")?;
+    match reply {
+        SandboxReply::Converted(output) => Ok(output),
+        SandboxReply::Failed {
+            error,
+            retryable: true,
+        } => Err(RetryableError(error).into()),
+        SandboxReply::Failed { error, .. } => Err(anyhow!(error)),
+    }
+}
+
+fn apply_limits(limits: SandboxLimits) -> std::io::Result<()> {
+    let set = |resource, value: u64| {
+        let limit = libc::rlimit {
+            rlim_cur: value as libc::rlim_t,
+            rlim_max: value as libc::rlim_t,
+        };
+        if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    set(libc::RLIMIT_AS, limits.memory_bytes)?;
+    set(libc::RLIMIT_CPU, limits.cpu_secs)?;
+    set(libc::RLIMIT_NOFILE, limits.open_files)?;
+    // Don't outlive a parent that crashed without killing us.
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+    }
+    Ok(())
+}
+
+/// Human readable reason a conversion process didn't exit cleanly.
+fn describe_exit(status: ExitStatus) -> String {
+    match status.signal() {
+        Some(libc::SIGSEGV) => "crashed with a segmentation fault".to_string(),
+        Some(libc::SIGBUS) => "crashed with a bus error".to_string(),
+        Some(libc::SIGABRT) => "aborted, possibly after hitting its memory limit".to_string(),
+        Some(libc::SIGXCPU) => "ran out of its CPU time limit".to_string(),
+        Some(libc::SIGKILL) => "was killed".to_string(),
+        Some(signal) => format!("was terminated by signal {signal}"),
+        None => format!("exited with {status}"),
+    }
+}
+
+/// The last few lines the child wrote to stderr, usually the panic or allocation failure.
+fn stderr_tail(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines: Vec<&str> = stderr.trim().lines().collect();
+    if lines.is_empty() {
+        return String::new();
+    }
+    format!(": {}", lines[lines.len().saturating_sub(5)..].join(" | "))
+}
+
+/// Entry point of the `convert` subcommand, run inside the sandboxed child.
+pub fn run_sandboxed_conversion() -> anyhow::Result<()> {
+    let mut reply_pipe = take_stdout_for_reply()?;
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_ansi(false)
+        .init();
+    // Before any thread is started, Landlock only confines the calling thread and what it starts.
+    #[cfg(target_os = "linux")]
+    confine_to(&env::current_dir()?)?;
+    let mut request = Vec::new();
+    std::io::stdin().read_to_end(&mut request)?;
+    let request: SandboxRequest = serde_json::from_slice(&request)?;
+    // A single thread keeps the address space small enough for the memory limit to bite on
+    // the conversion itself rather than on runtime overhead.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let input = request.input.to_string_lossy();
+    let reply = match runtime.block_on(convert_pdf(&input, &request.method, &request.options)) {
+        Ok(output) => SandboxReply::Converted(output),
+        Err(err) => SandboxReply::Failed {
+            retryable: is_retryable(&err),
+            error: format!("{err:#}"),
+        },
+    };
+    serde_json::to_writer(&mut reply_pipe, &reply)?;
+    reply_pipe.flush()?;
+    Ok(())
+}
+
+/// Only let this process, and whatever it starts from now on, write beneath `workdir` and read
+/// beneath it and the library paths.
+#[cfg(target_os = "linux")]
+fn confine_to(workdir: &Path) -> anyhow::Result<()> {
+    use landlock::{
+        ABI, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+        path_beneath_rules,
+    };
+
+    let abi = ABI::V5;
+    let mut read_paths: Vec<PathBuf> = SYSTEM_READ_PATHS.iter().map(PathBuf::from).collect();
+    read_paths.extend(SANDBOX_READ_PATHS.iter().cloned());
+    // The parent hands the child an absolute pdfium path, system ones are covered above.
+    read_paths.extend(PDFIUM_LIBRARY_PATH.iter().map(PathBuf::from));
+    if let Some(library_paths) = env::var_os("LD_LIBRARY_PATH") {
+        read_paths.extend(env::split_paths(&library_paths));
+    }
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(read_paths, AccessFs::from_read(abi)))?
+        .add_rules(path_beneath_rules([workdir], AccessFs::from_all(abi)))?
+        .restrict_self()
+        .context("Could not confine the conversion process")?;
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => debug!("Conversion process confined"),
+        RulesetStatus::PartiallyEnforced => {
+            debug!("Conversion process confined as far as this kernel's Landlock allows")
+        }
+        RulesetStatus::NotEnforced => {
+            warn!("This kernel doesn't support Landlock, the conversion process is unconfined")
+        }
+    }
+    Ok(())
+}
+
+/// Keep the original stdout for the reply and point fd 1 at stderr, so anything a native
+/// library prints can't corrupt it.
+fn take_stdout_for_reply() -> std::io::Result<File> {
+    unsafe {
+        let reply = libc::dup(libc::STDOUT_FILENO);
+        if reply < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(File::from_raw_fd(reply))
+    }
+}
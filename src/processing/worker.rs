@@ -1,85 +1,603 @@
-use std::time::Duration;
+use std::{
+    env,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
 
 use anyhow::{anyhow, bail};
-use tokio::sync::Semaphore;
-use tokio::time::sleep;
+use futures::future::join_all;
+use tokio::sync::{Notify, Semaphore, SemaphorePermit, broadcast::error::RecvError};
+use tokio::time::{MissedTickBehavior, interval, sleep, timeout};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::Instrument;
 
-use crate::logic::{get_file_task_from_queue, get_local_store, update_task_data};
-use crate::processing::{cheaply_process_pdf_path, process_marker_pdf, process_pdf};
-use crate::types::{DocStatus, FileStoreImplementation, MarkdownConversionMethod, ProcessingStage};
-use tracing::{error, info};
+use crate::common::metrics::{
+    Gauge, TASKS_CANCELLED, TASKS_COMPLETED, TASKS_DEAD_LETTERED, TASKS_FAILED, TASKS_IN_FLIGHT,
+    TASKS_QUARANTINED, TASKS_RETRIED, TASKS_STARTED, WORKER_POOL_BUSY, WORKER_POOL_SIZE,
+};
+use crate::logic::{
+    Stores, TASK_LEASE_DURATION, ack_task, charge_converted_pages, dead_letter_task,
+    extend_task_lease, find_quarantined, finish_task_data, get_file_task_from_queue,
+    get_task_data_from_id, hash_file, record_conversion_strike, retry_task_later, update_task_data,
+};
+use crate::processing::{is_poison_pill, is_retryable, process_pdf};
+use crate::types::{
+    DocStatus, MarkdownConversionMethod, ProcessingStage, QuarantinedDocument, QueueError, TaskID,
+    TaskLease, WorkerPoolInfo,
+};
+use tracing::{debug, error, info, warn};
 
-static PDF_SEMAPHORE: Semaphore = Semaphore::const_new(3);
+/// Largest size a worker pool can be given, far beyond what one machine can convert at once.
+pub const MAX_POOL_SIZE: u32 = 1024;
 
-/// Start the worker that continuously processes PDF tasks from the queue.
-pub async fn start_worker() {
-    info!("Starting pdf processing worker.");
-    let mut no_pdf_counter = 0;
+/// Conversion slots for one conversion method, so a slow method can't hold up the others.
+pub struct WorkerPool {
+    pub method: MarkdownConversionMethod,
+    semaphore: Semaphore,
+    slots: Mutex<PoolSlots>,
+    /// Woken on shrinking, so the slot held while waiting on the queue is handed back.
+    shrunk: Notify,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PoolSlots {
+    size: u32,
+    /// Slots to take out of the pool as running conversions hand them back.
+    owed: u32,
+}
+
+impl WorkerPool {
+    /// Sized by `SIMPLE_WORKERS`, `MARKER_WORKERS` or `OLMOCR_WORKERS`. These limit whole
+    /// documents, how many pages of one document olmOCR converts at once is
+    /// `OLMOCR_CONCURRENCY`.
+    fn from_env(method: MarkdownConversionMethod) -> Self {
+        let (var, default) = match method {
+            // Runs locally, one conversion per core.
+            MarkdownConversionMethod::Simple => (
+                "SIMPLE_WORKERS",
+                std::thread::available_parallelism().map_or(1, |cores| cores.get() as u32),
+            ),
+            MarkdownConversionMethod::Marker => ("MARKER_WORKERS", 3),
+            MarkdownConversionMethod::OlmOcr => ("OLMOCR_WORKERS", 3),
+        };
+        let size = env::var(var)
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(default)
+            .min(MAX_POOL_SIZE);
+        pool_metric(&WORKER_POOL_SIZE, method).set(size.into());
+        WorkerPool {
+            method,
+            semaphore: Semaphore::new(size as usize),
+            slots: Mutex::new(PoolSlots { size, owed: 0 }),
+            shrunk: Notify::new(),
+        }
+    }
+
+    fn slots(&self) -> std::sync::MutexGuard<'_, PoolSlots> {
+        self.slots
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn size(&self) -> u32 {
+        self.slots().size
+    }
+
+    /// Conversions of this method running right now.
+    pub fn busy(&self) -> u32 {
+        pool_metric(&WORKER_POOL_BUSY, self.method).get().max(0) as u32
+    }
+
+    /// Change how many conversions may run at once. Shrinking lets running conversions
+    /// finish, the pool only takes on new ones once it is below the new size.
+    pub fn resize(&self, size: u32) {
+        let size = size.min(MAX_POOL_SIZE);
+        let mut slots = self.slots();
+        let previous = slots.size;
+        if size > previous {
+            // Cancel out slots still owed from an earlier shrink before adding new ones.
+            let grow = size - previous;
+            let repaid = grow.min(slots.owed);
+            slots.owed -= repaid;
+            self.semaphore.add_permits((grow - repaid) as usize);
+        } else if size < previous {
+            let mut shrink = previous - size;
+            while shrink > 0
+                && let Ok(permit) = self.semaphore.try_acquire()
+            {
+                permit.forget();
+                shrink -= 1;
+            }
+            slots.owed += shrink;
+            self.shrunk.notify_waiters();
+        }
+        slots.size = size;
+        drop(slots);
+        pool_metric(&WORKER_POOL_SIZE, self.method).set(size.into());
+        info!(method = ?self.method, previous, size, "Resized worker pool.");
+    }
+
+    /// Take a freed slot out of the pool if a shrink still owes one.
+    fn pay_owed_slot<'a>(&self, permit: SemaphorePermit<'a>) -> Option<SemaphorePermit<'a>> {
+        let mut slots = self.slots();
+        if slots.owed == 0 {
+            return Some(permit);
+        }
+        slots.owed -= 1;
+        permit.forget();
+        None
+    }
+
+    pub fn info(&self) -> WorkerPoolInfo {
+        WorkerPoolInfo {
+            method: self.method,
+            size: self.size(),
+            busy: self.busy(),
+        }
+    }
+}
+
+/// One worker pool per conversion method, ordered like `MarkdownConversionMethod::ALL`.
+pub static WORKER_POOLS: LazyLock<[WorkerPool; 3]> =
+    LazyLock::new(|| MarkdownConversionMethod::ALL.map(WorkerPool::from_env));
+
+pub fn worker_pool(method: MarkdownConversionMethod) -> &'static WorkerPool {
+    &WORKER_POOLS[method_index(method)]
+}
+
+fn method_index(method: MarkdownConversionMethod) -> usize {
+    MarkdownConversionMethod::ALL
+        .iter()
+        .position(|candidate| *candidate == method)
+        .expect("ALL lists every conversion method")
+}
+
+fn pool_metric(metrics: &'static [Gauge; 3], method: MarkdownConversionMethod) -> &'static Gauge {
+    &metrics[method_index(method)]
+}
+
+/// Attempts a task gets before a retryable failure sends it to the dead letter queue.
+pub static TASK_MAX_ATTEMPTS: LazyLock<u32> = LazyLock::new(|| {
+    env::var("TASK_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(5)
+});
+
+/// Delay before the first retry, doubled for every attempt after that.
+pub static TASK_RETRY_BASE_DELAY: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        env::var("TASK_RETRY_BASE_SECS")
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(10),
+    )
+});
+
+pub static TASK_RETRY_MAX_DELAY: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        env::var("TASK_RETRY_MAX_SECS")
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(600),
+    )
+});
+
+/// How long a shutting down worker waits for in flight conversions before requeueing them.
+pub static SHUTDOWN_GRACE_PERIOD: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        env::var("SHUTDOWN_GRACE_SECS")
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(30),
+    )
+});
+
+/// How often a running task re-reads its status in case the event cancelling it got lost.
+pub static CANCELLATION_RECHECK_INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        env::var("CANCEL_RECHECK_SECS")
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(30),
+    )
+});
+
+/// Start the workers that continuously process PDF tasks from the queues, one pool per
+/// conversion method, returning once `shutdown` is cancelled and in flight tasks have
+/// finished or been requeued.
+pub async fn start_worker(stores: Stores, shutdown: CancellationToken) {
+    info!(
+        pools = ?WORKER_POOLS.iter().map(WorkerPool::info).collect::<Vec<_>>(),
+        "Starting pdf processing workers."
+    );
+    // Cancelled when the grace period runs out, telling in flight tasks to give up.
+    let abandon = CancellationToken::new();
+    let conversions = TaskTracker::new();
+    let pools = WORKER_POOLS.iter().map(|pool| {
+        tokio::spawn(
+            run_pool(
+                stores.clone(),
+                pool,
+                shutdown.clone(),
+                abandon.clone(),
+                conversions.clone(),
+            )
+            .in_current_span(),
+        )
+    });
+    join_all(pools.collect::<Vec<_>>()).await;
+    drain_in_flight_tasks(&conversions, &abandon).await;
+}
+
+/// Lease and convert tasks of the pool's method until shutdown, the conversions are spawned
+/// on `conversions`.
+async fn run_pool(
+    stores: Stores,
+    pool: &'static WorkerPool,
+    shutdown: CancellationToken,
+    abandon: CancellationToken,
+    conversions: TaskTracker,
+) {
+    let method = pool.method;
     loop {
-        let permit = PDF_SEMAPHORE.acquire().await;
-        match get_file_task_from_queue().await {
-            Some(status) => {
-                no_pdf_counter = 0;
-                tokio::spawn(async move {
-                    if let Err(err) = process_pdf_from_status(status).await {
-                        error!(%err, "encountered error processing pdf.");
+        // Take a slot before leasing, so a leased task starts straight away rather than
+        // spending its lease waiting for one. Holding it while the queue is empty costs nothing.
+        let permit = tokio::select! {
+            permit = pool.semaphore.acquire() => permit.expect("Worker pool semaphores are never closed"),
+            _ = shutdown.cancelled() => break,
+        };
+        // Listen before checking, so a shrink that lands in between still wakes us.
+        let shrunk = pool.shrunk.notified();
+        tokio::pin!(shrunk);
+        shrunk.as_mut().enable();
+        let Some(permit) = pool.pay_owed_slot(permit) else {
+            continue;
+        };
+        let next = tokio::select! {
+            next = get_file_task_from_queue(&stores, &method) => next,
+            _ = shrunk => continue,
+            _ = shutdown.cancelled() => break,
+        };
+        match next {
+            Ok(Some((status, mut lease))) => {
+                let stores = stores.clone();
+                let abandon = abandon.clone();
+                TASKS_STARTED.inc();
+                conversions.spawn(async move {
+                    let _in_flight = TASKS_IN_FLIGHT.track();
+                    let _busy = pool_metric(&WORKER_POOL_BUSY, method).track();
+                    let task_id = status.request_id;
+                    // If this task panics the heartbeat goes with it and the lease runs out.
+                    // `None` means shutdown ran out of time and the task was abandoned.
+                    let result = tokio::select! {
+                        result = process_pdf_from_status(&stores, status.clone()) => Some(result),
+                        err = keep_lease_alive(&stores, &mut lease) => Some(Err(err)),
+                        _ = wait_for_cancellation(&stores, task_id) => Some(Ok(TaskOutcome::Cancelled)),
+                        _ = abandon.cancelled() => None,
+                    };
+                    match result {
+                        None => match requeue_interrupted_task(&stores, &lease, status).await {
+                            Ok(()) => info!(task_id, "Requeued task interrupted by shutdown."),
+                            Err(err) => {
+                                warn!(%err, task_id, "Failed to requeue task interrupted by shutdown, it will be retried once its lease expires.")
+                            }
+                        },
+                        Some(Ok(outcome)) => {
+                            if let Err(err) = settle_task(&stores, &lease, outcome).await {
+                                warn!(%err, task_id, "Failed to settle finished task with the queue.");
+                            }
+                        }
+                        Some(Err(err)) => {
+                            error!(%err, task_id, "encountered error processing pdf, it will be retried once its lease expires.");
+                        }
                     }
                     drop(permit);
                 });
             }
-            None => {
-                // No tasks available, sleep briefly
-                no_pdf_counter += 1;
-                if no_pdf_counter >= 30 {
-                    info!("No pdfs detected after {no_pdf_counter} polls");
-                    no_pdf_counter = 0;
+            Ok(None) => debug!(?method, "No pdfs queued, waiting again."),
+            Err(err) => {
+                warn!(%err, ?method, "Failed to dequeue a task, trying again shortly.");
+                tokio::select! {
+                    _ = sleep(Duration::from_secs(2)) => {}
+                    _ = shutdown.cancelled() => break,
                 }
-                sleep(Duration::from_secs(2)).await;
             }
         }
     }
 }
 
-async fn process_pdf_from_status(mut status: DocStatus) -> anyhow::Result<()> {
-    async fn task_errored(mut status: DocStatus, err: anyhow::Error) -> anyhow::Error {
-        status.error = Some("Encountered error: ".to_string() + &err.to_string());
-        status.status = ProcessingStage::Errored;
-        let _ = update_task_data(status).await;
-        err
+/// Wait for every spawned conversion to finish, requeueing whatever is still running once
+/// the grace period is over.
+async fn drain_in_flight_tasks(conversions: &TaskTracker, abandon: &CancellationToken) {
+    let grace = *SHUTDOWN_GRACE_PERIOD;
+    info!(
+        ?grace,
+        "Worker stopped dequeuing, waiting for in flight conversions."
+    );
+    conversions.close();
+    if timeout(grace, conversions.wait()).await.is_ok() {
+        info!("All in flight conversions finished.");
+        return;
+    }
+    warn!(
+        in_flight = TASKS_IN_FLIGHT.get(),
+        "Grace period is over, requeueing conversions still in flight."
+    );
+    abandon.cancel();
+    if timeout(Duration::from_secs(10), conversions.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Some tasks could not be requeued in time, they will be retried once their leases expire."
+        );
+    }
+}
+
+/// Put a task the worker gave up on during shutdown straight back on the queue, without
+/// counting it as an attempt.
+async fn requeue_interrupted_task(
+    stores: &Stores,
+    lease: &TaskLease,
+    mut status: DocStatus,
+) -> anyhow::Result<()> {
+    status.status = ProcessingStage::Waiting;
+    update_task_data(stores, status).await?;
+    retry_task_later(stores, lease, Duration::ZERO).await?;
+    Ok(())
+}
+
+/// How an attempt at a task ended, once its status has been stored.
+enum TaskOutcome {
+    Completed,
+    /// Cancelled through the API, the status already says so.
+    Cancelled,
+    /// Failed in a way retrying won't fix.
+    Failed,
+    /// Failed in a way worth retrying after the delay.
+    Retry(Duration),
+    /// Kept failing in a retryable way until it ran out of attempts.
+    Exhausted {
+        error: String,
+        attempts: u32,
+    },
+}
+
+async fn settle_task(
+    stores: &Stores,
+    lease: &TaskLease,
+    outcome: TaskOutcome,
+) -> Result<(), QueueError> {
+    match outcome {
+        TaskOutcome::Completed => {
+            TASKS_COMPLETED.inc();
+            ack_task(stores, lease).await
+        }
+        TaskOutcome::Cancelled => {
+            TASKS_CANCELLED.inc();
+            ack_task(stores, lease).await
+        }
+        TaskOutcome::Failed => {
+            TASKS_FAILED.inc();
+            ack_task(stores, lease).await
+        }
+        TaskOutcome::Retry(delay) => {
+            TASKS_RETRIED.inc();
+            retry_task_later(stores, lease, delay).await
+        }
+        TaskOutcome::Exhausted { error, attempts } => {
+            TASKS_DEAD_LETTERED.inc();
+            dead_letter_task(stores, lease, error, attempts).await
+        }
+    }
+}
+
+/// Exponential backoff before the retry following the given attempt.
+fn retry_backoff(attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    (*TASK_RETRY_BASE_DELAY)
+        .saturating_mul(factor)
+        .min(*TASK_RETRY_MAX_DELAY)
+}
+
+/// Resolves once the task has been cancelled, dropping the conversion racing against it.
+/// Cancellations are heard of on the event bus, the status is only re-read when events may
+/// have been missed.
+async fn wait_for_cancellation(stores: &Stores, task_id: TaskID) {
+    let mut events = stores.event_bus.subscribe();
+    // The first tick is immediate, catching a cancel from before we subscribed.
+    let mut recheck = interval(*CANCELLATION_RECHECK_INTERVAL);
+    recheck.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut listening = true;
+    loop {
+        let cancelled = tokio::select! {
+            received = events.recv(), if listening => match received {
+                Ok(event) => {
+                    event.request_id == task_id && event.status == ProcessingStage::Cancelled
+                }
+                Err(RecvError::Lagged(_)) => is_cancelled(stores, task_id).await,
+                Err(RecvError::Closed) => {
+                    listening = false;
+                    false
+                }
+            },
+            _ = recheck.tick() => is_cancelled(stores, task_id).await,
+        };
+        if cancelled {
+            info!(task_id, "Task was cancelled, aborting its conversion.");
+            return;
+        }
+    }
+}
+
+/// Whether the stored status says the task was cancelled, so its result must not overwrite it.
+async fn is_cancelled(stores: &Stores, task_id: TaskID) -> bool {
+    get_task_data_from_id(stores, task_id)
+        .await
+        .is_ok_and(|status| status.status == ProcessingStage::Cancelled)
+}
+
+/// Heartbeat the lease until it is lost, which only returns if another worker may now hold it.
+async fn keep_lease_alive(stores: &Stores, lease: &mut TaskLease) -> anyhow::Error {
+    let interval = *TASK_LEASE_DURATION / 3;
+    loop {
+        sleep(interval).await;
+        match extend_task_lease(stores, lease).await {
+            Ok(()) => {}
+            Err(QueueError::LeaseLost) => {
+                return anyhow!("Lost the lease on task {}, abandoning it", lease.task.id);
+            }
+            Err(err) => warn!(%err, task_id = lease.task.id, "Failed to extend task lease."),
+        }
+    }
+}
+
+/// Error a task whose document is quarantined without converting it.
+async fn task_quarantined(
+    stores: &Stores,
+    mut status: DocStatus,
+    document: &QuarantinedDocument,
+) -> anyhow::Result<TaskOutcome> {
+    let task_id = status.request_id;
+    warn!(task_id, content_hash = %document.content_hash, "Document is quarantined, not converting it.");
+    status.status = ProcessingStage::Errored;
+    status.error = Some(format!(
+        "quarantined: this document crashed or timed out converters {} times, last error: {}",
+        document.strikes, document.error
+    ));
+    update_task_data(stores, status)
+        .await
+        .map_err(|err| anyhow!("Failed to record task quarantine: {err}"))?;
+    TASKS_QUARANTINED.inc();
+    Ok(TaskOutcome::Failed)
+}
+
+/// Convert the task's document and record the outcome.
+///
+/// `Err` means the outcome couldn't be stored, so the task is left for its lease to expire.
+async fn process_pdf_from_status(
+    stores: &Stores,
+    mut status: DocStatus,
+) -> anyhow::Result<TaskOutcome> {
+    async fn task_errored(
+        stores: &Stores,
+        mut status: DocStatus,
+        err: anyhow::Error,
+    ) -> anyhow::Result<TaskOutcome> {
+        let task_id = status.request_id;
+        if is_cancelled(stores, task_id).await {
+            info!(
+                task_id,
+                "Task was cancelled while converting, dropping its error."
+            );
+            return Ok(TaskOutcome::Cancelled);
+        }
+        let retryable = is_retryable(&err);
+        status.error = Some(format!("Encountered error: {err:#}"));
+        let outcome = if retryable && status.attempts < *TASK_MAX_ATTEMPTS {
+            let delay = retry_backoff(status.attempts);
+            warn!(err = %format!("{err:#}"), task_id, attempts = status.attempts, ?delay, "Task failed, retrying later");
+            status.status = ProcessingStage::Waiting;
+            TaskOutcome::Retry(delay)
+        } else {
+            error!(err = %format!("{err:#}"), task_id, attempts = status.attempts, retryable, "Task errored");
+            status.status = ProcessingStage::Errored;
+            if retryable {
+                TaskOutcome::Exhausted {
+                    error: status.error.clone().unwrap_or_default(),
+                    attempts: status.attempts,
+                }
+            } else {
+                TaskOutcome::Failed
+            }
+        };
+        update_task_data(stores, status)
+            .await
+            .map_err(|db_err| anyhow!("Failed to record task error ({err:#}): {db_err}"))?;
+        Ok(outcome)
     }
-    // Download the file
     let task_id = status.request_id;
+    if status.status == ProcessingStage::Cancelled {
+        info!(task_id, "Skipping cancelled task.");
+        return Ok(TaskOutcome::Cancelled);
+    }
+    // Download the file
     status.status = ProcessingStage::Processing;
-    if let Err(err) = update_task_data(status.clone()).await {
+    status.attempts += 1;
+    if let Err(err) = update_task_data(stores, status.clone()).await {
         bail!("Failed to set status to Processing for task {task_id}: {err}",);
     }
     info!(task_id, "Updated document to processing stage.");
 
-    let store = get_local_store();
-    let download_result = store
+    let download_result = stores
         .file_store
         .download_to_file(&status.file_location)
         .await;
     if let Err(err) = download_result {
-        return Err(task_errored(status, err.into()).await);
+        return task_errored(stores, status, err.into()).await;
+    }
+    // Deletes a downloaded copy however the task ends.
+    let local_file = download_result.unwrap();
+    let local_path = local_file.path();
+
+    // Check the content against the quarantine before handing it to a converter.
+    if status.content_hash.is_none() {
+        match hash_file(local_path).await {
+            Ok(content_hash) => status.content_hash = Some(content_hash),
+            Err(err) => {
+                let err = anyhow::Error::new(err).context("Could not hash downloaded file");
+                return task_errored(stores, status, err).await;
+            }
+        }
+    }
+    if let Some(content_hash) = &status.content_hash
+        && let Some(document) = find_quarantined(stores, content_hash).await?
+    {
+        return task_quarantined(stores, status, &document).await;
     }
-    let local_path = download_result.unwrap();
 
     // Process PDF to markdown
     info!(
         local_path=%local_path.to_string_lossy(),
         "Downloaded result successfully, processing pdf on locally",
     );
-    let local_path_str: &str = (&local_path).as_path().to_str().unwrap();
+    let local_path_str: &str = local_path.to_str().unwrap();
 
     // Update status based on processing result
-    match process_pdf(local_path_str, &status.conversion_method).await {
-        Ok(markdown) => {
-            status.markdown = Some(markdown);
+    match process_pdf(
+        local_path_str,
+        &status.conversion_method,
+        &status.conversion_options,
+    )
+    .await
+    {
+        Ok(output) => {
+            status.markdown = Some(output.markdown);
+            status.images = output.images;
+            status.metadata = output.metadata;
+            status.page_count = output.page_count;
+            status.error = None;
             status.status = ProcessingStage::Completed;
-            info!(task_id, "Successfully processed pdf");
-            match update_task_data(status).await {
-                Ok(_) => Ok(()),
+            let charge = status.api_key_id.clone().zip(status.page_count);
+            match finish_task_data(stores, status).await {
+                Ok(false) => {
+                    info!(
+                        task_id,
+                        "Task was cancelled while converting, dropping its result."
+                    );
+                    Ok(TaskOutcome::Cancelled)
+                }
+                Ok(true) => {
+                    info!(task_id, "Successfully processed pdf");
+                    if let Some((api_key_id, pages)) = charge
+                        && let Err(err) = charge_converted_pages(stores, &api_key_id, pages).await
+                    {
+                        warn!(%err, task_id, "Could not charge converted pages to the API key.");
+                    }
+                    Ok(TaskOutcome::Completed)
+                }
                 Err(err) => {
                     bail!(
                         "Encountered error pushing final data to db: ".to_string()
@@ -89,8 +607,19 @@ async fn process_pdf_from_status(mut status: DocStatus) -> anyhow::Result<()> {
             }
         }
         Err(err) => {
-            tracing::error!(%err,task_id,"Encountered error processing pdf");
-            Err(task_errored(status, anyhow!("Encountered error processing pdf: {err}")).await)
+            let err = err.context("Encountered error processing pdf");
+            if is_poison_pill(&err) {
+                match record_conversion_strike(stores, &status, local_path, format!("{err:#}"))
+                    .await
+                {
+                    Ok(Some(document)) => return task_quarantined(stores, status, &document).await,
+                    Ok(None) => {}
+                    Err(strike_err) => {
+                        warn!(err = %format!("{strike_err:#}"), task_id, "Failed to record conversion strike.")
+                    }
+                }
+            }
+            task_errored(stores, status, err).await
         }
     }
 }
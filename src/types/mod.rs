@@ -1,4 +1,11 @@
-use std::{collections::HashMap, path::PathBuf, sync::LazyLock};
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use thiserror::Error;
 
 use schemars::JsonSchema;
@@ -20,13 +27,17 @@ pub enum ProcessingStage {
     Waiting,
     Errored,
     Processing,
+    Cancelled,
 }
 impl ProcessingStage {
     fn is_successful(&self) -> bool {
         self == &ProcessingStage::Completed
     }
-    fn is_finished(&self) -> bool {
-        self == &ProcessingStage::Completed || self == &ProcessingStage::Errored
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            ProcessingStage::Completed | ProcessingStage::Errored | ProcessingStage::Cancelled
+        )
     }
 }
 
@@ -37,11 +48,20 @@ pub struct DocStatusResponse {
     request_check_leaf: String,
     markdown: Option<String>,
     status: ProcessingStage,
+    conversion_method: MarkdownConversionMethod,
+    conversion_options: ConversionOptions,
+    priority: TaskPriority,
+    owner: Option<String>,
+    batch: Option<String>,
     success: bool,
     completed: bool,
     images: Option<HashMap<String, String>>,
     metadata: Option<HashMap<String, String>>,
     error: Option<String>,
+    original_filename: Option<String>,
+    content_type: Option<String>,
+    page_count: Option<u32>,
+    attempts: u32,
 }
 
 pub static DOMAIN: LazyLock<String> =
@@ -54,7 +74,7 @@ fn make_request_leaf(id: TaskID) -> String {
     format!("/v1/status/{id}")
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, JsonSchema)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, JsonSchema, PartialEq, Eq, Hash)]
 pub enum MarkdownConversionMethod {
     Simple,
     Marker,
@@ -62,33 +82,264 @@ pub enum MarkdownConversionMethod {
     OlmOcr,
 }
 
-#[derive(Debug, Clone)]
+impl MarkdownConversionMethod {
+    pub const ALL: [MarkdownConversionMethod; 3] = [
+        MarkdownConversionMethod::Simple,
+        MarkdownConversionMethod::Marker,
+        MarkdownConversionMethod::OlmOcr,
+    ];
+
+    /// Lowercase name used in storage keys and metric labels.
+    pub fn slug(&self) -> &'static str {
+        match self {
+            MarkdownConversionMethod::Simple => "simple",
+            MarkdownConversionMethod::Marker => "marker",
+            MarkdownConversionMethod::OlmOcr => "olmocr",
+        }
+    }
+}
+
+/// How urgently a task should be converted. Each method's queue keeps a lane per priority,
+/// dequeuing mostly from the urgent lanes while still giving the others a share.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Serialize,
+    Deserialize,
+    Default,
+    JsonSchema,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskPriority {
+    /// A user waiting on the result.
+    Interactive,
+    #[default]
+    Normal,
+    /// Backfills and other batch work nobody is waiting on.
+    Bulk,
+}
+
+impl TaskPriority {
+    /// Most urgent first.
+    pub const ALL: [TaskPriority; 3] = [
+        TaskPriority::Interactive,
+        TaskPriority::Normal,
+        TaskPriority::Bulk,
+    ];
+
+    /// Lowercase name used in storage keys.
+    pub fn slug(&self) -> &'static str {
+        match self {
+            TaskPriority::Interactive => "interactive",
+            TaskPriority::Normal => "normal",
+            TaskPriority::Bulk => "bulk",
+        }
+    }
+}
+
+/// Knobs that tune a single conversion, stored with the task and honored by every
+/// `MarkdownConversionMethod` that claims to support them.
+#[derive(Serialize, Deserialize, Debug, JsonSchema, Clone, Default, PartialEq)]
+pub struct ConversionOptions {
+    /// Languages present in the document, used as a hint for OCR.
+    #[serde(default)]
+    pub langs: Vec<String>,
+    /// Force OCR on every page.
+    #[serde(default)]
+    pub force_ocr: bool,
+    /// Paginate output with page delimiters.
+    #[serde(default)]
+    pub paginate: bool,
+    /// Use an LLM to improve the accuracy of forms, tables and layout.
+    #[serde(default)]
+    pub use_llm: bool,
+    /// Throw away any existing OCR text layer and redo OCR.
+    #[serde(default)]
+    pub strip_existing_ocr: bool,
+    /// Disable image extraction.
+    #[serde(default)]
+    pub disable_image_extraction: bool,
+    /// Maximum number of pages to process from the start.
+    pub max_pages: Option<u32>,
+}
+
+impl ConversionOptions {
+    pub fn new(
+        langs: Option<&str>,
+        force_ocr: Option<bool>,
+        paginate: Option<bool>,
+        disable_image_extraction: Option<bool>,
+        max_pages: Option<u32>,
+    ) -> Self {
+        ConversionOptions {
+            langs: langs
+                .map(|langs| {
+                    langs
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|lang| !lang.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            force_ocr: force_ocr.unwrap_or_default(),
+            paginate: paginate.unwrap_or_default(),
+            use_llm: false,
+            strip_existing_ocr: false,
+            disable_image_extraction: disable_image_extraction.unwrap_or_default(),
+            max_pages,
+        }
+    }
+
+    /// Names of the options set to anything but their default.
+    pub fn set_options(&self) -> Vec<&'static str> {
+        let ConversionOptions {
+            langs,
+            force_ocr,
+            paginate,
+            use_llm,
+            strip_existing_ocr,
+            disable_image_extraction,
+            max_pages,
+        } = self;
+        [
+            ("langs", !langs.is_empty()),
+            ("force_ocr", *force_ocr),
+            ("paginate", *paginate),
+            ("use_llm", *use_llm),
+            ("strip_existing_ocr", *strip_existing_ocr),
+            ("disable_image_extraction", *disable_image_extraction),
+            ("max_pages", max_pages.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect()
+    }
+}
+
+/// Everything a conversion backend produced for a document.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ConversionOutput {
+    pub markdown: String,
+    /// Image filenames mapped to base64 encoded images.
+    pub images: Option<HashMap<String, String>>,
+    pub metadata: Option<HashMap<String, String>>,
+    pub page_count: Option<u32>,
+}
+
+impl From<String> for ConversionOutput {
+    fn from(markdown: String) -> Self {
+        ConversionOutput {
+            markdown,
+            ..ConversionOutput::default()
+        }
+    }
+}
+
+/// A failure worth another attempt later, like a timeout or a rate limited backend.
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct RetryableError(pub String);
+
+/// A conversion process crashed or was killed, rather than failing with an error of its own.
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct ConversionCrashed(pub String);
+
+/// A conversion option was requested from a backend that can't honor it.
+#[derive(Error, Debug)]
+#[error("The {method:?} conversion method does not support the `{option}` option")]
+pub struct UnsupportedOptionError {
+    pub method: MarkdownConversionMethod,
+    pub option: &'static str,
+}
+
+/// A webhook `callback_url` that mustn't be posted to.
+#[derive(Error, Debug)]
+pub enum CallbackUrlError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Could not resolve the callback_url host {host}: {source}")]
+    Unresolvable {
+        host: String,
+        source: std::io::Error,
+    },
+    #[error("callback_url host {host} resolves to {address}, which isn't a public address")]
+    NotPublic { host: String, address: IpAddr },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DocStatus {
     pub file_location: FileLocation,
     pub request_id: TaskID,
     pub conversion_method: MarkdownConversionMethod,
+    pub conversion_options: ConversionOptions,
+    #[serde(default)]
+    pub priority: TaskPriority,
     // queue_id: u64,
     pub markdown: Option<String>,
     pub status: ProcessingStage,
     pub images: Option<HashMap<String, String>>,
     pub metadata: Option<HashMap<String, String>>,
     pub error: Option<String>,
+    /// Filename supplied by the client for multipart uploads.
+    pub original_filename: Option<String>,
+    /// Content type supplied by the client for multipart uploads.
+    pub content_type: Option<String>,
+    /// Number of pages that were converted.
+    pub page_count: Option<u32>,
+    /// How many times a worker has started converting this task.
+    #[serde(default)]
+    pub attempts: u32,
+    /// Hex encoded sha256 of the source file, filled in on ingest or once downloaded.
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    /// Who submitted the task, queues share conversions fairly between owners.
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Id of the API key that submitted the task, its quotas are charged for the conversion.
+    #[serde(default)]
+    pub api_key_id: Option<String>,
+    /// Label the client grouped the task under, to follow the whole batch's events at once.
+    #[serde(default)]
+    pub batch: Option<String>,
+    /// Where the final status is posted once the task completes or errors.
+    #[serde(default)]
+    pub callback_url: Option<String>,
 }
 impl DocStatus {
     pub fn new_from_id_loc(
         id: TaskID,
         location: FileLocation,
         method: MarkdownConversionMethod,
+        options: ConversionOptions,
     ) -> Self {
         DocStatus {
             file_location: location,
             conversion_method: method,
+            conversion_options: options,
+            priority: TaskPriority::default(),
             request_id: id,
             markdown: None,
             status: ProcessingStage::Waiting,
             metadata: None,
             images: None,
             error: None,
+            original_filename: None,
+            content_type: None,
+            page_count: None,
+            attempts: 0,
+            content_hash: None,
+            owner: None,
+            api_key_id: None,
+            batch: None,
+            callback_url: None,
         }
     }
 }
@@ -101,44 +352,408 @@ impl From<DocStatus> for DocStatusResponse {
             request_check_leaf: make_request_leaf(input.request_id),
             markdown: input.markdown,
             status: input.status,
+            conversion_method: input.conversion_method,
+            conversion_options: input.conversion_options,
+            priority: input.priority,
+            owner: input.owner,
+            batch: input.batch,
             success: input.status.is_successful(),
             completed: input.status.is_finished(),
             images: input.images,
             metadata: input.metadata,
             error: input.error,
+            original_filename: input.original_filename,
+            content_type: input.content_type,
+            page_count: input.page_count,
+            attempts: input.attempts,
         }
     }
 }
 
-/// Simplified task message carrying ID and file location.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// A change to a task's status, pushed to clients following the task.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct TaskEvent {
+    pub request_id: TaskID,
+    pub status: ProcessingStage,
+    pub attempts: u32,
+    pub page_count: Option<u32>,
+    pub error: Option<String>,
+    pub owner: Option<String>,
+    pub batch: Option<String>,
+    /// Id of the API key that submitted the task.
+    pub api_key_id: Option<String>,
+}
+
+impl From<&DocStatus> for TaskEvent {
+    fn from(status: &DocStatus) -> Self {
+        TaskEvent {
+            request_id: status.request_id,
+            status: status.status,
+            attempts: status.attempts,
+            page_count: status.page_count,
+            error: status.error.clone(),
+            owner: status.owner.clone(),
+            batch: status.batch.clone(),
+            api_key_id: status.api_key_id.clone(),
+        }
+    }
+}
+
+/// Where a task's completion webhook stands.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookDeliveryState {
+    /// Waiting for its next attempt.
+    Pending,
+    /// The callback answered with a 2xx.
+    Delivered,
+    /// Every attempt failed.
+    Failed,
+}
+
+/// One try at posting a webhook.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct WebhookAttempt {
+    /// Unix milliseconds.
+    pub attempted_at: i64,
+    /// Status the callback answered with, `None` if it couldn't be reached.
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+/// The completion webhook of a task, posting its final status to the task's `callback_url`.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct WebhookDelivery {
+    /// Sent as `X-Crimson-Delivery`, so receivers can drop repeats.
+    pub id: String,
+    pub task_id: TaskID,
+    pub callback_url: String,
+    /// The final status being reported.
+    pub status: ProcessingStage,
+    pub state: WebhookDeliveryState,
+    /// Oldest first.
+    pub attempts: Vec<WebhookAttempt>,
+    /// Unix milliseconds, `None` once delivered or given up on.
+    pub next_attempt_at: Option<i64>,
+    /// Unix milliseconds.
+    pub created_at: i64,
+}
+
+/// Simplified task message carrying ID, file location and how to convert it.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct TaskMessage {
     pub id: TaskID,
     pub location: FileLocation,
+    pub conversion_method: MarkdownConversionMethod,
+    pub conversion_options: ConversionOptions,
+    /// Messages queued before priorities existed are normal.
+    #[serde(default)]
+    pub priority: TaskPriority,
+    /// Tasks without an owner are scheduled as one shared owner.
+    #[serde(default)]
+    pub owner: Option<String>,
+}
+
+/// A dequeued task, held by one worker until it is acked or `deadline` passes.
+#[derive(Clone, Debug)]
+pub struct TaskLease {
+    pub task: TaskMessage,
+    /// Opaque handle the queue uses to recognise this particular lease.
+    pub receipt: String,
+    pub deadline: SystemTime,
+}
+
+/// A task that ran out of attempts, parked until an admin requeues it.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct DeadLetter {
+    pub task: TaskMessage,
+    /// The error from the final attempt.
+    pub error: String,
+    pub attempts: u32,
+    /// Unix milliseconds.
+    pub dead_lettered_at: i64,
+}
+
+/// Concurrency of the conversions for one method in this worker process.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct WorkerPoolInfo {
+    pub method: MarkdownConversionMethod,
+    /// Conversions allowed to run at once, 0 pauses the method.
+    pub size: u32,
+    /// Conversions running right now, can briefly exceed `size` after shrinking the pool.
+    pub busy: u32,
+}
+
+/// How much of one owner's work a method's queue holds.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct OwnerQueueDepth {
+    /// `None` for tasks submitted without an owner.
+    pub owner: Option<String>,
+    pub conversion_method: MarkdownConversionMethod,
+    /// Tasks waiting to be dequeued, by priority.
+    pub queued: HashMap<TaskPriority, u64>,
+    /// Tasks leased to a worker, including ones waiting out a retry delay.
+    pub processing: u64,
+}
+
+/// A document that kept crashing or timing out converters, refused until an admin releases it.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct QuarantinedDocument {
+    /// Hex encoded sha256 of the document.
+    pub content_hash: String,
+    /// The task whose failure tipped it into quarantine.
+    pub task_id: TaskID,
+    /// Copy of the document under the quarantine prefix of the file store.
+    pub file_location: FileLocation,
+    /// The error from the final crash or timeout.
+    pub error: String,
+    pub strikes: u32,
+    /// Unix milliseconds.
+    pub quarantined_at: i64,
+}
+
+/// What an API key may do.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiKeyRole {
+    /// Submits tasks and reads back its own.
+    #[default]
+    User,
+    /// Reads every task and uses the admin routes.
+    Admin,
+}
+
+/// A key clients send in the `X-Api-Key` header. Only a hash of the secret is kept.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ApiKey {
+    /// Hex encoded sha256 of the secret.
+    pub id: String,
+    /// Tasks submitted with the key belong to this owner.
+    pub owner: String,
+    pub role: ApiKeyRole,
+    /// Documents the key may submit per UTC day, unlimited when `None`.
+    pub daily_documents: Option<u64>,
+    /// Pages the key may have converted per UTC day, unlimited when `None`.
+    pub daily_pages: Option<u64>,
+    /// Unix milliseconds.
+    pub created_at: i64,
+    /// Signs the completion webhooks of the key's tasks. Only shown to the key itself and
+    /// whoever created it, keys stored before webhooks existed have none until it is rotated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_secret: Option<String>,
+}
+
+impl ApiKey {
+    /// The key without its webhook secret, for listing it to admins.
+    pub fn redacted(mut self) -> Self {
+        self.webhook_secret = None;
+        self
+    }
+}
+
+/// What a key used on one UTC day.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct ApiKeyUsage {
+    pub documents: u64,
+    pub pages: u64,
+}
+
+/// An API key along with what it used so far today.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ApiKeyInfo {
+    #[serde(flatten)]
+    pub key: ApiKey,
+    /// Comes from the config file rather than the admin routes, so it can't be revoked.
+    pub configured: bool,
+    pub usage_today: ApiKeyUsage,
+}
+
+/// Milliseconds since the unix epoch, how lease deadlines are stored outside the process.
+pub fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis() as i64
+}
+
+/// A document fetched from the file store to convert. A copy downloaded for the conversion is
+/// deleted when this drops, a file the store already keeps on local disk is left alone.
+#[derive(Debug)]
+pub struct DownloadedFile {
+    path: LocalPath,
+    temporary: bool,
+}
+
+impl DownloadedFile {
+    /// The store's own file, kept once converted.
+    pub fn stored(path: LocalPath) -> Self {
+        DownloadedFile {
+            path,
+            temporary: false,
+        }
+    }
+
+    /// A copy only made for converting, deleted on drop along with its directory if empty.
+    pub fn temporary(path: LocalPath) -> Self {
+        DownloadedFile {
+            path,
+            temporary: true,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for DownloadedFile {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+            if let Some(parent) = self.path.parent() {
+                let _ = std::fs::remove_dir(parent);
+            }
+        }
+    }
 }
 
 /// Abstract file storage (upload/download/delete).
-// #[async_trait]
-pub trait FileStoreImplementation {
+#[async_trait]
+pub trait FileStoreImplementation: Send + Sync {
     async fn upload_from_file(
         &self,
         local_path: LocalPath,
         upload_key: String,
     ) -> Result<FileLocation, StoreError>;
-    async fn download_to_file(&self, src: &FileLocation) -> Result<LocalPath, StoreError>;
+    async fn download_to_file(&self, src: &FileLocation) -> Result<DownloadedFile, StoreError>;
     async fn delete(&self, target: &FileLocation) -> Result<(), StoreError>;
 }
 
 /// Abstract FIFO task queue for enqueuing and dequeuing tasks.
-pub trait TaskQueueImplementation {
-    async fn enqueue(self, task: TaskMessage) -> Result<(), QueueError>;
-    async fn dequeue(self) -> Result<Option<TaskMessage>, QueueError>;
+///
+/// Dequeuing leases a task rather than removing it. The worker extends the lease while it
+/// converts and acks once done, a task whose lease runs out goes back on the queue.
+#[async_trait]
+pub trait TaskQueueImplementation: Send + Sync {
+    async fn enqueue(&self, task: TaskMessage) -> Result<(), QueueError>;
+    /// Lease the next task for `lease_for`, requeueing expired leases first.
+    async fn dequeue(&self, lease_for: Duration) -> Result<Option<TaskLease>, QueueError>;
+    /// Like `dequeue`, but when nothing is queued wait up to `wait` for a task to show up,
+    /// waking as soon as one is enqueued or a lease runs out.
+    async fn dequeue_wait(
+        &self,
+        lease_for: Duration,
+        wait: Duration,
+    ) -> Result<Option<TaskLease>, QueueError>;
+    /// Push the deadline out to `lease_for` from now, `LeaseLost` if the task was already requeued.
+    async fn extend_lease(
+        &self,
+        lease: &mut TaskLease,
+        lease_for: Duration,
+    ) -> Result<(), QueueError>;
+    /// Drop a finished task from the queue for good.
+    async fn ack(&self, lease: &TaskLease) -> Result<(), QueueError>;
+    /// Give the task back to be dequeued again once `delay` has passed.
+    async fn release(&self, lease: &TaskLease, delay: Duration) -> Result<(), QueueError> {
+        // An expired lease is requeued on the next dequeue, so just let it expire later.
+        let mut lease = lease.clone();
+        self.extend_lease(&mut lease, delay).await
+    }
+    /// Move a leased task onto the dead letter queue.
+    async fn dead_letter(
+        &self,
+        lease: &TaskLease,
+        dead_letter: DeadLetter,
+    ) -> Result<(), QueueError>;
+    /// Every dead lettered task, oldest first.
+    async fn dead_letters(&self) -> Result<Vec<DeadLetter>, QueueError>;
+    /// Move a dead lettered task back onto the queue, `None` if there was no such task.
+    async fn requeue_dead_letter(&self, id: TaskID) -> Result<Option<DeadLetter>, QueueError>;
+    /// Queued and leased tasks of every owner with any, ordered by owner.
+    async fn owner_depths(&self) -> Result<Vec<OwnerQueueDepth>, QueueError>;
+    /// Tasks waiting to be dequeued, leased ones left out.
+    async fn depth(&self) -> Result<u64, QueueError>;
+}
+
+/// Crash and timeout counts per document content, and the documents quarantined for them.
+#[async_trait]
+pub trait QuarantineStoreImplementation: Send + Sync {
+    /// Count another crash or timeout against the content, returning the new total.
+    async fn record_strike(&self, content_hash: &str) -> Result<u32, QuarantineError>;
+    async fn quarantine(&self, document: QuarantinedDocument) -> Result<(), QuarantineError>;
+    async fn get_quarantined(
+        &self,
+        content_hash: &str,
+    ) -> Result<Option<QuarantinedDocument>, QuarantineError>;
+    /// Every quarantined document, oldest first.
+    async fn quarantined(&self) -> Result<Vec<QuarantinedDocument>, QuarantineError>;
+    /// Lift the quarantine and forget past strikes, `None` if it wasn't quarantined.
+    async fn release(
+        &self,
+        content_hash: &str,
+    ) -> Result<Option<QuarantinedDocument>, QuarantineError>;
+}
+
+/// API keys managed through the admin routes, and the daily usage of every key.
+#[async_trait]
+pub trait ApiKeyStoreImplementation: Send + Sync {
+    async fn get_key(&self, id: &str) -> Result<Option<ApiKey>, ApiKeyError>;
+    async fn put_key(&self, key: ApiKey) -> Result<(), ApiKeyError>;
+    /// Every stored key, oldest first.
+    async fn keys(&self) -> Result<Vec<ApiKey>, ApiKeyError>;
+    /// Forget the key, `None` if there was no such key.
+    async fn revoke_key(&self, id: &str) -> Result<Option<ApiKey>, ApiKeyError>;
+    /// Add to the key's usage on `day`, counted in days since the unix epoch.
+    async fn record_usage(&self, id: &str, day: i64, usage: ApiKeyUsage)
+    -> Result<(), ApiKeyError>;
+    async fn usage(&self, id: &str, day: i64) -> Result<ApiKeyUsage, ApiKeyError>;
+    /// Count one more document against the key on `day` in a single step, unless that would
+    /// take it past `limit`. Returns whether the document was counted.
+    async fn reserve_document(
+        &self,
+        id: &str,
+        day: i64,
+        limit: Option<u64>,
+    ) -> Result<bool, ApiKeyError>;
+    /// Hand back a document reserved on `day` that was never queued.
+    async fn refund_document(&self, id: &str, day: i64) -> Result<(), ApiKeyError>;
+}
+
+/// Fans task events out to every subscriber, in every process sharing the stores.
+#[async_trait]
+pub trait TaskEventBusImplementation: Send + Sync {
+    async fn publish(&self, event: TaskEvent) -> Result<(), TaskEventError>;
+    /// Events published from now on. Subscribers that fall behind miss events, and are told
+    /// how many through `RecvError::Lagged`.
+    fn subscribe(&self) -> tokio::sync::broadcast::Receiver<TaskEvent>;
 }
 
 /// Metadata store for tracking processing stage and other data.
-pub trait StatusStoreImplementation {
+#[async_trait]
+pub trait StatusStoreImplementation: Send + Sync {
     async fn set_doc_status(&self, status: DocStatus) -> Result<(), DocStatusError>;
     async fn get_doc_status(&self, id: TaskID) -> Result<DocStatus, DocStatusError>;
+    /// Mark the task cancelled unless it already finished. Checked and written in one step, so
+    /// a worker finishing the task meanwhile is never overwritten. Returns the cancelled status.
+    async fn cancel_doc_status(&self, id: TaskID) -> Result<DocStatus, CancelTaskError>;
+    /// Store the status unless the task was cancelled, checked and written in one step like
+    /// `cancel_doc_status`. Returns whether it was stored.
+    async fn finish_doc_status(&self, status: DocStatus) -> Result<bool, DocStatusError>;
+    /// Store the task's webhook delivery, replacing any earlier one. It comes due at its
+    /// `next_attempt_at`.
+    async fn set_webhook_delivery(&self, delivery: WebhookDelivery) -> Result<(), DocStatusError>;
+    async fn get_webhook_delivery(
+        &self,
+        id: TaskID,
+    ) -> Result<Option<WebhookDelivery>, DocStatusError>;
+    /// Take up to `limit` deliveries due by `now`, unix milliseconds. Each one isn't due again
+    /// until `claimed_until`, so other processes leave it alone while it is being attempted.
+    async fn claim_due_webhook_deliveries(
+        &self,
+        now: i64,
+        claimed_until: i64,
+        limit: usize,
+    ) -> Result<Vec<WebhookDelivery>, DocStatusError>;
 }
 
 // Errors for file storage operations on S3.
@@ -154,28 +769,114 @@ pub enum StoreError {
     InvalidLocation,
 }
 
-/// Errors for queue operations on Redis.
+/// Errors for queue operations.
 #[derive(Error, Debug)]
 pub enum QueueError {
     #[error("Processing Queue is Empty")]
     QueueEmpty,
+    #[error("Task lease expired or was taken over by another worker")]
+    LeaseLost,
     #[error("Redis error: {0}")]
     Redis(#[from] redis::RedisError),
+    #[error("Sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
     #[error("Serialization error: {0}")]
     Serde(#[from] serde_json::Error),
 }
 
-/// Errors for metadata store operations on Redis.
+/// Errors for metadata store operations.
 #[derive(Error, Debug)]
 pub enum DocStatusError {
     #[error("Doc ID Not Found")]
     DocidNotFound,
     #[error("Redis error: {0}")]
     Redis(#[from] redis::RedisError),
+    #[error("Sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Serialization error: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+/// Errors for quarantine store operations.
+#[derive(Error, Debug)]
+pub enum QuarantineError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("Sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Serialization error: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+/// Errors for API key store operations.
+#[derive(Error, Debug)]
+pub enum ApiKeyError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("Sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Serialization error: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+/// Errors for task event bus operations.
+#[derive(Error, Debug)]
+pub enum TaskEventError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("Sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
     #[error("Serialization error: {0}")]
     Serde(#[from] serde_json::Error),
 }
 
+/// Errors from storing a task's status and putting it on its queue.
+#[derive(Error, Debug)]
+pub enum IngestError {
+    #[error("Could not store the task status: {0}")]
+    Status(#[from] DocStatusError),
+    #[error("Could not enqueue the task: {0}")]
+    Queue(#[from] QueueError),
+}
+
+/// Errors from cancelling a task.
+#[derive(Error, Debug)]
+pub enum CancelTaskError {
+    #[error("Doc ID Not Found")]
+    DocidNotFound,
+    #[error("Task already finished as {0:?}")]
+    AlreadyFinished(ProcessingStage),
+    #[error("Status store error: {0}")]
+    Status(DocStatusError),
+}
+
+impl From<DocStatusError> for CancelTaskError {
+    fn from(err: DocStatusError) -> Self {
+        match err {
+            DocStatusError::DocidNotFound => CancelTaskError::DocidNotFound,
+            err => CancelTaskError::Status(err),
+        }
+    }
+}
+
+impl From<redis::RedisError> for CancelTaskError {
+    fn from(err: redis::RedisError) -> Self {
+        DocStatusError::from(err).into()
+    }
+}
+
+impl From<rusqlite::Error> for CancelTaskError {
+    fn from(err: rusqlite::Error) -> Self {
+        DocStatusError::from(err).into()
+    }
+}
+
+impl From<serde_json::Error> for CancelTaskError {
+    fn from(err: serde_json::Error) -> Self {
+        DocStatusError::from(err).into()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, JsonSchema, Clone)]
 pub struct S3Location {
     pub key: String,